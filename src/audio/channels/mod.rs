@@ -61,14 +61,37 @@ impl DutyCycleCounter {
 }
 
 pub const MAX_VOL: u8 = 15;
+
+const CLOCK_RATE: f32 = 4_194_304.0;
+
+// Convert a number of cycles per wave period into a frequency in Hz.
+pub fn cycles_to_hz(period: u32) -> f32 {
+    CLOCK_RATE / (period as f32)
+}
+
 pub const MIN_VOL: u8 = 0;
 
+// The DAC is on if the initial volume is non-zero or the envelope increases.
+// While it is off, the channel is disabled and can't be triggered.
+pub fn envelope_dac_enabled(vol_envelope_reg: u8) -> bool {
+    (vol_envelope_reg & bits![7, 6, 5, 4, 3]) != 0
+}
+
 pub fn get_freq_modulo(hi_reg: u8, lo_reg: u8) -> u32 {
     const HI_FREQ_MASK: u8 = bits![2, 1, 0];
     let hi = hi_reg & HI_FREQ_MASK;
     make_16!(hi, lo_reg) as u32
 }
 
+// On trigger, the frequency timer is reloaded from the period, except for its low 2 bits.
+// The timer counts down once every tick_cycles (4 for square, 2 for wave),
+// while freq_counter counts up to the period in cycles.
+pub fn trigger_freq_counter(freq_counter: u32, old_modulo: u32, new_modulo: u32, tick_cycles: u32) -> u32 {
+    let remaining = old_modulo.saturating_sub(freq_counter) / tick_cycles;
+    let reloaded = ((new_modulo / tick_cycles) & !3) | (remaining & 3);
+    new_modulo.saturating_sub(reloaded * tick_cycles)
+}
+
 // Convert from 4-bit samples to 32-bit floating point.
 pub fn u4_to_f32(amplitude: u8) -> f32 {
     const MAX_AMP: f32 = 15.0;
@@ -81,4 +104,62 @@ pub fn i4_to_f32(amplitude: u8) -> f32 {
     const MAX_AMP: f32 = 7.5;
 
     ((amplitude as f32) - MAX_AMP) / MAX_AMP
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The longest length register value plays for one length clock.
+    #[test]
+    fn one_tick_length() {
+        let mut square1 = square1::Square1::new();
+        square1.set_duty_length_reg(0x3F);
+        square1.set_vol_envelope_reg(0xF0);
+        square1.set_freq_hi_reg(0xC0);
+        assert!(square1.is_enabled());
+        square1.length_clock();
+        assert!(!square1.is_enabled());
+        square1.length_clock();     // Doesn't underflow
+
+        let mut square2 = square2::Square2::new();
+        square2.set_duty_length_reg(0x3F);
+        square2.set_vol_envelope_reg(0xF0);
+        square2.set_freq_hi_reg(0xC0);
+        assert!(square2.is_enabled());
+        square2.length_clock();
+        assert!(!square2.is_enabled());
+        square2.length_clock();
+
+        let mut wave = wave::Wave::new();
+        wave.set_playback_reg(0x80);
+        wave.set_length_reg(0xFF);
+        wave.set_freq_hi_reg(0xC0);
+        assert!(wave.is_enabled());
+        wave.length_clock();
+        assert!(!wave.is_enabled());
+        wave.length_clock();
+
+        let mut noise = noise::Noise::new();
+        noise.set_length_reg(0x3F);
+        noise.set_vol_envelope_reg(0xF0);
+        noise.set_trigger_reg(0xC0);
+        assert!(noise.is_enabled());
+        noise.length_clock();
+        assert!(!noise.is_enabled());
+        noise.length_clock();
+    }
+
+    // Without the length enable bit, the channel keeps playing.
+    #[test]
+    fn length_disabled() {
+        let mut noise = noise::Noise::new();
+        noise.set_length_reg(0x3F);
+        noise.set_vol_envelope_reg(0xF0);
+        noise.set_trigger_reg(0x80);
+        for _ in 0..256 {
+            noise.length_clock();
+        }
+        assert!(noise.is_enabled());
+    }
+}
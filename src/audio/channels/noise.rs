@@ -18,7 +18,6 @@ pub struct Noise {
     volume_modulo:  u8,
 
     length_counter: u8,
-    length_modulo:  u8,
 
     freq_counter:   u32,
     freq_modulo:    u32,
@@ -40,7 +39,6 @@ impl Noise {
             volume_modulo:      0,
 
             length_counter:     0,
-            length_modulo:      MAX_LEN,
 
             freq_counter:       0,
             freq_modulo:        0,
@@ -53,6 +51,9 @@ impl Noise {
 
     pub fn set_vol_envelope_reg(&mut self, val: u8) {
         self.vol_envelope_reg = val;
+        if !envelope_dac_enabled(val) {
+            self.enabled = false;
+        }
     }
 
     pub fn set_poly_counter_reg(&mut self, val: u8) {
@@ -60,6 +61,7 @@ impl Noise {
     }
 
     pub fn set_trigger_reg(&mut self, val: u8) {
+        self.trigger_reg = val;
         // And trigger event...
         if test_bit!(val, 7) {
             self.trigger();
@@ -69,6 +71,11 @@ impl Noise {
     pub fn is_enabled(&self) -> bool {
         self.enabled
     }
+
+    // Current LFSR clock rate in Hz.
+    pub fn frequency(&self) -> f32 {
+        cycles_to_hz(self.freq_modulo)
+    }
 }
 
 impl Channel for Noise {
@@ -82,8 +89,8 @@ impl Channel for Noise {
 
     fn length_clock(&mut self) {
         if self.enabled && test_bit!(self.trigger_reg, 6) {
-            self.length_counter -= 1;
-            if self.length_counter == self.length_modulo {
+            self.length_counter = self.length_counter.saturating_sub(1);
+            if self.length_counter == 0 {
                 self.enabled = false;
             }
         }
@@ -130,6 +137,8 @@ impl Channel for Noise {
 
         self.freq_counter = 0;
         self.length_counter = MAX_LEN;
+
+        self.enabled = false;
     }
 }
 
@@ -152,12 +161,11 @@ impl Noise {
         } << freq_modulo_shift;
         self.freq_counter = 0;
 
-        self.length_counter = MAX_LEN;
-        self.length_modulo = self.length_reg & LEN_MASK;
+        self.length_counter = MAX_LEN - (self.length_reg & LEN_MASK);
 
         self.lfsr_counter = 0xFFFF;
 
-        self.enabled = true;
+        self.enabled = envelope_dac_enabled(self.vol_envelope_reg);
     }
 
     fn lfsr_step(&mut self) {
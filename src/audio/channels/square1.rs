@@ -22,7 +22,6 @@ pub struct Square1 {
     volume_modulo:  u8,
 
     length_counter: u8,
-    length_modulo:  u8,
 
     freq_counter:   u32,
     freq_modulo:    u32,
@@ -48,7 +47,6 @@ impl Square1 {
             volume_modulo:      0,
 
             length_counter:     0,
-            length_modulo:      MAX_LEN,
 
             freq_counter:       0,
             freq_modulo:        0,
@@ -65,10 +63,14 @@ impl Square1 {
 
     pub fn set_vol_envelope_reg(&mut self, val: u8) {
         self.vol_envelope_reg = val;
+        if !envelope_dac_enabled(val) {
+            self.enabled = false;
+        }
     }
 
     pub fn set_freq_lo_reg(&mut self, val: u8) {
         self.freq_lo_reg = val;
+        self.update_period();
     }
 
     pub fn set_freq_hi_reg(&mut self, val: u8) {
@@ -76,6 +78,8 @@ impl Square1 {
         // And trigger event...
         if test_bit!(val, 7) {
             self.trigger();
+        } else {
+            self.update_period();
         }
     }
 
@@ -83,6 +87,11 @@ impl Square1 {
         self.enabled
     }
 
+    // Current pitch in Hz.
+    pub fn frequency(&self) -> f32 {
+        cycles_to_hz(self.freq_modulo * 8)
+    }
+
     pub fn sweep_clock(&mut self) {
         if self.enabled {
             if let Some(counter) = self.freq_sweep_counter {
@@ -109,8 +118,8 @@ impl Channel for Square1 {
 
     fn length_clock(&mut self) {
         if self.enabled && test_bit!(self.freq_hi_reg, 6) {
-            self.length_counter -= 1;
-            if self.length_counter == self.length_modulo {
+            self.length_counter = self.length_counter.saturating_sub(1);
+            if self.length_counter == 0 {
                 self.enabled = false;
             }
         }
@@ -164,6 +173,11 @@ impl Channel for Square1 {
 }
 
 impl Square1 {
+    // Frequency writes without a trigger change the period but keep the current phase.
+    fn update_period(&mut self) {
+        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+    }
+
     fn trigger(&mut self) {
         const FREQ_SWEEP_MASK: u8 = bits![6, 5, 4];
         const LEN_MASK: u8 = bits![5, 4, 3, 2, 1, 0];
@@ -177,20 +191,24 @@ impl Square1 {
         self.volume_modulo = self.vol_envelope_reg & VOL_SWEEP_MASK;
         self.volume_counter = if self.volume_modulo == 0 {None} else {Some(0)};
 
-        self.freq_counter = 0;
-        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+        let freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+        self.freq_counter = trigger_freq_counter(self.freq_counter, self.freq_modulo, freq_modulo, 4);
+        self.freq_modulo = freq_modulo;
 
-        self.length_counter = MAX_LEN;
-        self.length_modulo = self.duty_length_reg & LEN_MASK;
+        // Length counts down from (MAX_LEN - reg) to zero.
+        self.length_counter = MAX_LEN - (self.duty_length_reg & LEN_MASK);
 
-        self.enabled = true;
+        self.enabled = envelope_dac_enabled(self.vol_envelope_reg);
     }
 
+    // The swept frequency is written back to the frequency registers, so a later write to
+    // just one of them continues from it. The phase is kept.
     fn freq_sweep(&mut self) {
         const SWEEP_SHIFT_MASK: u8 = bits![2, 1, 0];
+        const HI_FREQ_MASK: u8 = bits![2, 1, 0];
         const MAX_FREQUENCY: u32 = 2047;
 
-        let freq_modulo = 2048 - (self.freq_modulo / 4);
+        let freq_modulo = get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg);
         let sweep_shift = self.sweep_reg & SWEEP_SHIFT_MASK;
         let freq_delta = freq_modulo >> sweep_shift;
         let new_modulo = if test_bit!(self.sweep_reg, 3) {
@@ -201,10 +219,59 @@ impl Square1 {
 
         if new_modulo > MAX_FREQUENCY {
             self.enabled = false;
+        } else if sweep_shift != 0 {
+            self.freq_lo_reg = lo_16!(new_modulo as u16);
+            self.freq_hi_reg = (self.freq_hi_reg & !HI_FREQ_MASK) | hi_16!(new_modulo as u16);
+            self.update_period();
         }
+    }
+}
 
-        self.freq_modulo = (2048 - new_modulo) * 4;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_writes_frequency() {
+        let mut square1 = Square1::new();
+        square1.set_sweep_reg(0x11);    // Every sweep clock, add half the frequency.
+        square1.set_vol_envelope_reg(0xF0);
+        square1.set_freq_lo_reg(0x00);
+        square1.set_freq_hi_reg(0x84);
+        square1.sweep_clock();
+        assert_eq!(get_freq_modulo(square1.freq_hi_reg, square1.freq_lo_reg), 0x600);
+        assert_eq!(square1.freq_modulo, (2048 - 0x600) * 4);
+
+        // Writing only the low byte keeps the swept high bits.
+        square1.set_freq_lo_reg(0x10);
+        assert_eq!(square1.freq_modulo, (2048 - 0x610) * 4);
+
+        // Overflow disables the channel, without writing the frequency.
+        square1.sweep_clock();
+        assert!(!square1.is_enabled());
+        assert_eq!(get_freq_modulo(square1.freq_hi_reg, square1.freq_lo_reg), 0x610);
+    }
 
-        self.freq_counter = 0;
+    #[test]
+    fn frequency_write_keeps_phase() {
+        let mut square1 = Square1::new();
+        square1.set_vol_envelope_reg(0xF0);
+        square1.set_freq_lo_reg(0x00);
+        square1.set_freq_hi_reg(0x84);  // 4096 cycles per duty step.
+        for _ in 0..((4096 * 3 + 100) / 4) {
+            square1.sample_clock(4);
+        }
+        let index = square1.duty_counter.index;
+
+        square1.set_freq_lo_reg(0x80);
+        square1.set_freq_hi_reg(0x05);
+        square1.set_freq_lo_reg(0x00);
+        assert_eq!(square1.freq_counter, 100);
+        assert_eq!(square1.duty_counter.index, index);
+        assert_eq!(square1.freq_modulo, (2048 - 0x500) * 4);
+
+        // A trigger reloads the timer.
+        square1.set_freq_hi_reg(0x85);
+        assert_ne!(square1.freq_counter, 100);
     }
 }
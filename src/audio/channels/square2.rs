@@ -18,7 +18,6 @@ pub struct Square2 {
     volume_modulo:  u8,
 
     length_counter: u8,
-    length_modulo:  u8,
 
     freq_counter:   u32,
     freq_modulo:    u32,
@@ -40,7 +39,6 @@ impl Square2 {
             volume_modulo:      0,
 
             length_counter:     0,
-            length_modulo:      MAX_LEN,
 
             freq_counter:       0,
             freq_modulo:        0,
@@ -53,10 +51,14 @@ impl Square2 {
 
     pub fn set_vol_envelope_reg(&mut self, val: u8) {
         self.vol_envelope_reg = val;
+        if !envelope_dac_enabled(val) {
+            self.enabled = false;
+        }
     }
 
     pub fn set_freq_lo_reg(&mut self, val: u8) {
         self.freq_lo_reg = val;
+        self.update_period();
     }
 
     pub fn set_freq_hi_reg(&mut self, val: u8) {
@@ -64,12 +66,19 @@ impl Square2 {
         // And trigger event...
         if test_bit!(val, 7) {
             self.trigger();
+        } else {
+            self.update_period();
         }
     }
 
     pub fn is_enabled(&self) -> bool {
         self.enabled
     }
+
+    // Current pitch in Hz.
+    pub fn frequency(&self) -> f32 {
+        cycles_to_hz(self.freq_modulo * 8)
+    }
 }
 
 impl Channel for Square2 {
@@ -83,8 +92,8 @@ impl Channel for Square2 {
 
     fn length_clock(&mut self) {
         if self.enabled && test_bit!(self.freq_hi_reg, 6) {
-            self.length_counter -= 1;
-            if self.length_counter == self.length_modulo {
+            self.length_counter = self.length_counter.saturating_sub(1);
+            if self.length_counter == 0 {
                 self.enabled = false;
             }
         }
@@ -130,10 +139,16 @@ impl Channel for Square2 {
 
         self.freq_counter = 0;
         self.length_counter = MAX_LEN;
+
+        self.enabled = false;
     }
 }
 
 impl Square2 {
+    fn update_period(&mut self) {
+        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+    }
+
     fn trigger(&mut self) {
         const LEN_MASK: u8 = bits![5, 4, 3, 2, 1, 0];
         const VOL_MASK: u8 = bits![7, 6, 5, 4];
@@ -143,12 +158,12 @@ impl Square2 {
         self.volume_modulo = self.vol_envelope_reg & VOL_SWEEP_MASK;
         self.volume_counter = if self.volume_modulo == 0 {None} else {Some(0)};
 
-        self.freq_counter = 0;
-        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+        let freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 4;
+        self.freq_counter = trigger_freq_counter(self.freq_counter, self.freq_modulo, freq_modulo, 4);
+        self.freq_modulo = freq_modulo;
 
-        self.length_counter = MAX_LEN;
-        self.length_modulo = self.duty_length_reg & LEN_MASK;
+        self.length_counter = MAX_LEN - (self.duty_length_reg & LEN_MASK);
 
-        self.enabled = true;
+        self.enabled = envelope_dac_enabled(self.vol_envelope_reg);
     }
 }
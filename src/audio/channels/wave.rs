@@ -27,7 +27,6 @@ pub struct Wave {
     shift_amount:   ShiftAmount,
 
     length_counter: u16,
-    length_modulo:  u16,
 
     freq_counter:   u32,
     freq_modulo:    u32,
@@ -51,15 +50,18 @@ impl Wave {
             shift_amount:       ShiftAmount::Mute,
 
             length_counter:     0,
-            length_modulo:      MAX_LEN,
 
             freq_counter:       0,
             freq_modulo:        0,
         }
     }
 
+    // Bit 7 controls the DAC. While it is off, the channel is disabled and can't be triggered.
     pub fn set_playback_reg(&mut self, val: u8) {
         self.playback_reg = val;
+        if !test_bit!(val, 7) {
+            self.enabled = false;
+        }
     }
 
     pub fn set_length_reg(&mut self, val: u8) {
@@ -72,6 +74,7 @@ impl Wave {
 
     pub fn set_freq_lo_reg(&mut self, val: u8) {
         self.freq_lo_reg = val;
+        self.update_period();
     }
 
     pub fn set_freq_hi_reg(&mut self, val: u8) {
@@ -79,6 +82,8 @@ impl Wave {
         // And trigger event...
         if test_bit!(val, 7) {
             self.trigger();
+        } else {
+            self.update_period();
         }
     }
 
@@ -86,6 +91,11 @@ impl Wave {
         self.enabled
     }
 
+    // Current pitch in Hz.
+    pub fn frequency(&self) -> f32 {
+        cycles_to_hz(self.freq_modulo * 32)
+    }
+
     pub fn write_wave(&mut self, loc: u16, val: u8) {
         self.wave_pattern[loc as usize] = val;
     }
@@ -106,8 +116,8 @@ impl Channel for Wave {
 
     fn length_clock(&mut self) {
         if self.enabled && test_bit!(self.freq_hi_reg, 6) {
-            self.length_counter -= 1;
-            if self.length_counter == self.length_modulo {
+            self.length_counter = self.length_counter.saturating_sub(1);
+            if self.length_counter == 0 {
                 self.enabled = false;
             }
         }
@@ -137,6 +147,10 @@ impl Channel for Wave {
 }
 
 impl Wave {
+    fn update_period(&mut self) {
+        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 2;
+    }
+
     fn trigger(&mut self) {
         const SHIFT_MASK: u8 = bits![6, 5];
 
@@ -148,13 +162,13 @@ impl Wave {
             _ => unreachable!()
         };
 
-        self.freq_counter = 0;
-        self.freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 2;
+        let freq_modulo = (2048 - get_freq_modulo(self.freq_hi_reg, self.freq_lo_reg)) * 2;
+        self.freq_counter = trigger_freq_counter(self.freq_counter, self.freq_modulo, freq_modulo, 2);
+        self.freq_modulo = freq_modulo;
 
-        self.length_counter = MAX_LEN;
-        self.length_modulo = self.length_reg as u16;
+        self.length_counter = MAX_LEN - self.length_reg as u16;
 
-        self.enabled = true;
+        self.enabled = test_bit!(self.playback_reg, 7);
     }
 
     fn read_wave_pattern(&self) -> f32 {
@@ -170,3 +184,27 @@ impl Wave {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trigger_keeps_low_timer_bits() {
+        let mut wave = Wave::new();
+        wave.set_playback_reg(0x80);
+        wave.set_freq_lo_reg(0x01);
+        wave.set_freq_hi_reg(0x87);  // Period 0x701: the timer ticks 255 times, every 2 cycles.
+        assert_eq!(wave.freq_counter, 0);
+
+        // 248 ticks remain. The low bits (0) are kept, and the rest reloaded: 252 ticks.
+        wave.sample_clock(14);
+        wave.set_freq_hi_reg(0x87);
+        assert_eq!(wave.freq_counter, (255 - 252) * 2);
+
+        // 250 ticks remain, so the low bits are 2: 254 ticks, one short of the period.
+        wave.sample_clock(4);
+        wave.set_freq_hi_reg(0x87);
+        assert_eq!(wave.freq_counter, 2);
+    }
+}
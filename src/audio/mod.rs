@@ -3,11 +3,19 @@ mod resampler;
 
 use bitflags::bitflags;
 use crossbeam_channel::Sender;
-use dasp::frame::Stereo;
+use dasp::frame::{
+    Frame,
+    Stereo
+};
 
 use crate::mem::MemDevice;
 
-pub use resampler::Resampler;
+use std::collections::VecDeque;
+
+pub use resampler::{
+    ResampleQuality,
+    Resampler
+};
 use channels::{
     Channel,
     square1::Square1,
@@ -70,11 +78,30 @@ impl PowerControl {
 }
 
 const SAMPLE_PACKET_SIZE: usize = 32;
-const CYCLES_PER_SECOND: usize = 154 * 456 * 60;
+const MAX_AUDIO_EVENTS: usize = 1024;
+pub const FRAMES_PER_SECOND: usize = 60;
+const CYCLES_PER_SECOND: usize = 154 * 456 * FRAMES_PER_SECOND;
 const INPUT_SAMPLE_RATE: f64 = 131_072.0;
+const SCOPE_BUFFER_SIZE: usize = 1024;
 
 pub type SamplePacket = Box<[Stereo<f32>]>;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioChannel {
+    Square1,
+    Square2,
+    Wave,
+    Noise
+}
+
+// A channel trigger (note on).
+#[derive(Clone, Copy, Debug)]
+pub struct AudioEvent {
+    pub channel:    AudioChannel,
+    pub frequency:  f32,    // Pitch of the note in Hz.
+    pub cycle:      u64,    // Cycles elapsed since power on.
+}
+
 // The structure that exists in memory. Sends data to the audio thread.
 pub struct AudioDevice {
     // Raw channel data
@@ -87,12 +114,14 @@ pub struct AudioDevice {
     volume_control:     VolumeControl,
     channel_enables:    ChannelEnables,
     power_control:      PowerControl,
+    cgb_mode:           bool,
 
     // Managing output of samples
     sample_buffer:      Vec<Stereo<f32>>,
     sender:             Option<Sender<SamplePacket>>,
     cycle_count:        f64,
     cycles_per_sample:  f64,
+    last_sample:        Stereo<f32>,
 
     vol_left:           f32,
     vol_right:          f32,
@@ -100,10 +129,17 @@ pub struct AudioDevice {
     // Managing clocking channels
     frame_cycle_count:  u32,
     frame_count:        u8,
+
+    // Channel triggers since the last drain
+    events:             Vec<AudioEvent>,
+    cycles_elapsed:     u64,
+
+    // Recent unmixed samples of each channel
+    scope_buffers:      [VecDeque<i8>; 4],
 }
 
 impl AudioDevice {
-    pub fn new() -> Self {
+    pub fn new(cgb_mode: bool) -> Self {
         AudioDevice {
             square_1:   Square1::new(),
             square_2:   Square2::new(),
@@ -112,31 +148,67 @@ impl AudioDevice {
 
             volume_control:     VolumeControl::default(),
             channel_enables:    ChannelEnables::default(),
-            power_control:      PowerControl::default(),
+            power_control:      PowerControl::POWER,    // On after the boot ROM.
+            cgb_mode:           cgb_mode,
 
             sample_buffer:      Vec::new(),
             sender:             None,
             cycle_count:        0.0,
             cycles_per_sample:  0.0,
+            last_sample:        Stereo::EQUILIBRIUM,
 
             vol_left:           0.0,
             vol_right:          0.0,
 
             frame_cycle_count:  0,
             frame_count:        0,
+
+            events:             Vec::new(),
+            cycles_elapsed:     0,
+
+            scope_buffers:      Default::default(),
         }
     }
 
     // Call to enable audio on the appropriate thread (this should be done before any processing)
     pub fn enable_audio(&mut self, sender: Sender<SamplePacket>) {
         self.sender = Some(sender);
+        self.set_cycles_per_sample();
+    }
 
-        let seconds_per_sample = 1.0 / INPUT_SAMPLE_RATE;
-        self.cycles_per_sample = seconds_per_sample * (CYCLES_PER_SECOND as f64);
+    // Generate samples at the normal rate, but discard them instead of sending.
+    pub fn enable_audio_null(&mut self) {
+        self.sender = None;
+        self.set_cycles_per_sample();
+    }
+
+    // Take all channel triggers that have occurred since the last call.
+    pub fn drain_events(&mut self) -> Vec<AudioEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // The most recent samples of each channel before mixing, oldest first.
+    pub fn scope_buffers(&self) -> [Vec<i8>; 4] {
+        [
+            self.scope_buffers[0].iter().cloned().collect(),
+            self.scope_buffers[1].iter().cloned().collect(),
+            self.scope_buffers[2].iter().cloned().collect(),
+            self.scope_buffers[3].iter().cloned().collect(),
+        ]
+    }
+
+    // Wave RAM, accessed directly regardless of whether the channel is playing.
+    pub fn get_wave_ram(&self) -> [u8; 16] {
+        self.wave.wave_pattern
+    }
+
+    pub fn set_wave_ram(&mut self, data: [u8; 16]) {
+        self.wave.wave_pattern = data;
     }
 
     pub fn clock(&mut self, cycles: u32) {
         self.cycle_count += cycles as f64;
+        self.cycles_elapsed += cycles as u64;
 
         // Modify channels
         self.clock_channels(cycles);
@@ -146,15 +218,26 @@ impl AudioDevice {
 
             // Generate sample
             let sample = self.generate_sample();
-            self.sample_buffer.push(sample);
-            
-            // Output to audio thread
-            if self.sample_buffer.len() > SAMPLE_PACKET_SIZE {
-                let sample_packet = self.sample_buffer.drain(..).collect::<SamplePacket>();
-                if let Some(s) = &self.sender {
-                    s.send(sample_packet).expect("Error sending!");
-                }
-            }
+            self.last_sample = sample;
+            self.output_sample(sample);
+        }
+    }
+
+    // Output silence without clocking the channels, fading out from the last sample.
+    // This keeps the audio thread fed while emulation is paused.
+    pub fn clock_silence(&mut self, cycles: u32) {
+        const FADE_FACTOR: f32 = 0.99;
+
+        if self.cycles_per_sample == 0.0 {
+            return;
+        }
+
+        self.cycle_count += cycles as f64;
+        while self.cycle_count >= self.cycles_per_sample {
+            self.cycle_count -= self.cycles_per_sample;
+
+            self.last_sample = self.last_sample.scale_amp(FADE_FACTOR);
+            self.output_sample(self.last_sample);
         }
     }
 }
@@ -186,6 +269,7 @@ impl MemDevice for AudioDevice {
 
             0xFF24  => self.volume_control.bits(),
             0xFF25  => self.channel_enables.bits(),
+            // Playing bits reflect the channel state. Unused bits read as 1.
             0xFF26  => {
                 let mut bits = self.power_control;
                 bits.set(PowerControl::PLAYING_1, self.square_1.is_enabled());
@@ -193,7 +277,7 @@ impl MemDevice for AudioDevice {
                 bits.set(PowerControl::PLAYING_3, self.wave.is_enabled());
                 bits.set(PowerControl::PLAYING_4, self.noise.is_enabled());
 
-                bits.bits()
+                bits.bits() | bits![6, 5, 4]
             },
 
             0xFF30..=0xFF3F => self.wave.read_wave(loc - 0xFF30),
@@ -203,28 +287,67 @@ impl MemDevice for AudioDevice {
     }
 
     fn write(&mut self, loc: u16, val: u8) {
+        if !self.power_control.is_on() {
+            // While powered off, only power control and wave RAM can be written.
+            // DMG can also write the length counters, but not the square duty.
+            match loc {
+                0xFF26 | 0xFF30..=0xFF3F => {},
+                0xFF11 if !self.cgb_mode => {
+                    let duty = self.square_1.duty_length_reg & 0xC0;
+                    return self.square_1.set_duty_length_reg(duty | (val & 0x3F));
+                },
+                0xFF16 if !self.cgb_mode => {
+                    let duty = self.square_2.duty_length_reg & 0xC0;
+                    return self.square_2.set_duty_length_reg(duty | (val & 0x3F));
+                },
+                0xFF1B if !self.cgb_mode => return self.wave.set_length_reg(val),
+                0xFF20 if !self.cgb_mode => return self.noise.set_length_reg(val),
+                _ => return,
+            }
+        }
+
         match loc {
             0xFF10  => self.square_1.set_sweep_reg(val),
             0xFF11  => self.square_1.set_duty_length_reg(val),
             0xFF12  => self.square_1.set_vol_envelope_reg(val),
             0xFF13  => self.square_1.set_freq_lo_reg(val),
-            0xFF14  => self.square_1.set_freq_hi_reg(val),
+            0xFF14  => {
+                self.square_1.set_freq_hi_reg(val);
+                if test_bit!(val, 7) {
+                    self.push_event(AudioChannel::Square1);
+                }
+            },
 
             0xFF16  => self.square_2.set_duty_length_reg(val),
             0xFF17  => self.square_2.set_vol_envelope_reg(val),
             0xFF18  => self.square_2.set_freq_lo_reg(val),
-            0xFF19  => self.square_2.set_freq_hi_reg(val),
+            0xFF19  => {
+                self.square_2.set_freq_hi_reg(val);
+                if test_bit!(val, 7) {
+                    self.push_event(AudioChannel::Square2);
+                }
+            },
 
             0xFF1A  => self.wave.set_playback_reg(val),
             0xFF1B  => self.wave.set_length_reg(val),
             0xFF1C  => self.wave.set_vol_reg(val),
             0xFF1D  => self.wave.set_freq_lo_reg(val),
-            0xFF1E  => self.wave.set_freq_hi_reg(val),
+            0xFF1E  => {
+                self.wave.set_freq_hi_reg(val);
+                if test_bit!(val, 7) {
+                    self.push_event(AudioChannel::Wave);
+                }
+            },
 
             0xFF20  => self.noise.set_length_reg(val),
             0xFF21  => self.noise.set_vol_envelope_reg(val),
             0xFF22  => self.noise.set_poly_counter_reg(val),
-            0xFF23  => self.noise.set_trigger_reg(val),
+            0xFF23  => {
+                self.noise.set_trigger_reg(val);
+                if test_bit!(val, 7) {
+                    self.push_event(AudioChannel::Noise);
+                }
+            },
 
             0xFF24  => {
                 const REDUCTION_FACTOR: f32 = 1.0 / (4.0 * 7.0);    // 4 channels, max vol = 7
@@ -234,13 +357,17 @@ impl MemDevice for AudioDevice {
                 self.volume_control = vol_ctrl;
             },
             0xFF25  => self.channel_enables = ChannelEnables::from_bits_truncate(val),
+            // Only the power bit can be written.
             0xFF26  => {
                 let power_on = test_bit!(val, 7);
                 if !power_on {
                     self.power_control.remove(PowerControl::POWER);
                     self.reset();
-                } else {
+                } else if !self.power_control.is_on() {
+                    // The frame sequencer restarts, so the first step (a length clock) is 8192 cycles away.
                     self.power_control.insert(PowerControl::POWER);
+                    self.frame_cycle_count = 0;
+                    self.frame_count = 0;
                 }
             },
 
@@ -252,6 +379,26 @@ impl MemDevice for AudioDevice {
 }
 
 impl AudioDevice {
+    fn set_cycles_per_sample(&mut self) {
+        let seconds_per_sample = 1.0 / INPUT_SAMPLE_RATE;
+        self.cycles_per_sample = seconds_per_sample * (CYCLES_PER_SECOND as f64);
+    }
+
+    fn output_sample(&mut self, sample: Stereo<f32>) {
+        self.sample_buffer.push(sample);
+
+        // Output to audio thread
+        if self.sample_buffer.len() > SAMPLE_PACKET_SIZE {
+            let sample_packet = self.sample_buffer.drain(..).collect::<SamplePacket>();
+            let disconnected = self.sender.as_ref()
+                .is_some_and(|s| s.send(sample_packet).is_err());
+            if disconnected {
+                log::warn!("Audio receiver disconnected: disabling audio output");
+                self.sender = None;
+            }
+        }
+    }
+
     fn generate_sample(&mut self) -> Stereo<f32> {
         if self.power_control.is_on() {
             let square_1 = self.square_1.get_sample();
@@ -259,6 +406,8 @@ impl AudioDevice {
             let wave = self.wave.get_sample();
             let noise = self.noise.get_sample();
 
+            self.capture_scope([square_1, square_2, wave, noise]);
+
             let left_1 = if self.channel_enables.contains(ChannelEnables::LEFT_1) {square_1} else {0.0};
             let left_2 = if self.channel_enables.contains(ChannelEnables::LEFT_2) {square_2} else {0.0};
             let left_3 = if self.channel_enables.contains(ChannelEnables::LEFT_3) {wave} else {0.0};
@@ -274,10 +423,37 @@ impl AudioDevice {
 
             [left_mixed, right_mixed]
         } else {
+            self.capture_scope([0.0; 4]);
             [0.0, 0.0]
         }
     }
 
+    fn capture_scope(&mut self, samples: [f32; 4]) {
+        for (buffer, sample) in self.scope_buffers.iter_mut().zip(samples.iter()) {
+            if buffer.len() >= SCOPE_BUFFER_SIZE {
+                buffer.pop_front();
+            }
+            buffer.push_back((sample * 127.0) as i8);
+        }
+    }
+
+    fn push_event(&mut self, channel: AudioChannel) {
+        if self.events.len() < MAX_AUDIO_EVENTS {
+            let frequency = match channel {
+                AudioChannel::Square1   => self.square_1.frequency(),
+                AudioChannel::Square2   => self.square_2.frequency(),
+                AudioChannel::Wave      => self.wave.frequency(),
+                AudioChannel::Noise     => self.noise.frequency(),
+            };
+
+            self.events.push(AudioEvent {
+                channel:    channel,
+                frequency:  frequency,
+                cycle:      self.cycles_elapsed,
+            });
+        }
+    }
+
     fn reset(&mut self) {
         self.square_1.reset();
         self.square_2.reset();
@@ -288,6 +464,7 @@ impl AudioDevice {
         self.channel_enables = ChannelEnables::default();
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn clock_channels(&mut self, cycles: u32) {
         const FRAME_MODULO: u32 = 8192; // Clock rate / 8192 = 512
         // Advance samples
@@ -324,4 +501,159 @@ impl AudioDevice {
             self.frame_count = (self.frame_count + 1) % 8;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_sink_discards_samples() {
+        let mut audio = AudioDevice::new(false);
+        audio.enable_audio_null();
+        for _ in 0..(154 * 456) {
+            audio.clock(4);
+        }
+
+        // Samples are generated, but never build up.
+        assert_eq!(audio.scope_buffers()[0].len(), SCOPE_BUFFER_SIZE);
+        assert!(audio.sample_buffer.len() <= SAMPLE_PACKET_SIZE);
+    }
+
+    #[test]
+    fn square_scope_buffer() {
+        let mut audio = AudioDevice::new(false);
+        audio.enable_audio_null();
+        audio.write(0xFF11, 0x00);  // 12.5% duty
+        audio.write(0xFF12, 0xF0);
+        audio.write(0xFF13, 0x00);
+        audio.write(0xFF14, 0x87);  // Period 0x700: 1024 cycles per duty step, 8 steps per wave.
+        for _ in 0..(154 * 456 / 4) {
+            audio.clock(4);
+        }
+
+        let scope = audio.scope_buffers();
+        assert_eq!(scope[0].len(), SCOPE_BUFFER_SIZE);
+        assert!(scope[0].iter().all(|&sample| sample == 127 || sample == -127));
+        let highs = scope[0].iter().filter(|&&sample| sample > 0).count();
+        assert!((SCOPE_BUFFER_SIZE / 8 - 16..=SCOPE_BUFFER_SIZE / 8 + 16).contains(&highs), "{} high samples", highs);
+        // One high pulse per wave: 8192 cycles, or about 255 samples.
+        let rising_edges = scope[0].windows(2).filter(|pair| pair[0] < 0 && pair[1] > 0).count();
+        assert!((3..=4).contains(&rising_edges), "{} rising edges", rising_edges);
+
+        assert!(scope[1..].iter().all(|buffer| buffer.iter().all(|&sample| sample == 0)));
+    }
+
+    #[test]
+    fn trigger_event() {
+        let mut audio = AudioDevice::new(false);
+        audio.write(0xFF12, 0xF0);
+        audio.write(0xFF13, 0xD6);
+        audio.write(0xFF14, 0x86);  // Period 0x6D6: 131072 / (2048 - 1750) = 439.8Hz
+
+        let events = audio.drain_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].channel, AudioChannel::Square1);
+        assert!((events[0].frequency - 439.8).abs() < 0.1);
+        assert!(audio.drain_events().is_empty());
+    }
+
+    #[test]
+    fn wave_ram_round_trip() {
+        let mut audio = AudioDevice::new(false);
+        let wave: [u8; 16] = core::array::from_fn(|i| (i as u8) * 0x11);
+        audio.set_wave_ram(wave);
+        assert_eq!(audio.get_wave_ram(), wave);
+        assert_eq!(audio.read(0xFF30), 0x00);
+        assert_eq!(audio.read(0xFF3F), 0xFF);
+    }
+
+    #[test]
+    fn power_control_write() {
+        let mut audio = AudioDevice::new(false);
+        audio.write(0xFF26, 0x8F);
+        assert_eq!(audio.read(0xFF26), 0xF0);
+
+        audio.write(0xFF17, 0xF0);
+        audio.write(0xFF19, 0x80);
+        audio.write(0xFF26, 0x80);
+        assert_eq!(audio.read(0xFF26), 0xF2);
+
+        // Powering off stops every channel.
+        audio.write(0xFF26, 0x0F);
+        assert_eq!(audio.read(0xFF26), 0x70);
+    }
+
+    #[test]
+    fn power_on_frame_sequencer() {
+        let mut audio = AudioDevice::new(false);
+        for _ in 0..3 {
+            audio.clock_channels(8192);
+        }
+        audio.clock_channels(4000);
+        assert_eq!((audio.frame_count, audio.frame_cycle_count), (3, 4000));
+
+        audio.write(0xFF26, 0x00);
+        audio.write(0xFF26, 0x80);
+        assert_eq!((audio.frame_count, audio.frame_cycle_count), (0, 0));
+
+        // Square 2 with 1 length step left: the first sequencer step clocks length.
+        audio.write(0xFF16, 0x3F);
+        audio.write(0xFF17, 0xF0);
+        audio.write(0xFF19, 0xC0);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x02);
+        audio.clock_channels(8191);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x02);
+        audio.clock_channels(1);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x00);
+
+        // Writing the power bit again while on doesn't restart the sequencer.
+        audio.clock_channels(4000);
+        audio.write(0xFF26, 0x80);
+        assert_eq!((audio.frame_count, audio.frame_cycle_count), (1, 4000));
+    }
+
+    #[test]
+    fn dac_enable() {
+        let mut audio = AudioDevice::new(false);
+        audio.write(0xFF17, 0xF0);
+        audio.write(0xFF19, 0x80);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x02);
+
+        // DAC off: the channel is disabled, and can't be triggered.
+        audio.write(0xFF17, 0x00);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x00);
+        audio.write(0xFF19, 0x80);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x00);
+
+        // Zero volume with an increasing envelope turns the DAC on.
+        audio.write(0xFF17, 0x08);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x00);
+        audio.write(0xFF19, 0x80);
+        assert_eq!(audio.read(0xFF26) & 0x02, 0x02);
+
+        audio.write(0xFF1A, 0x00);
+        audio.write(0xFF1E, 0x80);
+        assert_eq!(audio.read(0xFF26) & 0x04, 0x00);
+        audio.write(0xFF1A, 0x80);
+        audio.write(0xFF1E, 0x80);
+        assert_eq!(audio.read(0xFF26) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn powered_off_writes() {
+        for &cgb_mode in [false, true].iter() {
+            let mut audio = AudioDevice::new(cgb_mode);
+            audio.write(0xFF26, 0x00);
+
+            audio.write(0xFF12, 0xF0);
+            assert_eq!(audio.read(0xFF12), 0x00);
+            audio.write(0xFF30, 0x12);
+            assert_eq!(audio.read(0xFF30), 0x12);
+
+            // DMG length counters can be written, but not the duty.
+            audio.write(0xFF16, 0xFF);
+            assert_eq!(audio.read(0xFF16), if cgb_mode {0x00} else {0x3F});
+        }
+    }
+}
@@ -1,8 +1,15 @@
 // Module that resamples from 32_000 to the output sample rate.
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{
+    never,
+    Receiver
+};
 use dasp::{
     frame::{Frame, Stereo},
-    interpolate::sinc::Sinc,
+    interpolate::{
+        linear::Linear,
+        sinc::Sinc,
+        Interpolator
+    },
     ring_buffer::Fixed,
     signal::{
         interpolate::Converter,
@@ -10,17 +17,70 @@ use dasp::{
     }
 };
 
+// High-pass capacitor charge factor per clock cycle (at 4.19MHz).
+const HIGHPASS_CHARGE: f64 = 0.999958;
+const CLOCK_RATE: f64 = 4_194_304.0;
+
+// Frames used by the sinc interpolator. Half of these are either side of the output sample.
+const SINC_FRAMES: usize = 16;
+
+// Interpolation used when resampling. Higher quality takes more CPU time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResampleQuality {
+    #[default]
+    Linear, // Straight line between input samples.
+    Cubic,  // Cubic spline through the last 3 input samples. No more delay than linear.
+    Sinc,   // Windowed sinc over 16 input samples. Cleanest high frequencies, for recording.
+}
+
 pub struct Resampler {
-    converter: Converter<Source, Sinc<[Stereo<f32>; 2]>>
+    converter:          Converter<Source, Interpolation>,
+    target_sample_rate: f64,
+    quality:            ResampleQuality,
+
+    highpass:           bool,
+    highpass_charge:    f32,
+    capacitor:          Stereo<f32>,
 }
 
 impl Resampler {
     pub fn new(receiver: Receiver<super::SamplePacket>, target_sample_rate: f64) -> Self {
-        let sinc = Sinc::new(Fixed::from([Stereo::EQUILIBRIUM; 2]));
         Resampler {
-            converter: Source::new(receiver).from_hz_to_hz(sinc, super::INPUT_SAMPLE_RATE, target_sample_rate)
+            converter:          Source::new(receiver).from_hz_to_hz(Interpolation::new(ResampleQuality::default()), super::INPUT_SAMPLE_RATE, target_sample_rate),
+            target_sample_rate: target_sample_rate,
+            quality:            ResampleQuality::default(),
+
+            highpass:           true,
+            highpass_charge:    HIGHPASS_CHARGE.powf(CLOCK_RATE / target_sample_rate) as f32,
+            capacitor:          Stereo::EQUILIBRIUM,
         }
     }
+
+    // The hardware output passes through a DC-blocking capacitor.
+    pub fn set_highpass(&mut self, enabled: bool) {
+        self.highpass = enabled;
+        self.capacitor = Stereo::EQUILIBRIUM;
+    }
+
+    // Swap the interpolator. Its history is reset, but input samples aren't lost.
+    pub fn set_quality(&mut self, quality: ResampleQuality) {
+        let placeholder = Source::new(never()).from_hz_to_hz(Interpolation::new(quality), super::INPUT_SAMPLE_RATE, self.target_sample_rate);
+        let source = std::mem::replace(&mut self.converter, placeholder).into_source();
+        self.converter = source.from_hz_to_hz(Interpolation::new(quality), super::INPUT_SAMPLE_RATE, self.target_sample_rate);
+        self.quality = quality;
+    }
+
+    // Output samples' worth of input that has been generated but not yet output.
+    // This is the queued packets, plus the frames the interpolator looks ahead.
+    pub fn latency_samples(&self) -> usize {
+        let lookahead = match self.quality {
+            ResampleQuality::Linear => 1,
+            ResampleQuality::Cubic  => 1,
+            ResampleQuality::Sinc   => SINC_FRAMES / 2,
+        };
+        let input_frames = self.converter.source().buffered_frames() + lookahead;
+        (input_frames as f64 * self.target_sample_rate / super::INPUT_SAMPLE_RATE).round() as usize
+    }
 }
 
 impl Iterator for Resampler {
@@ -28,7 +88,91 @@ impl Iterator for Resampler {
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.converter.is_exhausted() {}
-        Some(self.converter.next())
+        let frame = self.converter.next();
+        if self.highpass {
+            let out = [frame[0] - self.capacitor[0], frame[1] - self.capacitor[1]];
+            self.capacitor = [
+                frame[0] - out[0] * self.highpass_charge,
+                frame[1] - out[1] * self.highpass_charge
+            ];
+            Some(out)
+        } else {
+            Some(frame)
+        }
+    }
+}
+
+enum Interpolation {
+    Linear(Linear<Stereo<f32>>),
+    Cubic(Cubic),
+    Sinc(Sinc<[Stereo<f32>; SINC_FRAMES]>),
+}
+
+impl Interpolation {
+    fn new(quality: ResampleQuality) -> Self {
+        match quality {
+            ResampleQuality::Linear => Interpolation::Linear(Linear::new(Stereo::EQUILIBRIUM, Stereo::EQUILIBRIUM)),
+            ResampleQuality::Cubic  => Interpolation::Cubic(Cubic::new()),
+            ResampleQuality::Sinc   => Interpolation::Sinc(Sinc::new(Fixed::from([Stereo::EQUILIBRIUM; SINC_FRAMES]))),
+        }
+    }
+}
+
+impl Interpolator for Interpolation {
+    type Frame = Stereo<f32>;
+
+    fn interpolate(&self, x: f64) -> Self::Frame {
+        match self {
+            Interpolation::Linear(i)    => i.interpolate(x),
+            Interpolation::Cubic(i)     => i.interpolate(x),
+            Interpolation::Sinc(i)      => i.interpolate(x),
+        }
+    }
+
+    fn next_source_frame(&mut self, source_frame: Self::Frame) {
+        match self {
+            Interpolation::Linear(i)    => i.next_source_frame(source_frame),
+            Interpolation::Cubic(i)     => i.next_source_frame(source_frame),
+            Interpolation::Sinc(i)      => i.next_source_frame(source_frame),
+        }
+    }
+}
+
+// Interpolates between the last 2 frames, so it has the same delay as linear interpolation.
+// The slopes at each end are estimated from the last 3 frames, which is exact for quadratics.
+struct Cubic {
+    frames: [Stereo<f32>; 3],
+}
+
+impl Cubic {
+    fn new() -> Self {
+        Cubic {
+            frames: [Stereo::EQUILIBRIUM; 3]
+        }
+    }
+}
+
+impl Interpolator for Cubic {
+    type Frame = Stereo<f32>;
+
+    fn interpolate(&self, x: f64) -> Self::Frame {
+        let x = x as f32;
+        let [p0, p1, p2] = self.frames;
+        let mut out = Stereo::EQUILIBRIUM;
+        for (c, o) in out.iter_mut().enumerate() {
+            // Cubic Hermite spline from p1 to p2.
+            let m1 = 0.5 * (p2[c] - p0[c]);
+            let m2 = 0.5 * (p0[c] - 4.0 * p1[c] + 3.0 * p2[c]);
+            let a = 2.0 * (p1[c] - p2[c]) + m1 + m2;
+            let b = 3.0 * (p2[c] - p1[c]) - 2.0 * m1 - m2;
+            *o = ((a * x + b) * x + m1) * x + p1[c];
+        }
+        out
+    }
+
+    fn next_source_frame(&mut self, source_frame: Self::Frame) {
+        self.frames.rotate_left(1);
+        self.frames[2] = source_frame;
     }
 }
 
@@ -49,6 +193,11 @@ impl Source {
             n:          0,
         }
     }
+
+    // All packets are the same size.
+    fn buffered_frames(&self) -> usize {
+        self.receiver.len() * (super::SAMPLE_PACKET_SIZE + 1) + (self.current.len() - self.n)
+    }
 }
 
 impl Signal for Source {
@@ -65,4 +214,70 @@ impl Signal for Source {
             self.current[0]
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RMS error resampling a sine sweep from 100Hz to 8kHz, compared to the ideal output.
+    fn sweep_error(quality: ResampleQuality) -> f64 {
+        const TARGET_RATE: f64 = 44_100.0;
+        const INPUT_FRAMES: usize = 131_072 / 4;
+        let sweep = |n: f64| {
+            let t = n / super::super::INPUT_SAMPLE_RATE;
+            let end = INPUT_FRAMES as f64 / super::super::INPUT_SAMPLE_RATE;
+            (2.0 * std::f64::consts::PI * (100.0 * t + 7_900.0 * t * t / (2.0 * end))).sin()
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let input = (0..INPUT_FRAMES).map(|n| [sweep(n as f64) as f32; 2]).collect::<Vec<_>>();
+        for packet in input.chunks(super::super::SAMPLE_PACKET_SIZE) {
+            sender.send(packet.to_vec().into_boxed_slice()).unwrap();
+        }
+
+        let mut resampler = Resampler::new(receiver, TARGET_RATE);
+        resampler.set_quality(quality);
+        resampler.set_highpass(false);
+
+        // Both interpolators lag the input by two frames. Skip the start, where they're filling up.
+        let step = super::super::INPUT_SAMPLE_RATE / TARGET_RATE;
+        let outputs = ((INPUT_FRAMES - 16) as f64 / step) as usize;
+        let errors = resampler.take(outputs).enumerate().skip(16).map(|(n, frame)| {
+            let error = frame[0] as f64 - sweep(n as f64 * step - 2.0);
+            error * error
+        }).collect::<Vec<_>>();
+        (errors.iter().sum::<f64>() / errors.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn cubic_beats_linear() {
+        let linear = sweep_error(ResampleQuality::Linear);
+        let cubic = sweep_error(ResampleQuality::Cubic);
+        assert!(linear > 1e-4, "linear error {}", linear);
+        assert!(cubic < linear / 4.0, "cubic error {}, linear error {}", cubic, linear);
+    }
+
+    // Resample a quarter of a second of constant input.
+    fn dc_output(highpass: bool) -> Vec<f32> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for _ in 0..(32_768 / super::super::SAMPLE_PACKET_SIZE) {
+            sender.send(vec![[0.5, -0.5]; super::super::SAMPLE_PACKET_SIZE].into_boxed_slice()).unwrap();
+        }
+
+        let mut resampler = Resampler::new(receiver, 44_100.0);
+        resampler.set_highpass(highpass);
+        resampler.take(11_000).map(|frame| frame[0]).collect()
+    }
+
+    #[test]
+    fn highpass() {
+        // The step from silence passes through, then the DC offset decays away (time constant about 6ms).
+        let filtered = dc_output(true);
+        assert!(filtered[0..100].iter().any(|&sample| sample > 0.4));
+        assert!(filtered[3_000..].iter().all(|&sample| sample.abs() < 0.001));
+
+        let unfiltered = dc_output(false);
+        assert!(unfiltered[3_000..].iter().all(|&sample| (sample - 0.5).abs() < 0.001));
+    }
+}
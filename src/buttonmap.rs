@@ -0,0 +1,140 @@
+// Mapping from front-end key codes to buttons and hotkeys.
+use std::collections::HashMap;
+
+use bitflags::bitflags;
+
+use crate::Button;
+
+bitflags! {
+    // Modifier keys held alongside a hotkey.
+    #[derive(Default)]
+    pub struct Modifiers: u8 {
+        const SHIFT = bit!(0);
+        const CTRL  = bit!(1);
+        const ALT   = bit!(2);
+    }
+}
+
+// Emulator actions for the front-end to carry out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    Reset,
+    SaveState,
+    LoadState,
+    FastForward,
+    Pause,
+    Screenshot,
+}
+
+// Key codes used by the default layout: winit's VirtualKeyCode discriminants, cast to u32.
+// These follow the order of the enum (Key1-Key0 are 0-9, A-Z are 10-35, and so on).
+pub mod keycode {
+    pub const R:        u32 = 27;
+    pub const S:        u32 = 28;
+    pub const X:        u32 = 33;
+    pub const Z:        u32 = 35;
+    pub const LEFT:     u32 = 70;
+    pub const UP:       u32 = 71;
+    pub const RIGHT:    u32 = 72;
+    pub const DOWN:     u32 = 73;
+    pub const RETURN:   u32 = 75;
+    pub const SPACE:    u32 = 76;
+    pub const TAB:      u32 = 146;
+}
+
+const DEFAULT_KEYS: [(u32, Button); 8] = [
+    (keycode::X,        Button::A),
+    (keycode::Z,        Button::B),
+    (keycode::SPACE,    Button::Select),
+    (keycode::RETURN,   Button::Start),
+    (keycode::UP,       Button::Up),
+    (keycode::DOWN,     Button::Down),
+    (keycode::LEFT,     Button::Left),
+    (keycode::RIGHT,    Button::Right),
+];
+
+// Other hotkeys are unbound by default.
+const DEFAULT_HOTKEYS: [(u32, Modifiers, Hotkey); 3] = [
+    (keycode::R,    Modifiers::SHIFT,   Hotkey::Reset),
+    (keycode::S,    Modifiers::SHIFT,   Hotkey::SaveState),
+    (keycode::TAB,  Modifiers::empty(), Hotkey::FastForward),
+];
+
+#[derive(Clone)]
+pub struct ButtonMap {
+    keys:       HashMap<u32, Button>,
+    hotkeys:    HashMap<(u32, Modifiers), Hotkey>,
+}
+
+impl ButtonMap {
+    // A map with no keys bound.
+    pub fn new() -> Self {
+        ButtonMap {
+            keys:       HashMap::new(),
+            hotkeys:    HashMap::new(),
+        }
+    }
+
+    // Bind a key to a button, replacing any existing binding for the key.
+    // Several keys can be bound to the same button.
+    pub fn insert(&mut self, keycode: u32, button: Button) {
+        self.keys.insert(keycode, button);
+    }
+
+    pub fn remove(&mut self, keycode: u32) {
+        self.keys.remove(&keycode);
+    }
+
+    pub fn get(&self, keycode: u32) -> Option<Button> {
+        self.keys.get(&keycode).cloned()
+    }
+
+    // Bind a key combo to a hotkey. Hotkeys are kept separate from the game keys,
+    // so the same key can be a button on its own and a hotkey with modifiers.
+    pub fn insert_hotkey(&mut self, keycode: u32, modifiers: Modifiers, hotkey: Hotkey) {
+        self.hotkeys.insert((keycode, modifiers), hotkey);
+    }
+
+    pub fn remove_hotkey(&mut self, keycode: u32, modifiers: Modifiers) {
+        self.hotkeys.remove(&(keycode, modifiers));
+    }
+
+    // Modifiers must match exactly.
+    pub fn get_hotkey(&self, keycode: u32, modifiers: Modifiers) -> Option<Hotkey> {
+        self.hotkeys.get(&(keycode, modifiers)).cloned()
+    }
+}
+
+impl Default for ButtonMap {
+    fn default() -> Self {
+        ButtonMap {
+            keys:       DEFAULT_KEYS.iter().cloned().collect(),
+            hotkeys:    DEFAULT_HOTKEYS.iter().map(|&(k, m, h)| ((k, m), h)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keys() {
+        let map = ButtonMap::default();
+        assert_eq!(map.get(keycode::X), Some(Button::A));
+        assert_eq!(map.get(keycode::Z), Some(Button::B));
+        assert_eq!(map.get(keycode::SPACE), Some(Button::Select));
+        assert_eq!(map.get(keycode::RETURN), Some(Button::Start));
+        assert_eq!(map.get(keycode::UP), Some(Button::Up));
+        assert_eq!(map.get(keycode::DOWN), Some(Button::Down));
+        assert_eq!(map.get(keycode::LEFT), Some(Button::Left));
+        assert_eq!(map.get(keycode::RIGHT), Some(Button::Right));
+        assert_eq!(map.get(keycode::R), None);
+
+        assert_eq!(map.get_hotkey(keycode::R, Modifiers::SHIFT), Some(Hotkey::Reset));
+        assert_eq!(map.get_hotkey(keycode::S, Modifiers::SHIFT), Some(Hotkey::SaveState));
+        assert_eq!(map.get_hotkey(keycode::TAB, Modifiers::empty()), Some(Hotkey::FastForward));
+        assert_eq!(map.get_hotkey(keycode::R, Modifiers::SHIFT | Modifiers::CTRL), None);
+        assert_eq!(map.get_hotkey(keycode::X, Modifiers::empty()), None);
+    }
+}
@@ -0,0 +1,100 @@
+// Cheat codes.
+// Game Genie codes patch ROM reads, and GameShark codes write to RAM every frame.
+
+// Returned when a cheat is added, to remove it later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CheatId(pub(crate) u32);
+
+// Replace the byte read from a ROM address.
+// With a compare value, the byte is only replaced if it matches, so other banks are unaffected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RomPatch {
+    pub addr:       u16,
+    pub val:        u8,
+    pub compare:    Option<u8>,
+}
+
+// Write a byte to RAM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RamPoke {
+    pub addr:   u16,
+    pub val:    u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cheat {
+    GameGenie(RomPatch),
+    GameShark(RamPoke),
+}
+
+// Game Genie codes are 6 or 9 hex digits (ABC-DEF or ABC-DEF-GHI).
+// GameShark codes are 8 hex digits (TTVVLLHH): type, value, then the address low byte first.
+pub fn parse(code: &str) -> Result<Cheat, String> {
+    let digits = code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8).ok_or_else(|| format!("invalid character '{}' in cheat code", c)))
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    match digits.len() {
+        6 | 9 => parse_game_genie(&digits).map(Cheat::GameGenie),
+        8 if !code.contains('-') => parse_game_shark(&digits).map(Cheat::GameShark),
+        _ => Err(format!("unrecognised cheat code: {}", code)),
+    }
+}
+
+// The address nibbles are scrambled, and the top one is inverted.
+// The compare value is rotated right by 2 and XORed with 0xBA. The 8th digit is unused.
+fn parse_game_genie(d: &[u8]) -> Result<RomPatch, String> {
+    let val = (d[0] << 4) | d[1];
+    let addr = (((d[5] ^ 0xF) as u16) << 12) | ((d[2] as u16) << 8) | ((d[3] as u16) << 4) | (d[4] as u16);
+    if addr >= 0x8000 {
+        return Err(format!("Game Genie address {:04X} is outside ROM", addr));
+    }
+
+    let compare = if d.len() == 9 {
+        Some(((d[6] << 4) | d[8]).rotate_right(2) ^ 0xBA)
+    } else {
+        None
+    };
+
+    Ok(RomPatch {
+        addr:       addr,
+        val:        val,
+        compare:    compare,
+    })
+}
+
+// The type byte selects a RAM bank on some devices. Writes here go to whichever bank is mapped.
+fn parse_game_shark(d: &[u8]) -> Result<RamPoke, String> {
+    let byte = |i: usize| (d[i * 2] << 4) | d[i * 2 + 1];
+    let val = byte(1);
+    let addr = make_16!(byte(3), byte(2));
+    if !(0xA000..0xE000).contains(&addr) {
+        return Err(format!("GameShark address {:04X} is outside RAM", addr));
+    }
+
+    Ok(RamPoke {
+        addr:   addr,
+        val:    val,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_genie() {
+        assert_eq!(parse("00A-17B-C49"), Ok(Cheat::GameGenie(RomPatch {addr: 0x4A17, val: 0x00, compare: Some(0xC8)})));
+        assert_eq!(parse("3EA-17B"), Ok(Cheat::GameGenie(RomPatch {addr: 0x4A17, val: 0x3E, compare: None})));
+        assert!(parse("00A-170-C49").is_err());     // Address 0xFA17
+    }
+
+    #[test]
+    fn game_shark() {
+        assert_eq!(parse("014234C1"), Ok(Cheat::GameShark(RamPoke {addr: 0xC134, val: 0x42})));
+        assert!(parse("01423480").is_err());        // Address 0x8034
+        assert!(parse("0142-34C1").is_err());
+        assert!(parse("01G234C1").is_err());
+    }
+}
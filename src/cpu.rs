@@ -3,17 +3,26 @@ use bitflags::bitflags;
 use crossbeam_channel::Sender;
 
 use crate::{
-    audio::SamplePacket,
-    mem::{MemBus, MemDevice},
+    audio::{
+        AudioEvent,
+        SamplePacket
+    },
+    mem::{BankState, CartFeatureSet, DisabledRamRead, IoHandler, IrTransceiver, MemBus, MemDevice, SerialPort},
     interrupt::*,
     joypad::{
         Buttons,
         Directions
-    }
+    },
+    video::ColourCorrection,
+    cheats::{Cheat, CheatId}
 };
 
-use std::sync::{
-    Arc, Mutex
+use std::{
+    hash::Hasher,
+    ops::RangeInclusive,
+    sync::{
+        Arc, Mutex
+    }
 };
 
 bitflags! {
@@ -29,7 +38,17 @@ bitflags! {
 const GB_STEP: u32 = 4;
 const CGB_STEP: u32 = 2;
 
+// CPU power states, reported when they change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerState {
+    Running,
+    Halted,
+    Stopped,
+    DoubleSpeed
+}
+
 // LR35902 CPU
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     // Accumulator
     a: u8,
@@ -48,6 +67,8 @@ pub struct CPU {
     // Interrupts
     ime: bool,
     cont: bool,
+    halt_bug: bool,
+    stopped: bool,
 
     // Stack Pointer & PC
     sp: u16,
@@ -60,7 +81,11 @@ pub struct CPU {
     step_cycles:        u32,
     v_blank_latch:      bool,
     double_speed_latch: bool,
-    cgb_dma_active:     bool
+    cgb_dma_active:     bool,
+    cycle_count:        u64,    // At the 4.19MHz clock, so double speed doesn't change the rate.
+    instr_count:        u64,
+
+    power_state_callback:   Option<Box<dyn FnMut(PowerState)>>
 }
 
 
@@ -127,27 +152,43 @@ impl CPU {
             h:      0x01,
             l:      0x4D,
             flags:  CPUFlags::ZERO | CPUFlags::HC | CPUFlags::CARRY,
-            ime:    true,
-            cont:   true,
+            ime:    false,  // The boot ROM exits with interrupts disabled.
+            cont:   true,   // Not halted, so the first step executes the instruction at 0x100.
+            halt_bug:   false,
+            stopped:    false,
             sp:     0xFFFE,
             pc:     0x100,
             mem:    mem,
             step_cycles:        GB_STEP,
             v_blank_latch:      false,
             double_speed_latch: false,
-            cgb_dma_active:     false
+            cgb_dma_active:     false,
+            cycle_count:        0,
+            instr_count:        0,
+
+            power_state_callback:   None
         }
     }
 
     // Execute the next action.
     // If it returns true, keep stepping.
     // If it returns false, wait.
+    #[allow(clippy::needless_return)]
     pub fn step(&mut self) -> bool {
         if self.v_blank_latch {
             self.v_blank_latch = false;
             return false;   // V-Blank has been entered, emulator needs to sync up.
         }
 
+        // Nothing is clocked while stopped. Only a button press wakes the CPU.
+        if self.stopped {
+            if !self.mem.joypad_pressed() {
+                return false;
+            }
+            self.stopped = false;
+            self.set_power_state(PowerState::Running);
+        }
+
         if self.handle_interrupts() {
             return true;
         }
@@ -162,15 +203,51 @@ impl CPU {
         return true;
     }
 
+    pub fn add_cheat(&mut self, id: CheatId, cheat: Cheat) {
+        self.mem.add_cheat(id, cheat);
+    }
+
+    pub fn remove_cheat(&mut self, id: CheatId) {
+        self.mem.remove_cheat(id);
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.mem.clear_cheats();
+    }
+
     pub fn frame_update(&mut self, frame: Arc<Mutex<[u8]>>) {
         self.mem.frame(frame);
         self.mem.flush_cart();
     }
 
+    pub fn paused_frame_update(&mut self, cycles: u32) {
+        self.mem.paused_frame(cycles);
+    }
+
     pub fn enable_audio(&mut self, sender: Sender<SamplePacket>) {
         self.mem.enable_audio(sender);
     }
 
+    pub fn enable_audio_null(&mut self) {
+        self.mem.enable_audio_null();
+    }
+
+    pub fn drain_audio_events(&mut self) -> Vec<AudioEvent> {
+        self.mem.drain_audio_events()
+    }
+
+    pub fn scope_buffers(&self) -> [Vec<i8>; 4] {
+        self.mem.scope_buffers()
+    }
+
+    pub fn get_wave_ram(&self) -> [u8; 16] {
+        self.mem.get_wave_ram()
+    }
+
+    pub fn set_wave_ram(&mut self, data: [u8; 16]) {
+        self.mem.set_wave_ram(data);
+    }
+
     pub fn set_button(&mut self, button: Buttons, val: bool) {
         self.mem.set_button(button, val);
     }
@@ -179,9 +256,150 @@ impl CPU {
         self.mem.set_direction(direction, val);
     }
 
+    pub fn set_initial_input(&mut self, buttons: Buttons, directions: Directions) {
+        self.mem.set_initial_input(buttons, directions);
+    }
+
     pub fn cart_name(&self) -> String {
         self.mem.cart_name()
     }
+
+    pub fn cart_title_bytes(&self) -> Vec<u8> {
+        self.mem.cart_title_bytes()
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.mem.is_cgb()
+    }
+
+    pub fn dump_background(&self) -> Vec<u8> {
+        self.mem.dump_background()
+    }
+
+    pub fn dump_tiles(&self, bank: u8) -> Vec<u8> {
+        self.mem.dump_tiles(bank)
+    }
+
+    pub fn dump_tilemap(&self, bank: u8) -> Vec<u8> {
+        self.mem.dump_tilemap(bank)
+    }
+
+    pub fn set_smooth_scroll(&mut self, enabled: bool) {
+        self.mem.set_smooth_scroll(enabled);
+    }
+
+    pub fn sprite_overflow_lines(&self) -> Vec<u8> {
+        self.mem.sprite_overflow_lines()
+    }
+
+    pub fn set_sprite_debug(&mut self, enabled: bool) {
+        self.mem.set_sprite_debug(enabled);
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.mem.set_colour_correction(correction);
+    }
+
+    pub fn register_io_handler(&mut self, range: RangeInclusive<u16>, handler: Box<dyn IoHandler>) -> Result<(), String> {
+        self.mem.register_io_handler(range, handler)
+    }
+
+    pub fn set_ir_transceiver(&mut self, transceiver: Box<dyn IrTransceiver>) {
+        self.mem.set_ir_transceiver(transceiver);
+    }
+
+    pub fn connect_serial(&mut self, port: Box<dyn SerialPort>) -> Box<dyn SerialPort> {
+        self.mem.connect_serial(port)
+    }
+
+    pub fn set_power_state_callback(&mut self, callback: Box<dyn FnMut(PowerState)>) {
+        self.power_state_callback = Some(callback);
+    }
+
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.mem.save_data()
+    }
+
+    pub fn current_banks(&self) -> BankState {
+        self.mem.current_banks()
+    }
+
+    pub fn take_rumble_state(&mut self) -> bool {
+        self.mem.take_rumble_state()
+    }
+
+    pub fn cart_features(&self) -> CartFeatureSet {
+        self.mem.cart_features()
+    }
+
+    pub fn set_deterministic(&mut self) {
+        self.mem.set_deterministic();
+    }
+
+    pub fn set_dma_bus_conflicts(&mut self, enabled: bool) {
+        self.mem.set_dma_bus_conflicts(enabled);
+    }
+
+    pub fn set_lcd_on_quirk(&mut self, enabled: bool) {
+        self.mem.set_lcd_on_quirk(enabled);
+    }
+
+    pub fn set_mbc1_multicart(&mut self, multicart: bool) {
+        self.mem.set_mbc1_multicart(multicart);
+    }
+
+    pub fn set_disabled_ram_read(&mut self, policy: DisabledRamRead) {
+        self.mem.set_disabled_ram_read(policy);
+    }
+
+    pub fn fast_boot(&mut self) {
+        self.mem.fast_boot();
+    }
+
+    // Hash the registers and memory, for detecting when two emulators have diverged.
+    pub fn hash_state<H: Hasher>(&self, state: &mut H) {
+        state.write(&[self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.flags.bits()]);
+        state.write(&self.sp.to_le_bytes());
+        state.write(&self.pc.to_le_bytes());
+        state.write(&[self.ime as u8, self.cont as u8, self.halt_bug as u8, self.stopped as u8]);
+        self.mem.hash_state(state);
+    }
+
+    // Start executing a routine, with A as its argument. Interrupts are disabled.
+    pub fn start_routine(&mut self, pc: u16, sp: u16, a: u8) {
+        self.a = a;
+        self.sp = sp;
+        self.pc = pc;
+        self.ime = false;
+        self.cont = true;
+        self.stopped = false;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Access memory without using any cycles.
+    pub fn read_byte(&self, loc: u16) -> u8 {
+        if loc == 0xFF4D && self.mem.is_cgb() {
+            self.read_key1()
+        } else {
+            self.mem.read(loc)
+        }
+    }
+
+    pub fn write_byte(&mut self, loc: u16, val: u8) {
+        if loc == 0xFF4D && self.mem.is_cgb() {
+            self.double_speed_latch = test_bit!(val, 0);
+        } else {
+            self.mem.write(loc, val);
+        }
+    }
+
+    // Cycles and instructions executed since power on.
+    pub fn counters(&self) -> (u64, u64) {
+        (self.cycle_count, self.instr_count)
+    }
 }
 
 // Top level internals
@@ -189,6 +407,7 @@ impl CPU {
     // Increment cycle count and update timer.
     #[inline]
     fn clock_inc(&mut self) {
+        self.cycle_count += self.step_cycles as u64;
         self.cgb_dma_active = self.mem.clock(self.step_cycles);
         self.v_blank_latch = self.v_blank_latch || self.mem.video_mode(self.step_cycles);
     }
@@ -198,7 +417,10 @@ impl CPU {
         let interrupts = self.mem.get_interrupts();
 
         if !interrupts.is_empty() {
-            self.cont = true;
+            if !self.cont {
+                self.cont = true;
+                self.set_power_state(PowerState::Running);
+            }
 
             if self.ime {
                 self.clock_inc();
@@ -235,6 +457,7 @@ impl CPU {
 
     // Run a single instruction.
     fn exec_instruction(&mut self) {
+        self.instr_count += 1;
         let instr = self.fetch();
 
         let op8 = |cpu: &mut CPU| match instr % 8 {
@@ -336,7 +559,7 @@ impl CPU {
             0x68..=0x6F => self.l = op8(self),
 
             0x70..=0x75 => {let op = op8(self); self.write_hl(op, With::None)},
-            0x76 => self.cont = false,
+            0x76 => self.halt(),
             0x77 => {let op = op8(self); self.write_hl(op, With::None)},
             0x78..=0x7F => self.a = op8(self),
 
@@ -423,6 +646,7 @@ impl CPU {
     }
 
     // Run an instruction with "0xCB" as the first byte.
+    // Register ops take 8 cycles. (HL) ops take 16, except BIT which doesn't write back and takes 12.
     fn prefix_cb(&mut self, instr: u8) {
         let op = match instr % 0x8 {
             0 => self.b,
@@ -507,36 +731,71 @@ impl CPU {
     #[inline]
     fn read_mem(&mut self, loc: u16) -> u8 {
         self.clock_inc();
-        if loc == 0xFF4D {
-            if self.step_cycles == GB_STEP {0} else {0x80}
+        if loc == 0xFF4D && self.mem.is_cgb() {
+            self.read_key1()
         } else {
-            self.mem.read(loc)
+            self.mem.cpu_read(loc)
         }
     }
 
+    // KEY1: bit 7 is the current speed, bit 0 is set if a switch is prepared.
+    fn read_key1(&self) -> u8 {
+        let speed = if self.step_cycles == GB_STEP {0} else {bit!(7)};
+        let prepared = if self.double_speed_latch {bit!(0)} else {0};
+        speed | prepared | 0x7E
+    }
+
     #[inline]
     fn write_mem(&mut self, loc: u16, val: u8) {
         self.clock_inc();
-        if (loc == 0xFF4D) && test_bit!(val, 0) {
-            self.double_speed_latch = true;
+        self.write_byte(loc, val);
+    }
+
+    // If an interrupt is already pending with IME off, the CPU doesn't halt.
+    // Instead PC fails to increment after the next fetch, so the following byte is read twice.
+    fn halt(&mut self) {
+        if !self.ime && !self.mem.get_interrupts().is_empty() {
+            self.halt_bug = true;
         } else {
-            self.mem.write(loc, val);
+            self.cont = false;
+            self.set_power_state(PowerState::Halted);
         }
     }
 
+    // STOP resets the divider, and switches speed if a switch was prepared.
+    // Otherwise the CPU and LCD stop until a button is pressed.
     fn stop(&mut self) {
+        // STOP is followed by a padding byte, which should be 0.
+        let padding = self.fetch();
+        if padding != 0 {
+            log::warn!("STOP at {:04X} followed by {:02X} instead of 00", self.pc.wrapping_sub(2), padding);
+        }
+
+        self.mem.write(0xFF04, 0);
         if self.double_speed_latch {
             self.step_cycles = if self.step_cycles == GB_STEP {CGB_STEP} else {GB_STEP};
             self.double_speed_latch = false;
+            self.set_power_state(if self.step_cycles == CGB_STEP {PowerState::DoubleSpeed} else {PowerState::Running});
         } else {
-            self.cont = false;
+            self.stopped = true;
+            self.set_power_state(PowerState::Stopped);
+        }
+    }
+
+    fn set_power_state(&mut self, state: PowerState) {
+        if let Some(callback) = &mut self.power_state_callback {
+            callback(state);
         }
     }
 
     // read mem pointed to by pc (and inc pc)
     fn fetch(&mut self) -> u8 {
         let result = self.read_mem(self.pc);
-        self.pc = self.pc.wrapping_add(1);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
 
         result
     }
@@ -565,20 +824,16 @@ impl CPU {
     // increments sp - TODO: maybe improve this fn
     fn add_sp(&mut self, imm: u8) -> u16 {
         self.clock_inc();
-        let offset = imm as i8;
-        let result = (self.sp as i32) + (offset as i32);
+        // The offset is signed, but the flags come from an unsigned add to the low byte.
+        let offset = imm as i8 as u16;
         self.flags = CPUFlags::default();
-        if offset >= 0 {
-            self.flags.set(CPUFlags::HC, (self.sp & 0xF) + ((offset as u16) & 0xF) > 0xF);
-            self.flags.set(CPUFlags::CARRY, (self.sp & 0xFF) + offset as u16 > 0xFF);
-        } else {
-            self.flags.set(CPUFlags::HC, (self.sp as i32) & 0xF >= result & 0xF);
-            self.flags.set(CPUFlags::CARRY, (self.sp as i32) & 0xFF >= result & 0xFF);
-        }
-        result as u16
+        self.flags.set(CPUFlags::HC, (self.sp & 0xF) + ((imm as u16) & 0xF) > 0xF);
+        self.flags.set(CPUFlags::CARRY, (self.sp & 0xFF) + (imm as u16) > 0xFF);
+        self.sp.wrapping_add(offset)
     }
 
     // writes sp to mem
+    // Together with the opcode and immediate fetch this takes 20 cycles.
     fn write_sp(&mut self, imm: u16) {
         let lo_byte = lo_16!(self.sp);
         let hi_byte = hi_16!(self.sp);
@@ -628,7 +883,7 @@ impl CPU {
         let result = (self.a as i16) - (op as i16) - (c as i16);
         self.flags = CPUFlags::NEG;
         self.flags.set(CPUFlags::ZERO, (result as u8) == 0);
-        self.flags.set(CPUFlags::HC, (self.a & 0xF) < (((result as u8) & 0xF) + c));
+        self.flags.set(CPUFlags::HC, (self.a & 0xF) < ((op & 0xF) + c));
         self.flags.set(CPUFlags::CARRY, result < 0);
         self.a = result as u8;
     }
@@ -689,6 +944,8 @@ impl CPU {
         op.wrapping_sub(1)
     }
 
+    // Adjust A to BCD after an add or subtract. The adjustment only depends on the flags and A:
+    // after a subtract the carry is never changed, and HC is always cleared.
     fn daa(&mut self) {
         let mut result = (self.a as u16) as i16;
         if self.flags.contains(CPUFlags::NEG) {
@@ -717,7 +974,7 @@ impl CPU {
 
     fn cpl(&mut self) {
         self.flags.insert(CPUFlags::NEG | CPUFlags::HC);
-        self.a = self.a ^ 0xFF;
+        self.a ^= 0xFF;
     }
 
     // Stack
@@ -834,8 +1091,9 @@ impl CPU {
         Some(result)
     }
 
+    // Clears N, HC and C, and sets Z for a zero result.
     fn swap(&mut self, op: u8) -> Option<u8> {
-        let result = (op << 4) | (op >> 4);
+        let result = op.rotate_left(4);
         self.flags = CPUFlags::default();
         self.flags.set(CPUFlags::ZERO, result == 0);
         Some(result)
@@ -852,6 +1110,7 @@ impl CPU {
         Some(result)
     }
 
+    // Carry is left untouched.
     fn bit(&mut self, b: u8, op: u8) -> Option<u8> {
         self.flags.set(CPUFlags::ZERO, !test_bit!(op, b));
         self.flags.remove(CPUFlags::NEG);
@@ -870,6 +1129,7 @@ impl CPU {
         self.flags.toggle(CPUFlags::CARRY);
     }
 
+    #[allow(clippy::needless_return)]
     fn nop(&self) {
         return;
     }
@@ -884,22 +1144,24 @@ impl CPU {
     }
 
     // Jump
+    // The immediate is always fetched, so jumps take 12 cycles if not taken, 16 if taken.
     fn jp(&mut self, cd: Cond, loc: u16) {
-        if cd.check(&self) {
+        if cd.check(self) {
             self.clock_inc();
             self.pc = loc
         }
     }
 
+    // 8 cycles if not taken, 12 if taken.
     fn jr(&mut self, cd: Cond, loc: i8) {
-        if cd.check(&self) {
+        if cd.check(self) {
             self.clock_inc();
             self.pc = ((self.pc as i32) + (loc as i32)) as u16;
         }
     }
 
     fn call(&mut self, cd: Cond, loc: u16) {
-        if cd.check(&self) {
+        if cd.check(self) {
             self.clock_inc();
             let hi_byte = hi_16!(self.pc);
             let lo_byte = lo_16!(self.pc);
@@ -912,7 +1174,7 @@ impl CPU {
     fn ret(&mut self, cd: Cond) {
         self.clock_inc();
 
-        if cd.check(&self) {
+        if cd.check(self) {
             if cd != Cond::AL {
                 self.clock_inc();
             }
@@ -962,4 +1224,287 @@ impl CPU {
     pub fn get_mem_at(&self, loc: u16) -> u8 {
         self.mem.read(loc)
     }
-}
\ No newline at end of file
+
+    #[cfg(feature = "debug")]
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+        self.cont = true;
+        self.stopped = false;
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn set_sp(&mut self, addr: u16) {
+        self.sp = addr;
+    }
+
+    // Call a routine, without using any cycles.
+    #[cfg(feature = "debug")]
+    pub fn jump_to(&mut self, addr: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.mem.write(self.sp, hi_16!(self.pc));
+        self.sp = self.sp.wrapping_sub(1);
+        self.mem.write(self.sp, lo_16!(self.pc));
+        self.set_pc(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn cpu(code: &[u8]) -> CPU {
+        let mut cpu = CPU::new(testutil::mem(testutil::rom(code)));
+        cpu.pc = testutil::CODE_START;
+        cpu
+    }
+
+    // Run one instruction and return the cycles it took.
+    fn exec(cpu: &mut CPU) -> u64 {
+        let start = cpu.cycle_count;
+        cpu.exec_instruction();
+        cpu.cycle_count - start
+    }
+
+    #[test]
+    fn first_step() {
+        // The test ROM has JP CODE_START at the entry point.
+        let mut cpu = CPU::new(testutil::mem(testutil::rom(&[0x18, 0xFE])));
+        assert_eq!(cpu.pc, 0x100);
+        cpu.step();
+        assert_eq!(cpu.pc, testutil::CODE_START);
+    }
+
+    #[test]
+    fn ld_nn_sp() {
+        let mut cpu = cpu(&[0x08, 0x00, 0xC0, 0x08, 0xFF, 0xFF]);   // LD (C000),SP; LD (FFFF),SP
+        cpu.sp = 0x1234;
+        assert_eq!(exec(&mut cpu), 20);
+        assert_eq!(cpu.mem.read(0xC000), 0x34);
+        assert_eq!(cpu.mem.read(0xC001), 0x12);
+
+        // The high byte wraps around to 0x0000, which is ignored by a cart without a mapper.
+        cpu.sp = 0x0013;
+        assert_eq!(exec(&mut cpu), 20);
+        assert_eq!(cpu.mem.read(0xFFFF), 0x13);
+        assert_eq!(cpu.mem.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn halt_power_state() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut cpu = cpu(&[0xF3, 0x76, 0x00]);    // DI; HALT; NOP
+        let states = Rc::new(RefCell::new(Vec::new()));
+        let callback_states = states.clone();
+        cpu.set_power_state_callback(Box::new(move |state| callback_states.borrow_mut().push(state)));
+        cpu.mem.write(0xFFFF, 0x04);
+        cpu.mem.write(0xFF0F, 0x00);
+
+        exec(&mut cpu);
+        exec(&mut cpu);
+        assert_eq!(*states.borrow(), [PowerState::Halted]);
+
+        // A timer interrupt wakes the CPU, even with IME off.
+        cpu.mem.write(0xFF0F, 0x04);
+        cpu.step();
+        assert_eq!(*states.borrow(), [PowerState::Halted, PowerState::Running]);
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut cpu = cpu(&[0x76, 0x3C, 0x00]);    // HALT; INC A; NOP
+        cpu.ime = false;
+        cpu.a = 0;
+        cpu.mem.write(0xFFFF, 0x04);
+        cpu.mem.write(0xFF0F, 0x04);
+
+        // The CPU doesn't halt, and the byte after HALT is run twice.
+        exec(&mut cpu);
+        assert!(cpu.cont);
+        assert_eq!(cpu.pc, testutil::CODE_START + 1);
+        exec(&mut cpu);
+        assert_eq!(cpu.pc, testutil::CODE_START + 1);
+        exec(&mut cpu);
+        assert_eq!(cpu.pc, testutil::CODE_START + 2);
+        assert_eq!(cpu.a, 2);
+        exec(&mut cpu);
+        assert_eq!(cpu.pc, testutil::CODE_START + 3);
+        assert_eq!(cpu.a, 2);
+    }
+
+    #[test]
+    fn swap() {
+        let mut cpu = cpu(&[0xCB, 0x37, 0xCB, 0x37, 0xCB, 0x36]);  // SWAP A; SWAP A; SWAP (HL)
+        cpu.a = 0xF0;
+        cpu.flags = CPUFlags::all();
+        assert_eq!(exec(&mut cpu), 8);
+        assert_eq!((cpu.a, cpu.flags), (0x0F, CPUFlags::default()));
+
+        cpu.a = 0x00;
+        cpu.flags = CPUFlags::CARRY;
+        exec(&mut cpu);
+        assert_eq!(cpu.flags, CPUFlags::ZERO);
+
+        cpu.h = 0xC0;
+        cpu.l = 0x00;
+        cpu.mem.write(0xC000, 0x12);
+        assert_eq!(exec(&mut cpu), 16);
+        assert_eq!(cpu.mem.read(0xC000), 0x21);
+    }
+
+    #[test]
+    fn bit_keeps_carry() {
+        // BIT 0,A; BIT 3,A; BIT 7,A; BIT 3,A
+        let mut cpu = cpu(&[0xCB, 0x47, 0xCB, 0x5F, 0xCB, 0x7F, 0xCB, 0x5F]);
+        cpu.a = 0x08;
+        for expected_zero in [true, false, true] {
+            cpu.flags = CPUFlags::CARRY | CPUFlags::NEG;
+            exec(&mut cpu);
+            let zero = if expected_zero {CPUFlags::ZERO} else {CPUFlags::default()};
+            assert_eq!(cpu.flags, zero | CPUFlags::HC | CPUFlags::CARRY);
+        }
+
+        cpu.flags = CPUFlags::ZERO;
+        exec(&mut cpu);
+        assert_eq!(cpu.flags, CPUFlags::HC);
+    }
+
+    #[test]
+    fn cb_timing() {
+        // SWAP B; SWAP (HL); BIT 0,(HL); RES 0,(HL)
+        let mut cpu = cpu(&[0xCB, 0x30, 0xCB, 0x36, 0xCB, 0x46, 0xCB, 0x86]);
+        cpu.h = 0xC0;
+        cpu.l = 0x00;
+        assert_eq!(exec(&mut cpu), 8);
+        assert_eq!(exec(&mut cpu), 16);
+        assert_eq!(exec(&mut cpu), 12);
+        assert_eq!(exec(&mut cpu), 16);
+    }
+
+    #[test]
+    fn conditional_jump_timing() {
+        // JR NZ,+2; JR NZ,+2
+        let mut jr = cpu(&[0x20, 0x02, 0x20, 0x02]);
+        jr.flags = CPUFlags::ZERO;
+        assert_eq!(exec(&mut jr), 8);
+        assert_eq!(jr.pc, testutil::CODE_START + 2);
+        jr.flags = CPUFlags::default();
+        assert_eq!(exec(&mut jr), 12);
+        assert_eq!(jr.pc, testutil::CODE_START + 6);
+
+        // JP NZ,0x0200; JP NZ,0x0200
+        let mut jp = cpu(&[0xC2, 0x00, 0x02, 0xC2, 0x00, 0x02]);
+        jp.flags = CPUFlags::ZERO;
+        assert_eq!(exec(&mut jp), 12);
+        jp.flags = CPUFlags::default();
+        assert_eq!(exec(&mut jp), 16);
+        assert_eq!(jp.pc, 0x0200);
+    }
+
+    // Flags for an 8-bit add or subtract, found from the bits that changed in the result.
+    fn reference_flags(a: u8, op: u8, carry: bool, subtract: bool) -> (u8, CPUFlags) {
+        let c = carry as i32;
+        let full = if subtract {(a as i32) - (op as i32) - c} else {(a as i32) + (op as i32) + c};
+        let result = full as u8;
+
+        let mut flags = if subtract {CPUFlags::NEG} else {CPUFlags::default()};
+        flags.set(CPUFlags::ZERO, result == 0);
+        flags.set(CPUFlags::HC, ((a ^ op ^ result) & 0x10) != 0);
+        flags.set(CPUFlags::CARRY, !(0..=0xFF).contains(&full));
+        (result, flags)
+    }
+
+    #[test]
+    fn adc_sbc_carry() {
+        let mut cpu = cpu(&[]);
+        for a in 0..=0xFF {
+            for op in 0..=0xFF {
+                for &carry in [false, true].iter() {
+                    for &subtract in [false, true].iter() {
+                        cpu.a = a;
+                        cpu.flags.set(CPUFlags::CARRY, carry);
+                        if subtract {
+                            cpu.sub(true, op);
+                        } else {
+                            cpu.add(true, op);
+                        }
+                        assert_eq!((cpu.a, cpu.flags), reference_flags(a, op, carry, subtract), "A={:02X} op={:02X} C={} sub={}", a, op, carry, subtract);
+                    }
+                }
+            }
+        }
+
+        // The carry-in alone can cause a half borrow.
+        cpu.a = 0x10;
+        cpu.flags = CPUFlags::CARRY;
+        cpu.sub(true, 0x0F);
+        assert_eq!(cpu.a, 0x00);
+        assert_eq!(cpu.flags, CPUFlags::ZERO | CPUFlags::NEG | CPUFlags::HC);
+    }
+
+    // DAA as documented: the correction is chosen from the flags and the value before adjusting.
+    fn reference_daa(a: u8, flags: CPUFlags) -> (u8, CPUFlags) {
+        let subtract = flags.contains(CPUFlags::NEG);
+        let mut correction = 0;
+        let mut carry = flags.contains(CPUFlags::CARRY);
+        if flags.contains(CPUFlags::HC) || (!subtract && (a & 0x0F) > 0x09) {
+            correction |= 0x06;
+        }
+        if carry || (!subtract && a > 0x99) {
+            correction |= 0x60;
+            carry = true;
+        }
+        let result = if subtract {a.wrapping_sub(correction)} else {a.wrapping_add(correction)};
+
+        let mut out_flags = flags & CPUFlags::NEG;
+        out_flags.set(CPUFlags::ZERO, result == 0);
+        out_flags.set(CPUFlags::CARRY, carry);
+        (result, out_flags)
+    }
+
+    #[test]
+    fn daa() {
+        let mut cpu = cpu(&[]);
+        for a in 0..=0xFF {
+            for flag_bits in 0..8 {
+                let flags = CPUFlags::from_bits_truncate(flag_bits << 4);
+                cpu.a = a;
+                cpu.flags = flags | CPUFlags::ZERO;
+                cpu.daa();
+                assert_eq!((cpu.a, cpu.flags), reference_daa(a, flags), "A={:02X} flags={:?}", a, flags);
+            }
+        }
+    }
+
+    #[test]
+    fn sp_offset_flags() {
+        // (SP, operand, result, flags) - the flags come from the unsigned low byte.
+        let cases = [
+            (0x00FF, 0x01, 0x0100, CPUFlags::HC | CPUFlags::CARRY),
+            (0x000F, 0x01, 0x0010, CPUFlags::HC),
+            (0x00F0, 0x10, 0x0100, CPUFlags::CARRY),
+            (0x0000, 0xFF, 0xFFFF, CPUFlags::default()),
+            (0x0001, 0xFF, 0x0000, CPUFlags::HC | CPUFlags::CARRY),
+            (0xFFF8, 0x08, 0x0000, CPUFlags::HC | CPUFlags::CARRY),
+            (0x1234, 0x80, 0x11B4, CPUFlags::default()),
+        ];
+        for &(sp, imm, result, flags) in cases.iter() {
+            let mut add = cpu(&[0xE8, imm]);    // ADD SP,e8
+            add.sp = sp;
+            add.flags = CPUFlags::all();
+            assert_eq!(exec(&mut add), 16);
+
+            let mut ld = cpu(&[0xF8, imm]);     // LD HL,SP+e8
+            ld.sp = sp;
+            ld.flags = CPUFlags::all();
+            assert_eq!(exec(&mut ld), 12);
+
+            assert_eq!(add.sp, result, "SP={:04X} e8={:02X}", sp, imm);
+            assert_eq!(ld.get_16(Reg::HL), result, "SP={:04X} e8={:02X}", sp, imm);
+            assert_eq!(ld.sp, sp);
+            assert_eq!(add.flags, flags, "SP={:04X} e8={:02X}", sp, imm);
+            assert_eq!(ld.flags, add.flags, "SP={:04X} e8={:02X}", sp, imm);
+        }
+    }
+}
@@ -14,9 +14,9 @@ pub struct CPUState {
     pub sp: u16
 }
 
-impl CPUState {
-    pub fn to_string(&self) -> String {
-        format!("a:{:02X} b:{:02X} c:{:02X} d:{:02X} e:{:02X} h:{:02X} l:{:02X}\n\
+impl std::fmt::Display for CPUState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a:{:02X} b:{:02X} c:{:02X} d:{:02X} e:{:02X} h:{:02X} l:{:02X}\n\
                 znhc: {:08b}\n\
                 pc: {:04X} sp: {:04X}",
                 self.a, self.b, self.c, self.d, self.e, self.h, self.l,
@@ -24,3 +24,101 @@ impl CPUState {
                 self.pc, self.sp)
     }
 }
+
+const REGS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const REG_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU_OPS: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const CB_OPS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+// Get the mnemonic for the instruction starting at the first byte.
+pub fn disassemble(instr: [u8; 3]) -> String {
+    let op = instr[0];
+    let imm = instr[1];
+    let imm_16 = make_16!(instr[2], instr[1]);
+    let reg = |i: u8| REGS[(i & 7) as usize];
+    let pair = REG_PAIRS[((op >> 4) & 3) as usize];
+    let cond = CONDS[((op >> 3) & 3) as usize];
+
+    match op {
+        0x00 => "NOP".to_string(),
+        0x08 => format!("LD (${:04X}),SP", imm_16),
+        0x10 => "STOP".to_string(),
+        0x18 => format!("JR {}", imm as i8),
+        0x20 | 0x28 | 0x30 | 0x38 => format!("JR {},{}", cond, imm as i8),
+        0x02 => "LD (BC),A".to_string(),
+        0x12 => "LD (DE),A".to_string(),
+        0x22 => "LD (HL+),A".to_string(),
+        0x32 => "LD (HL-),A".to_string(),
+        0x0A => "LD A,(BC)".to_string(),
+        0x1A => "LD A,(DE)".to_string(),
+        0x2A => "LD A,(HL+)".to_string(),
+        0x3A => "LD A,(HL-)".to_string(),
+        0x07 => "RLCA".to_string(),
+        0x0F => "RRCA".to_string(),
+        0x17 => "RLA".to_string(),
+        0x1F => "RRA".to_string(),
+        0x27 => "DAA".to_string(),
+        0x2F => "CPL".to_string(),
+        0x37 => "SCF".to_string(),
+        0x3F => "CCF".to_string(),
+        0x76 => "HALT".to_string(),
+        x if x & 0xCF == 0x01 => format!("LD {},${:04X}", pair, imm_16),
+        x if x & 0xCF == 0x03 => format!("INC {}", pair),
+        x if x & 0xCF == 0x09 => format!("ADD HL,{}", pair),
+        x if x & 0xCF == 0x0B => format!("DEC {}", pair),
+        x if x & 0xC7 == 0x04 => format!("INC {}", reg(x >> 3)),
+        x if x & 0xC7 == 0x05 => format!("DEC {}", reg(x >> 3)),
+        x if x & 0xC7 == 0x06 => format!("LD {},${:02X}", reg(x >> 3), imm),
+        0x40..=0x7F => format!("LD {},{}", reg(op >> 3), reg(op)),
+        0x80..=0xBF => format!("{}{}", ALU_OPS[((op >> 3) & 7) as usize], reg(op)),
+
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => format!("RET {}", cond),
+        0xC2 | 0xCA | 0xD2 | 0xDA => format!("JP {},${:04X}", cond, imm_16),
+        0xC4 | 0xCC | 0xD4 | 0xDC => format!("CALL {},${:04X}", cond, imm_16),
+        0xC3 => format!("JP ${:04X}", imm_16),
+        0xC9 => "RET".to_string(),
+        0xCB => format!("{}{}", match imm >> 6 {
+            0 => format!("{} ", CB_OPS[((imm >> 3) & 7) as usize]),
+            1 => format!("BIT {},", (imm >> 3) & 7),
+            2 => format!("RES {},", (imm >> 3) & 7),
+            _ => format!("SET {},", (imm >> 3) & 7),
+        }, reg(imm)),
+        0xCD => format!("CALL ${:04X}", imm_16),
+        0xD9 => "RETI".to_string(),
+        0xE0 => format!("LDH ($FF{:02X}),A", imm),
+        0xE2 => "LD ($FF00+C),A".to_string(),
+        0xE8 => format!("ADD SP,{}", imm as i8),
+        0xE9 => "JP (HL)".to_string(),
+        0xEA => format!("LD (${:04X}),A", imm_16),
+        0xF0 => format!("LDH A,($FF{:02X})", imm),
+        0xF2 => "LD A,($FF00+C)".to_string(),
+        0xF3 => "DI".to_string(),
+        0xF8 => format!("LD HL,SP{:+}", imm as i8),
+        0xF9 => "LD SP,HL".to_string(),
+        0xFA => format!("LD A,(${:04X})", imm_16),
+        0xFB => "EI".to_string(),
+        x if x & 0xCF == 0xC1 => format!("POP {}", STACK_PAIRS[((x >> 4) & 3) as usize]),
+        x if x & 0xCF == 0xC5 => format!("PUSH {}", STACK_PAIRS[((x >> 4) & 3) as usize]),
+        x if x & 0xC7 == 0xC6 => format!("{}${:02X}", ALU_OPS[((x >> 3) & 7) as usize], imm),
+        x if x & 0xC7 == 0xC7 => format!("RST ${:02X}", x & 0x38),
+        _ => format!("DB ${:02X}", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembly() {
+        assert_eq!(disassemble([0x00, 0x00, 0x00]), "NOP");
+        assert_eq!(disassemble([0x21, 0x34, 0x12]), "LD HL,$1234");
+        assert_eq!(disassemble([0x20, 0xFE, 0x00]), "JR NZ,-2");
+        assert_eq!(disassemble([0x7E, 0x00, 0x00]), "LD A,(HL)");
+        assert_eq!(disassemble([0xCB, 0x7C, 0x00]), "BIT 7,H");
+        assert_eq!(disassemble([0xF8, 0x80, 0x00]), "LD HL,SP-128");
+        assert_eq!(disassemble([0xD3, 0x00, 0x00]), "DB $D3");
+    }
+}
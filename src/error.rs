@@ -0,0 +1,26 @@
+// Errors returned by the public interface.
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RustBoyError {
+    InvalidROM(String),     // ROM file couldn't be read.
+    InvalidGBS(String),     // GBS file couldn't be parsed.
+    InvalidTrack(u8),       // GBS track doesn't exist.
+    InvalidSampleRate(usize),   // Audio sample rate is zero or too high.
+    AudioDisabled,          // Audio output isn't available in deterministic mode.
+}
+
+impl fmt::Display for RustBoyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RustBoyError::*;
+        match self {
+            InvalidROM(s)       => write!(f, "invalid ROM: {}", s),
+            InvalidGBS(s)       => write!(f, "invalid GBS file: {}", s),
+            InvalidTrack(t)     => write!(f, "invalid GBS track: {}", t),
+            InvalidSampleRate(r)    => write!(f, "invalid audio sample rate: {}", r),
+            AudioDisabled       => write!(f, "audio output is disabled in deterministic mode"),
+        }
+    }
+}
+
+impl std::error::Error for RustBoyError {}
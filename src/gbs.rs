@@ -0,0 +1,93 @@
+// GBS (Game Boy Sound) music files.
+// These are loaded into a generated ROM, with a small driver that calls the init and play routines.
+
+const HEADER_SIZE: usize = 0x70;
+const MIN_LOAD_ADDR: u16 = 0x400;
+
+const PLAY_HANDLER: u16 = 0x80;     // Interrupt handler that calls the play routine.
+pub const DRIVER_ENTRY: u16 = 0x100;
+
+pub struct GBSInfo {
+    pub track_count:    u8,
+    pub first_track:    u8,
+    pub stack_pointer:  u16,
+}
+
+// Parse the GBS header and build a ROM that plays it.
+pub fn make_rom(data: &[u8]) -> Result<(Vec<u8>, GBSInfo), String> {
+    if data.len() < HEADER_SIZE || &data[0..3] != b"GBS" {
+        return Err("missing GBS header".to_string());
+    }
+    if data[3] != 1 {
+        return Err(format!("unsupported version {}", data[3]));
+    }
+
+    let track_count = data[4];
+    let first_track = data[5];
+    let load_addr = make_16!(data[7], data[6]);
+    let init_addr = make_16!(data[9], data[8]);
+    let play_addr = make_16!(data[0xB], data[0xA]);
+    let stack_pointer = make_16!(data[0xD], data[0xC]);
+    let timer_modulo = data[0xE];
+    let timer_control = data[0xF];
+
+    if track_count == 0 || first_track == 0 || first_track > track_count {
+        return Err(format!("invalid track numbers: first {} of {}", first_track, track_count));
+    }
+    if !(MIN_LOAD_ADDR..0x8000).contains(&load_addr) {
+        return Err(format!("invalid load address {:04X}", load_addr));
+    }
+
+    let code = &data[HEADER_SIZE..];
+    let rom_size = (load_addr as usize + code.len()).next_power_of_two().max(0x8000);
+    let mut rom = vec![0; rom_size];
+    rom[(load_addr as usize)..(load_addr as usize + code.len())].copy_from_slice(code);
+
+    // RST vectors jump to the same offset from the load address.
+    for rst in (0..0x40).step_by(8) {
+        let target = load_addr + rst as u16;
+        rom[rst..(rst + 3)].copy_from_slice(&[0xC3, lo_16!(target), hi_16!(target)]);
+    }
+
+    // The play routine is called on timer interrupts if the timer is enabled, V-blank otherwise.
+    let (interrupt_vector, interrupt_enable) = if test_bit!(timer_control, 2) {(0x50, 0x04)} else {(0x40, 0x01)};
+    rom[interrupt_vector..(interrupt_vector + 3)].copy_from_slice(&[0xC3, lo_16!(PLAY_HANDLER), hi_16!(PLAY_HANDLER)]);
+
+    let play_handler = [
+        0xF5, 0xC5, 0xD5, 0xE5,                         // PUSH AF, BC, DE, HL
+        0xCD, lo_16!(play_addr), hi_16!(play_addr),     // CALL play
+        0xE1, 0xD1, 0xC1, 0xF1,                         // POP HL, DE, BC, AF
+        0xD9,                                           // RETI
+    ];
+    rom[(PLAY_HANDLER as usize)..(PLAY_HANDLER as usize + play_handler.len())].copy_from_slice(&play_handler);
+
+    // Entered with the track number in A.
+    let driver = [
+        0xF3,                                           // DI
+        0x47,                                           // LD B,A
+        0x3E, 0x0A, 0xEA, 0x00, 0x00,                   // Enable cart RAM
+        0xAF, 0xE0, 0x26,                               // Reset audio
+        0x3E, 0x80, 0xE0, 0x26,
+        0x3E, 0x77, 0xE0, 0x24,
+        0x3E, 0xFF, 0xE0, 0x25,
+        0x3E, timer_modulo, 0xE0, 0x06,                 // Set up timer
+        0x3E, timer_control & 0x7, 0xE0, 0x07,
+        0x3E, interrupt_enable, 0xE0, 0xFF,             // Set up interrupts
+        0xAF, 0xE0, 0x0F,
+        0x78,                                           // LD A,B
+        0xCD, lo_16!(init_addr), hi_16!(init_addr),     // CALL init
+        0xFB,                                           // EI
+        0x76, 0x00, 0x18, 0xFC,                         // Loop: HALT, NOP, JR loop
+    ];
+    rom[(DRIVER_ENTRY as usize)..(DRIVER_ENTRY as usize + driver.len())].copy_from_slice(&driver);
+
+    // MBC3 with RAM.
+    rom[0x147] = 0x12;
+    rom[0x149] = 0x02;
+
+    Ok((rom, GBSInfo {
+        track_count:    track_count,
+        first_track:    first_track - 1,
+        stack_pointer:  stack_pointer,
+    }))
+}
@@ -20,20 +20,15 @@ bitflags! {
     }
 }
 
-enum Select {
-    Direction,
-    Button,
-    None
-}
-
 const SELECT_DIRECTION: u8  = 4;
 const SELECT_BUTTONS: u8    = 5;
+const SELECT_MASK: u8       = bit!(SELECT_DIRECTION) | bit!(SELECT_BUTTONS);
 
 pub struct Joypad {
     buttons:    Buttons,
     directions: Directions,
 
-    selector:   Select,
+    selector:   u8,     // Bits 4-5 as written, active-low.
     change:     bool
 }
 
@@ -43,27 +38,27 @@ impl Joypad {
             buttons:    Buttons::default(),
             directions: Directions::default(),
 
-            selector:   Select::None,
+            selector:   SELECT_MASK,
             change:     false
         }
     }
 
+    // Bits 6-7 always read 1, bits 4-5 read back the selection.
+    // Inputs are active-low: pressed buttons in any selected group read 0.
     pub fn read(&self) -> u8 {
-        match self.selector {
-            Select::Direction => (!self.directions.bits() & 0xF),
-            Select::Button => (!self.buttons.bits() & 0xF),
-            Select::None => 0
+        let mut pressed = 0;
+        if !test_bit!(self.selector, SELECT_DIRECTION) {
+            pressed |= self.directions.bits();
         }
+        if !test_bit!(self.selector, SELECT_BUTTONS) {
+            pressed |= self.buttons.bits();
+        }
+
+        0xC0 | self.selector | (!pressed & 0xF)
     }
 
     pub fn write(&mut self, val: u8) {
-        self.selector = if !test_bit!(val, SELECT_BUTTONS) {
-            Select::Button
-        } else if !test_bit!(val, SELECT_DIRECTION) {
-            Select::Direction
-        } else {
-            Select::None
-        };
+        self.selector = val & SELECT_MASK;
     }
 
     pub fn set_direction(&mut self, direction: Directions, val: bool) {
@@ -76,9 +71,43 @@ impl Joypad {
         self.change = self.change || val;
     }
 
+    // Set inputs held at power on. These don't trigger an interrupt.
+    pub fn set_initial(&mut self, buttons: Buttons, directions: Directions) {
+        self.buttons = buttons;
+        self.directions = directions;
+        self.change = false;
+    }
+
     pub fn check_interrupt(&mut self) -> bool {
         let trigger_interrupt = self.change;
         self.change = false;
         trigger_interrupt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_values() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Buttons::A, true);
+        joypad.set_button(Buttons::START, true);
+        joypad.set_direction(Directions::LEFT, true);
+
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xED);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD6);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC4);
+
+        // Nothing pressed.
+        let mut joypad = Joypad::new();
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xCF);
+    }
+}
@@ -1,3 +1,6 @@
+// Structs are initialised with aligned `field: value` pairs, even when the names match.
+#![allow(clippy::redundant_field_names)]
+
 #[macro_use]
 mod utils;
 
@@ -8,11 +11,22 @@ mod timer;
 mod audio;
 mod interrupt;
 mod joypad;
+mod error;
+mod gbs;
+mod savepath;
+mod testpattern;
+mod buttonmap;
+mod screenshot;
+mod cheats;
+
+#[cfg(test)]
+mod testutil;
 
 #[cfg(feature = "debug")]
 pub mod debug;
 
 pub use video::{
+    ColourCorrection,
     UserPalette
 };
 
@@ -21,20 +35,98 @@ use joypad::{
     Directions
 };
 
-use std::sync::{
-    Arc,
-    Mutex
+use std::{
+    cell::RefCell,
+    hash::Hasher,
+    ops::RangeInclusive,
+    rc::Rc,
+    sync::{
+        Arc,
+        Mutex
+    },
+    time::{
+        Duration,
+        Instant
+    }
 };
 
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{
+    bounded,
+    unbounded,
+    Receiver,
+    Sender,
+    TrySendError
+};
 
-use audio::Resampler;
+use audio::{
+    Resampler,
+    FRAMES_PER_SECOND
+};
+pub use audio::{
+    AudioChannel,
+    AudioEvent,
+    ResampleQuality
+};
+pub use error::RustBoyError;
+pub use savepath::SaveNaming;
+pub use testpattern::TestPattern;
+pub use cheats::CheatId;
+pub use buttonmap::{
+    keycode,
+    ButtonMap,
+    Hotkey,
+    Modifiers
+};
+use gbs::GBSInfo;
+use utils::Fnv1a;
 use cpu::CPU;
-use mem::MemBus;
-pub use mem::ROMType;
+pub use cpu::PowerState;
+use mem::{
+    CaptureSerialPort,
+    MemBus,
+    SaveType
+};
+pub use mem::{
+    BankState,
+    CartFeatureSet,
+    DisabledRamRead,
+    IoHandler,
+    IrTransceiver,
+    NullSerialPort,
+    ROMType,
+    SerialPort
+};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const FRAME_SIZE_BYTES: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 4;
+
+// Hardware timing: one frame is 154 lines of 456 cycles.
+const CLOCK_RATE: u64 = 4_194_304;
+const FRAME_CYCLES: u64 = 154 * 456;
+
+// Highest output sample rate accepted by enable_audio.
+const MAX_SAMPLE_RATE: usize = 384_000;
+
+// Frames queued for a frame channel receiver before new frames are dropped.
+const FRAME_CHANNEL_SIZE: usize = 8;
+
+// Called with an RGBA frame, and its width and height.
+pub type PostProcessor = Box<dyn FnMut(&mut [u8], u32, u32)>;
 
-pub const FRAME_SIZE_BYTES: usize = 160 * 144 * 4;
+// Called after each frame with its timing.
+pub type PerfCallback = Box<dyn FnMut(FrameTiming)>;
 
+// How long a frame took to emulate, and how much work it did.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    pub emulation_time: Duration,   // Host time spent emulating (and post-processing) the frame. Zero in deterministic mode.
+    pub target_time:    Duration,   // Time the frame takes on hardware.
+    pub cycles:         u64,        // At the 4.19MHz clock. A full frame is 70224.
+    pub instructions:   u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Button {
     Up,
     Down,
@@ -46,42 +138,433 @@ pub enum Button {
     Select
 }
 
+// The hardware being emulated. SGB features aren't emulated, so SGB carts run as DMG.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg,
+    Cgb
+}
+
+// A set of held buttons.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct ButtonState {
+    buttons:    Buttons,
+    directions: Directions,
+}
+
+impl ButtonState {
+    pub fn new(held: &[Button]) -> Self {
+        use Button::*;
+
+        let mut state = ButtonState::default();
+        for button in held {
+            match button {
+                Up      => state.directions.insert(Directions::UP),
+                Down    => state.directions.insert(Directions::DOWN),
+                Left    => state.directions.insert(Directions::LEFT),
+                Right   => state.directions.insert(Directions::RIGHT),
+                A       => state.buttons.insert(Buttons::A),
+                B       => state.buttons.insert(Buttons::B),
+                Start   => state.buttons.insert(Buttons::START),
+                Select  => state.buttons.insert(Buttons::SELECT),
+            }
+        }
+        state
+    }
+}
+
+impl std::ops::BitOr for ButtonState {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        ButtonState {
+            buttons:    self.buttons | other.buttons,
+            directions: self.directions | other.directions,
+        }
+    }
+}
+
 pub struct RustBoy {
     cpu:            CPU,
 
     frame:          Arc<Mutex<[u8; FRAME_SIZE_BYTES]>>,
+    frame_sender:   Option<Sender<Vec<u8>>>,
+    post_processor: Option<PostProcessor>,
+    perf_callback:  Option<PerfCallback>,
+    paused:         bool,
+
+    gbs:            Option<GBSInfo>,
+    gbs_track:      u8,
+
+    test_pattern:   Option<usize>,  // Frame count, if showing test patterns instead of emulating.
+    button_map:     ButtonMap,
+    held_hotkeys:   Vec<(u32, Hotkey)>, // Hotkeys pressed through handle_key, by key code.
+    next_cheat_id:  u32,
+    deterministic:  bool,
 }
 
 impl RustBoy {
     pub fn new(rom: ROMType, save_file_name: &str, palette: UserPalette) -> Box<Self> {
-        let mem = MemBus::new(rom, save_file_name, palette);
+        let mem = MemBus::new(rom, SaveType::File(save_file_name.to_string()), palette);
+        Self::from_mem(mem)
+    }
+
+    // Load a ROM and keep save RAM in memory, instead of in a file.
+    // Previous save data can optionally be provided.
+    // Use save_data to export the save RAM.
+    pub fn new_in_memory(rom: Vec<u8>, sram: Option<Vec<u8>>, palette: UserPalette) -> Box<Self> {
+        let mem = MemBus::new(ROMType::Data(rom), SaveType::Memory(sram), palette);
+        Self::from_mem(mem)
+    }
+
+    // Get a save file path for a ROM file, to pass to new.
+    // Naming saves by title or hash lets all saves share a directory without collisions.
+    pub fn default_save_path(rom_path: &str, naming: SaveNaming) -> Result<String, RustBoyError> {
+        savepath::save_path(rom_path, naming).map_err(RustBoyError::InvalidROM)
+    }
+
+    // Load a GBS music file. The first track starts playing immediately.
+    // Audio is output as normal once enabled. The frame is not used.
+    pub fn new_gbs(data: &[u8]) -> Result<Box<Self>, RustBoyError> {
+        let (rom, info) = gbs::make_rom(data).map_err(RustBoyError::InvalidGBS)?;
+        let first_track = info.first_track;
+
+        let mem = MemBus::new(ROMType::Data(rom), SaveType::Memory(None), UserPalette::Greyscale);
+        let mut rustboy = Self::from_mem(mem);
+        rustboy.gbs = Some(info);
+        rustboy.play_track(first_track)?;
+
+        Ok(rustboy)
+    }
+
+    // Create an emulator that outputs test patterns instead of running a game.
+    // Each frame shows the next pattern, cycling through TestPattern in order.
+    pub fn new_test_pattern() -> Box<Self> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);   // JR -2
+
+        let mem = MemBus::new(ROMType::Data(rom), SaveType::Memory(None), UserPalette::Greyscale);
+        let mut rustboy = Self::from_mem(mem);
+        rustboy.test_pattern = Some(0);
+        rustboy
+    }
+
+    // Start playing a GBS track, counting from 0.
+    pub fn play_track(&mut self, track: u8) -> Result<(), RustBoyError> {
+        match &self.gbs {
+            Some(info) if track < info.track_count => {
+                self.cpu.start_routine(gbs::DRIVER_ENTRY, info.stack_pointer, track);
+                self.gbs_track = track;
+                Ok(())
+            },
+            _ => Err(RustBoyError::InvalidTrack(track))
+        }
+    }
+
+    // Play the next GBS track, wrapping around after the last one.
+    pub fn next_track(&mut self) -> Result<(), RustBoyError> {
+        let next_track = self.gbs_track + 1;
+        let track_count = self.gbs.as_ref().map_or(0, |info| info.track_count);
+        self.play_track(if next_track < track_count {next_track} else {0})
+    }
+
+    // Make the same ROM, save data and inputs always produce the same output, for fuzzing and golden-image tests.
+    // - Work RAM and high RAM are zeroed.
+    // - The cart real-time clock is stopped at the time the save was made (or the Unix epoch without a save).
+    // - Frame timing isn't measured: the perf callback reports an emulation time of zero.
+    // - Lines are drawn on the emulation thread, without the render thread.
+    // - Audio samples are generated and discarded. enable_audio fails.
+    pub fn deterministic(mut self: Box<Self>) -> Box<Self> {
+        self.cpu.set_deterministic();
+        self.cpu.enable_audio_null();
+        self.deterministic = true;
+        self
+    }
+
+    // While OAM DMA is copying, the CPU can only read IO and HRAM, and other reads see the byte being copied.
+    // On by default. Turn this off to let the CPU read anywhere during DMA.
+    // Reads through read_byte and the debug interface are never restricted.
+    pub fn dma_bus_conflicts(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.cpu.set_dma_bus_conflicts(enabled);
+        self
+    }
+
+    // When the LCD is switched on, the first line skips the OAM scan and the first frame is blank (white).
+    // On by default. Turn this off to draw the first frame as normal.
+    pub fn lcd_on_quirk(mut self: Box<Self>, enabled: bool) -> Box<Self> {
+        self.cpu.set_lcd_on_quirk(enabled);
+        self
+    }
+
+    // Force MBC1 multicart (MBC1M) bank wiring on or off.
+    // Multicarts are detected by a second game header 256KB into a 1MB ROM, which can be wrong.
+    pub fn mbc1_multicart(mut self: Box<Self>, multicart: bool) -> Box<Self> {
+        self.cpu.set_mbc1_multicart(multicart);
+        self
+    }
+
+    // Choose what disabled cart RAM reads as. Open bus (0xFF) by default.
+    pub fn disabled_ram_read(mut self: Box<Self>, policy: DisabledRamRead) -> Box<Self> {
+        self.cpu.set_disabled_ram_read(policy);
+        self
+    }
+
+    // Start from the exact state the boot ROM leaves IO registers and palettes in.
+    // By default these start at zero (apart from the CPU registers), which some games don't expect.
+    pub fn fast_boot(mut self: Box<Self>) -> Box<Self> {
+        self.cpu.fast_boot();
+        self
+    }
+
+    fn from_mem(mem: MemBus) -> Box<Self> {
         let cpu = CPU::new(mem);
 
         Box::new(RustBoy {
             cpu:            cpu,
 
             frame:          Arc::new(Mutex::new([255; FRAME_SIZE_BYTES])),
+            frame_sender:   None,
+            post_processor: None,
+            perf_callback:  None,
+            paused:         false,
+
+            gbs:            None,
+            gbs_track:      0,
+
+            test_pattern:   None,
+            button_map:     ButtonMap::default(),
+            held_hotkeys:   Vec::new(),
+            next_cheat_id:  0,
+            deterministic:  false,
         })
     }
 
-    pub fn enable_audio(&mut self, sample_rate: usize) -> RustBoyAudioHandle {
+    // Fails if the sample rate is zero or above 384kHz, or in deterministic mode.
+    pub fn enable_audio(&mut self, sample_rate: usize) -> Result<RustBoyAudioHandle, RustBoyError> {
+        if self.deterministic {
+            return Err(RustBoyError::AudioDisabled);
+        }
+        if sample_rate == 0 || sample_rate > MAX_SAMPLE_RATE {
+            return Err(RustBoyError::InvalidSampleRate(sample_rate));
+        }
+
         let (audio_send, audio_recv) = unbounded();
 
         self.cpu.enable_audio(audio_send);
 
-        RustBoyAudioHandle {
-            resampler: Resampler::new(audio_recv, sample_rate as f64)
+        Ok(RustBoyAudioHandle {
+            resampler:      Resampler::new(audio_recv, sample_rate as f64),
+            sample_rate:    sample_rate,
+            master_volume:  1.0,
+        })
+    }
+
+    // Run audio generation as normal but discard the output.
+    // Useful for profiling without an audio device.
+    pub fn enable_audio_null(&mut self) {
+        self.cpu.enable_audio_null();
+    }
+
+    // Headless driver for test ROMs (e.g. blargg's) that print their results over the link cable.
+    // Runs until the output contains "Passed" or "Failed", or until max_frames frames have run.
+    // Each frame is 70224 cycles (1/60 of a second), so at most max_frames * 70224 cycles are emulated.
+    // The connected serial port is restored afterwards. Returns everything printed, with invalid UTF-8 replaced.
+    pub fn run_to_serial_output(&mut self, max_frames: usize) -> String {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let previous_port = self.cpu.connect_serial(Box::new(CaptureSerialPort::new(output.clone())));
+
+        for _ in 0..max_frames {
+            self.run_until_vblank();
+            let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+            if text.contains("Passed") || text.contains("Failed") {
+                break;
+            }
         }
+
+        self.cpu.connect_serial(previous_port);
+        let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+        text
     }
 
     // Call every 1/60 seconds.
     pub fn frame(&mut self, frame: &mut [u8]) {
-        self.cpu.frame_update(self.frame.clone());    // Draw video and read inputs
+        let start_time = if self.deterministic {None} else {Some(Instant::now())};
+        let (start_cycles, start_instrs) = self.cpu.counters();
+
+        self.run_until_vblank();
+
+        self.peek_frame(frame);
+
+        if let Some(post_processor) = &mut self.post_processor {
+            post_processor(frame, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        }
+
+        if self.perf_callback.is_some() {
+            let (cycles, instrs) = self.cpu.counters();
+            let timing = FrameTiming {
+                emulation_time: start_time.map_or(Duration::default(), |t| t.elapsed()),
+                target_time:    self.recommended_frame_interval(),
+                cycles:         cycles - start_cycles,
+                instructions:   instrs - start_instrs,
+            };
+            if let Some(callback) = &mut self.perf_callback {
+                callback(timing);
+            }
+        }
 
-        while self.cpu.step() {}    // Execute up to v-blanking
+        self.send_frame(frame.to_vec());
+    }
+
+    // Fast-forward: run several frames, and output only the last one.
+    // Skipped frames are still drawn internally (and generate audio), but aren't copied out,
+    // post-processed or sent to the frame channel.
+    pub fn run_frames(&mut self, count: usize, frame: &mut [u8]) {
+        if count == 0 {
+            self.peek_frame(frame);
+            return;
+        }
+
+        for _ in 1..count {
+            self.run_until_vblank();
+        }
+        self.frame(frame);
+    }
+
+    // Run the emulator for one frame, up to V-blank, without copying out the frame.
+    // Returns false if no new frame was drawn (while paused).
+    // The post-processor and frame channel are only used by frame.
+    pub fn run_until_vblank(&mut self) -> bool {
+        if self.paused {
+            self.cpu.paused_frame_update(FRAME_CYCLES as u32);  // Keep audio output going
+            false
+        } else if let Some(frame_count) = self.test_pattern {
+            testpattern::draw(testpattern::pattern_for_frame(frame_count), &mut *self.frame.lock().unwrap());
+            self.test_pattern = Some(frame_count + 1);
+            true
+        } else {
+            self.cpu.frame_update(self.frame.clone());    // Draw video and read inputs
+
+            while self.cpu.step() {}    // Execute up to v-blanking
+            if self.cpu.is_stopped() {
+                self.cpu.paused_frame_update(FRAME_CYCLES as u32);
+            }
+            true
+        }
+    }
+
+    // Copy the last completed RGBA frame, without running the emulator or applying the post-processor.
+    pub fn peek_frame(&self, frame: &mut [u8]) {
+        frame.copy_from_slice(&(*self.frame.lock().unwrap()));
+    }
+
+    // Get a copy of the last completed RGBA frame.
+    pub fn screenshot_raw(&self) -> [u8; FRAME_SIZE_BYTES] {
+        *self.frame.lock().unwrap()
+    }
+
+    // Encode the last completed frame as a PNG file.
+    pub fn screenshot_png(&self) -> Vec<u8> {
+        screenshot::encode_png(&*self.frame.lock().unwrap())
+    }
+
+    fn send_frame(&mut self, frame: Vec<u8>) {
+        if let Some(sender) = &self.frame_sender {
+            // If the receiver is behind, the frame is dropped.
+            if let Err(TrySendError::Disconnected(_)) = sender.try_send(frame) {
+                self.frame_sender = None;
+            }
+        }
+    }
 
-        let new_frame = self.frame.lock().unwrap();
-        frame.copy_from_slice(&(*new_frame));
+    // Get a copy of each completed RGBA frame, for recording.
+    // Frames are dropped if the receiver falls behind, so emulation is never blocked.
+    // Calling this again replaces the previous channel.
+    pub fn frame_channel(&mut self) -> Receiver<Vec<u8>> {
+        let (frame_send, frame_recv) = bounded(FRAME_CHANNEL_SIZE);
+        self.frame_sender = Some(frame_send);
+        frame_recv
+    }
+
+    // Apply an effect to each completed frame, before it is returned from frame.
+    // Frames sent to the frame channel are also processed.
+    pub fn set_post_processor(&mut self, post_processor: PostProcessor) {
+        self.post_processor = Some(post_processor);
+    }
+
+    // Get the timing of each frame, e.g. for an FPS or load meter.
+    pub fn set_perf_callback(&mut self, callback: PerfCallback) {
+        self.perf_callback = Some(callback);
+    }
+
+    // While paused, frame doesn't run the emulator. The frame stays the same and audio fades to silence.
+    // The audio handle keeps receiving samples, so it doesn't need to be recreated.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    // The real time between frames on hardware (~59.73Hz).
+    // This is the same in double speed mode, as the LCD timing doesn't change.
+    pub fn recommended_frame_interval(&self) -> Duration {
+        Duration::from_nanos(FRAME_CYCLES * 1_000_000_000 / CLOCK_RATE)
+    }
+
+    // Get a half-size (80x72) RGBA copy of the last frame, for save previews.
+    pub fn thumbnail(&self) -> Vec<u8> {
+        const THUMB_WIDTH: usize = SCREEN_WIDTH / 2;
+        const THUMB_HEIGHT: usize = SCREEN_HEIGHT / 2;
+
+        let frame = self.frame.lock().unwrap();
+        let mut thumbnail = vec![0; THUMB_WIDTH * THUMB_HEIGHT * 4];
+
+        // Average each 2x2 block of pixels.
+        for (i, out) in thumbnail.chunks_exact_mut(4).enumerate() {
+            let x = (i % THUMB_WIDTH) * 2;
+            let y = (i / THUMB_WIDTH) * 2;
+            for (c, o) in out.iter_mut().enumerate() {
+                let sum = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)].iter()
+                    .map(|(px, py)| frame[(py * SCREEN_WIDTH + px) * 4 + c] as u16)
+                    .sum::<u16>();
+                *o = (sum / 4) as u8;
+            }
+        }
+
+        thumbnail
+    }
+
+    // Get the full 256x256 RGBA background map, with the visible screen area outlined in red.
+    pub fn dump_background(&self) -> Vec<u8> {
+        self.cpu.dump_background()
+    }
+
+    // Get the raw tile data (0x1800 bytes) from a VRAM bank. Bank 1 only exists on CGB.
+    pub fn dump_tiles(&self, bank: u8) -> Vec<u8> {
+        self.cpu.dump_tiles(bank)
+    }
+
+    // Get both tile maps (0x800 bytes) from a VRAM bank. On CGB, bank 1 holds the tile attributes.
+    pub fn dump_tilemap(&self, bank: u8) -> Vec<u8> {
+        self.cpu.dump_tilemap(bank)
+    }
+
+    // Enhancement: draw the background halfway between the previous and current scroll position.
+    // This smooths out slow scrolling, but isn't accurate. Off by default.
+    pub fn set_smooth_scroll(&mut self, enabled: bool) {
+        self.cpu.set_smooth_scroll(enabled);
+    }
+
+    // Lines in the last frame with more than 10 objects, so some weren't drawn.
+    pub fn sprite_overflow_lines(&self) -> Vec<u8> {
+        self.cpu.sprite_overflow_lines()
+    }
+
+    // Debug overlay: outline each object. Objects that aren't drawn due to the 10 per line limit are red.
+    pub fn set_sprite_debug_overlay(&mut self, enabled: bool) {
+        self.cpu.set_sprite_debug(enabled);
+    }
+
+    // Adjust CGB colours to look like a particular screen. Has no effect on DMG games.
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.cpu.set_colour_correction(correction);
     }
 
     pub fn set_button(&mut self, button: Button, val: bool) {
@@ -99,20 +582,247 @@ impl RustBoy {
         }
     }
 
+    // Press or release the button bound to a front-end key code. Unbound keys are ignored.
+    pub fn set_key_state(&mut self, keycode: u32, pressed: bool) {
+        if let Some(button) = self.button_map.get(keycode) {
+            self.set_button(button, pressed);
+        }
+    }
+
+    // Look up the hotkey bound to a key combo. The front-end is responsible for the action.
+    pub fn get_hotkey(&self, keycode: u32, modifiers: Modifiers) -> Option<Hotkey> {
+        self.button_map.get_hotkey(keycode, modifiers)
+    }
+
+    // Handle a key event from the front-end. A bound key combo is a hotkey, otherwise the key is a game button.
+    // Hotkeys are returned with true when pressed, and with false when the key is released, whatever
+    // modifiers are held by then. Pause is carried out here. The front-end carries out the actions the
+    // library can't: reset and save states need the ROM and save files to be reloaded, and fast-forward
+    // is done by running frames with run_frames while the key is held.
+    pub fn handle_key(&mut self, keycode: u32, modifiers: Modifiers, pressed: bool) -> Option<(Hotkey, bool)> {
+        let held = self.held_hotkeys.iter().position(|&(held_key, _)| held_key == keycode);
+        if !pressed {
+            if let Some(i) = held {
+                let (_, hotkey) = self.held_hotkeys.swap_remove(i);
+                return Some((hotkey, false));
+            }
+            self.set_key_state(keycode, false);
+            return None;
+        }
+
+        match self.button_map.get_hotkey(keycode, modifiers) {
+            Some(hotkey) => {
+                // Key repeats don't toggle pause again.
+                if held.is_none() {
+                    if hotkey == Hotkey::Pause {
+                        self.paused = !self.paused;
+                    }
+                    self.held_hotkeys.push((keycode, hotkey));
+                }
+                Some((hotkey, true))
+            },
+            None => {
+                self.set_key_state(keycode, true);
+                None
+            }
+        }
+    }
+
+    // Replace the key bindings used by set_key_state, get_hotkey and handle_key.
+    pub fn set_button_map(&mut self, button_map: ButtonMap) {
+        self.button_map = button_map;
+    }
+
+    // Hold buttons from power on, e.g. to boot into a game's debug mode.
+    // Call before the first frame. Any buttons not provided are released.
+    pub fn set_initial_buttons(&mut self, held: ButtonState) {
+        self.cpu.set_initial_input(held.buttons, held.directions);
+    }
+
+    // Advance one frame with the inputs of both players, for lockstep netplay.
+    // The player is the local player's number: 0 or 1. Both players share the joypad, but the inputs
+    // are hashed in player order, so peers that disagree on who is who see a desync.
+    // Returns a hash of the emulator state, which should match between peers each frame.
+    // The hash is stable across platforms, so peers can run different builds.
+    // Use deterministic to create the emulator, and frame_channel to get the video.
+    pub fn advance_lockstep(&mut self, local_input: ButtonState, remote_input: ButtonState, player: u8) -> u64 {
+        let players = if player == 0 {[local_input, remote_input]} else {[remote_input, local_input]};
+        let input = local_input | remote_input;
+
+        self.cpu.set_button(Buttons::all(), false);
+        self.cpu.set_button(input.buttons, true);
+        self.cpu.set_direction(Directions::all(), false);
+        self.cpu.set_direction(input.directions, true);
+
+        self.run_until_vblank();
+        let frame = self.frame.lock().unwrap().to_vec();   // Before any local post-processing
+        self.send_frame(frame);
+
+        let mut hasher = Fnv1a::default();
+        self.cpu.hash_state(&mut hasher);
+        hasher.write(&*self.frame.lock().unwrap());
+        for player_input in players.iter() {
+            hasher.write(&[player_input.buttons.bits(), player_input.directions.bits()]);
+        }
+        hasher.finish()
+    }
+
     pub fn cart_name(&self) -> String {
         self.cpu.cart_name()
     }
+
+    // The title from the cart header, without any text decoding.
+    pub fn cart_title_bytes(&self) -> Vec<u8> {
+        self.cpu.cart_title_bytes()
+    }
+
+    // The hardware model in use. CGB carts run as DMG if a non-default palette was chosen.
+    pub fn model(&self) -> Model {
+        if self.cpu.is_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    // Get the contents of battery-backed cart RAM, in the same format as the save file.
+    // Returns None if the cart has no battery.
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.cpu.save_data()
+    }
+
+    // Add a Game Genie (ABC-DEF or ABC-DEF-GHI) or GameShark (01VVLLHH) code.
+    // Game Genie codes patch ROM reads. GameShark codes write to RAM at the start of every frame.
+    pub fn add_cheat(&mut self, code: &str) -> Result<CheatId, String> {
+        let cheat = cheats::parse(code)?;
+        let id = CheatId(self.next_cheat_id);
+        self.next_cheat_id += 1;
+        self.cpu.add_cheat(id, cheat);
+        Ok(id)
+    }
+
+    // Removing a GameShark code doesn't restore the RAM it was writing to.
+    pub fn remove_cheat(&mut self, id: CheatId) {
+        self.cpu.remove_cheat(id);
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cpu.clear_cheats();
+    }
+
+    // Read memory as the CPU would see it, without running any cycles. For cheats and scripting.
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.cpu.read_byte(addr)
+    }
+
+    // Write memory as the CPU would, without running any cycles. For cheats and scripting.
+    // This has the same side effects as a CPU write: writing to 0x0000-0x7FFF switches cart banks,
+    // and writing IO registers can start DMA, trigger audio channels, etc.
+    // Like the CPU, VRAM and OAM writes are ignored while the LCD is reading them. VRAM is locked
+    // for the write, so this is safe while the render thread is drawing.
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.cpu.write_byte(addr, val);
+    }
+
+    // Get the currently selected cart ROM and RAM banks.
+    pub fn current_banks(&self) -> BankState {
+        self.cpu.current_banks()
+    }
+
+    // Get the hardware the cartridge header reports: battery, clock, rumble, etc.
+    pub fn cart_features(&self) -> CartFeatureSet {
+        self.cpu.cart_features()
+    }
+
+    // Poll the MBC5 rumble motor, e.g. once per frame to drive gamepad haptics.
+    // Returns true if the motor is on or was turned on since the last call. Always false without rumble.
+    pub fn take_rumble_state(&mut self) -> bool {
+        self.cpu.take_rumble_state()
+    }
+
+    // Map custom hardware registers into unused IO space (0xFF00-0xFF7F).
+    // Fails if the range overlaps existing hardware registers or another handler.
+    pub fn register_io_handler(&mut self, range: RangeInclusive<u16>, handler: Box<dyn IoHandler>) -> Result<(), String> {
+        self.cpu.register_io_handler(range, handler)
+    }
+
+    // Connect the CGB infrared port. Without a transceiver, no light is ever received.
+    pub fn set_ir_transceiver(&mut self, transceiver: Box<dyn IrTransceiver>) {
+        self.cpu.set_ir_transceiver(transceiver);
+    }
+
+    // Connect a link cable. By default nothing is connected, and transfers receive 0xFF.
+    pub fn connect_serial(&mut self, port: Box<dyn SerialPort>) {
+        self.cpu.connect_serial(port);
+    }
+
+    // Called when the CPU enters or exits HALT or STOP mode, or switches speed.
+    pub fn set_power_state_callback(&mut self, callback: Box<dyn FnMut(PowerState)>) {
+        self.cpu.set_power_state_callback(callback);
+    }
+
+    // Get all audio channel triggers since the last call.
+    // Useful for syncing visuals to in-game music.
+    pub fn drain_audio_events(&mut self) -> Vec<AudioEvent> {
+        self.cpu.drain_audio_events()
+    }
+
+    // Recent samples of each channel (square 1, square 2, wave, noise) before mixing, oldest first.
+    // Useful for drawing an oscilloscope for each channel.
+    pub fn scope_buffers(&self) -> [Vec<i8>; 4] {
+        self.cpu.scope_buffers()
+    }
+
+    // Read the wave channel's sample RAM (0xFF30-0xFF3F).
+    pub fn get_wave_ram(&self) -> [u8; 16] {
+        self.cpu.get_wave_ram()
+    }
+
+    // Write a custom waveform to the wave channel's sample RAM, even while it is playing.
+    pub fn set_wave_ram(&mut self, data: [u8; 16]) {
+        self.cpu.set_wave_ram(data);
+    }
 }
 
 pub struct RustBoyAudioHandle {
-    resampler: Resampler,
+    resampler:      Resampler,
+    sample_rate:    usize,
+    master_volume:  f32,
 }
 
 impl RustBoyAudioHandle {
+    // Stereo samples generated each frame at the output sample rate.
+    // A packet needs two f32 values per stereo sample.
+    pub fn samples_per_frame(&self) -> usize {
+        self.sample_rate.div_ceil(FRAMES_PER_SECOND)
+    }
+
+    // Current audio latency in stereo samples at the output rate, for A/V sync.
+    // This is the audio generated but not yet returned, plus one packet. It grows if packets
+    // aren't requested as fast as the emulator produces them.
+    pub fn latency_samples(&self) -> usize {
+        self.resampler.latency_samples() + self.samples_per_frame()
+    }
+
+    // Choose the interpolation used to convert to the output sample rate. Linear by default.
+    pub fn set_quality(&mut self, quality: ResampleQuality) {
+        self.resampler.set_quality(quality);
+    }
+
+    // Remove DC offset from the output, like the hardware does. On by default.
+    pub fn set_highpass(&mut self, enabled: bool) {
+        self.resampler.set_highpass(enabled);
+    }
+
+    // Gain applied to the output, on top of the game's own volume. Clamped between 0 (silent) and 1 (full).
+    pub fn set_master_volume(&mut self, gain: f32) {
+        self.master_volume = if gain.is_nan() {0.0} else {gain.clamp(0.0, 1.0)};
+    }
+
     pub fn get_audio_packet(&mut self, packet: &mut [f32]) {
         for (o_frame, i_frame) in packet.chunks_exact_mut(2).zip(&mut self.resampler) {
             for (o, i) in o_frame.iter_mut().zip(i_frame.iter()) {
-                *o = *i;
+                *o = *i * self.master_volume;
             }
         }
     }
@@ -132,7 +842,1073 @@ impl RustBoy {
         self.cpu.get_instr()
     }
 
+    // Get the PC and the instruction about to be executed.
+    pub fn current_instruction(&self) -> (u16, String) {
+        (self.cpu.get_state().pc, debug::disassemble(self.cpu.get_instr()))
+    }
+
     pub fn get_mem_at(&self, loc: u16) -> u8 {
         self.cpu.get_mem_at(loc)
     }
-}
\ No newline at end of file
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.cpu.set_pc(addr);
+    }
+
+    pub fn set_sp(&mut self, addr: u16) {
+        self.cpu.set_sp(addr);
+    }
+
+    // Push the current PC and jump to the address, like a CALL.
+    pub fn jump_to(&mut self, addr: u16) {
+        self.cpu.jump_to(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An emulator running code from the test ROM, with no mapper.
+    fn rustboy(code: &[u8]) -> Box<RustBoy> {
+        RustBoy::new_in_memory(testutil::rom(code), None, UserPalette::Greyscale)
+    }
+
+    #[test]
+    fn thumbnail() {
+        let rustboy = rustboy(&[0x18, 0xFE]);
+        for pixel in rustboy.frame.lock().unwrap().chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[10, 20, 30, 255]);
+        }
+
+        let thumbnail = rustboy.thumbnail();
+        assert_eq!(thumbnail.len(), 80 * 72 * 4);
+        assert!(thumbnail.chunks_exact(4).all(|pixel| pixel == [10, 20, 30, 255]));
+    }
+
+    // Draw a DMG cart with a colour 3 sprite in the top-left corner, and return the top-left pixel.
+    fn sprite_pixel(palette: UserPalette) -> Vec<u8> {
+        let mut rom = testutil::rom(&[0x18, 0xFE]);
+        rom[0x134] = 0x70;  // Title hash of Link's Awakening
+        rom[0x14B] = 0x01;  // Nintendo licensee
+        let mut rustboy = RustBoy::new_in_memory(rom, None, palette);
+
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8010..0x8020 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for (i, &val) in [16, 8, 1, 0].iter().enumerate() {
+            rustboy.write_byte(0xFE00 + i as u16, val);
+        }
+        rustboy.write_byte(0xFF47, 0x00);
+        rustboy.write_byte(0xFF48, 0xE4);
+        rustboy.write_byte(0xFF40, 0x83);
+        top_left_pixel(&mut rustboy)
+    }
+
+    // Run a few frames, to skip the blank frame after the LCD is enabled, and return the top-left pixel.
+    fn top_left_pixel(rustboy: &mut RustBoy) -> Vec<u8> {
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        frame[0..4].to_vec()
+    }
+
+    // Draw an 8x16 sprite at the top-left with the OAM attributes, and return the colour of each row.
+    // The top tile has colour 3 in its first row then colour 1, and the bottom tile is all colour 2.
+    fn tall_sprite_rows(attributes: u8) -> Vec<Vec<u8>> {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.write_byte(0xFF40, 0x00);
+        for row in 0..16 {
+            let (lo, hi) = match row {
+                0       => (0xFF, 0xFF),
+                1..=7   => (0xFF, 0x00),
+                _       => (0x00, 0xFF)
+            };
+            rustboy.write_byte(0x8020 + row * 2, lo);
+            rustboy.write_byte(0x8021 + row * 2, hi);
+        }
+        // The low bit of the tile number is ignored.
+        for (i, &val) in [16, 8, 3, attributes].iter().enumerate() {
+            rustboy.write_byte(0xFE00 + i as u16, val);
+        }
+        rustboy.write_byte(0xFF47, 0x00);
+        rustboy.write_byte(0xFF48, 0xE4);
+        rustboy.write_byte(0xFF40, 0x87);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        (0..16).map(|y| frame[(y * SCREEN_WIDTH * 4)..(y * SCREEN_WIDTH * 4 + 4)].to_vec()).collect()
+    }
+
+    #[test]
+    fn tall_sprite_y_flip() {
+        let rows = tall_sprite_rows(0x00);
+        let (colour_3, colour_1, colour_2) = (rows[0].clone(), rows[1].clone(), rows[8].clone());
+        assert!(colour_3 != colour_1 && colour_1 != colour_2 && colour_2 != colour_3);
+        let mut expected = vec![colour_3];
+        expected.extend(std::iter::repeat_n(colour_1, 7));
+        expected.extend(std::iter::repeat_n(colour_2, 8));
+        assert_eq!(rows, expected);
+
+        // Flipped: the bottom tile is drawn first, and both tiles are flipped.
+        expected.reverse();
+        assert_eq!(tall_sprite_rows(0x40), expected);
+    }
+
+    #[test]
+    fn forced_cgb_sprite_palette() {
+        assert_eq!(sprite_pixel(UserPalette::Greyscale), [0, 0, 0, 255]);
+        assert_eq!(sprite_pixel(UserPalette::Default), [0x00, 0x4A, 0x00, 255]);
+    }
+
+    #[test]
+    fn per_line_palette() {
+        // In each H-blank, set BGP to the next line number. The blank background uses colour 0,
+        // so line n should be drawn with shade n % 4.
+        let mut rustboy = rustboy(&[
+            0xF0, 0x41, 0xE6, 0x03, 0x20, 0xFA, // Wait for H-blank
+            0xF0, 0x44, 0x3C, 0xE0, 0x47,       // BGP = LY + 1
+            0xF0, 0x41, 0xE6, 0x03, 0x28, 0xFA, // Wait for H-blank to end
+            0x18, 0xED
+        ]);
+        rustboy.write_byte(0xFF40, 0x91);
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+
+        let line_colour = |y: usize| &frame[(y * SCREEN_WIDTH * 4)..(y * SCREEN_WIDTH * 4 + 4)];
+        for y in 0..4 {
+            for other in (y + 1)..4 {
+                assert_ne!(line_colour(y), line_colour(other));
+            }
+        }
+        for y in 0..SCREEN_HEIGHT {
+            assert_eq!(line_colour(y), line_colour(y % 4), "line {}", y);
+        }
+    }
+
+    #[test]
+    fn inline_renderer() {
+        // Per-line palette changes, drawn on the render thread and inline, give the same RGBA output.
+        let code = [
+            0xF0, 0x41, 0xE6, 0x03, 0x20, 0xFA,
+            0xF0, 0x44, 0x3C, 0xE0, 0x47,
+            0xF0, 0x41, 0xE6, 0x03, 0x28, 0xFA,
+            0x18, 0xED
+        ];
+        let mut frames = [vec![0; FRAME_SIZE_BYTES], vec![0; FRAME_SIZE_BYTES]];
+        for (rustboy, frame) in [rustboy(&code), rustboy(&code).deterministic()].iter_mut().zip(frames.iter_mut()) {
+            rustboy.write_byte(0xFF40, 0x91);
+            rustboy.run_frames(3, frame);
+        }
+        assert!(frames[0] == frames[1]);
+        assert!(frames[1].chunks_exact(4).all(|pixel| pixel[3] == 255));
+        assert_ne!(frames[1][0..4], frames[1][(SCREEN_WIDTH * 4)..(SCREEN_WIDTH * 4 + 4)]);
+    }
+
+    #[test]
+    fn write_byte_video_access() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]).fast_boot();
+        rustboy.write_byte(0xFF40, 0x93);   // LCD, sprites and background on
+        rustboy.run_until_vblank();
+
+        // In V-blank VRAM and OAM are free.
+        rustboy.write_byte(0x8000, 0x12);
+        rustboy.write_byte(0xFE00, 0x34);
+        assert_eq!(rustboy.read_byte(0x8000), 0x12);
+        assert_eq!(rustboy.read_byte(0xFE00), 0x34);
+
+        // While the LCD is drawing, both are blocked.
+        while (rustboy.read_byte(0xFF41) & 0x3) != 3 {
+            rustboy.cpu.step();
+        }
+        rustboy.write_byte(0x8000, 0x56);
+        rustboy.write_byte(0xFE00, 0x78);
+        assert_eq!(rustboy.read_byte(0x8000), 0xFF);
+        assert_eq!(rustboy.read_byte(0xFE00), 0xFF);
+
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0x8000), 0x12);
+        assert_eq!(rustboy.read_byte(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn read_byte_during_dma() {
+        let mut rustboy = rustboy(&[0x3E, 0xC0, 0xE0, 0x46, 0x00, 0x00, 0x18, 0xFE]);  // LD A,C0; LDH (46),A; NOP; NOP
+        rustboy.write_byte(0xFF40, 0x00);
+        rustboy.write_byte(0xFE00, 0x5A);
+        rustboy.write_byte(0xFE9F, 0x77);
+        rustboy.write_byte(0xC000, 0x5A);
+        // JP CODE_START at the entry point, then up to the second NOP, when the copy starts.
+        for _ in 0..5 {
+            rustboy.cpu.step();
+        }
+
+        // The CPU would read 0xFF here, but read_byte sees the OAM contents.
+        assert_eq!(rustboy.read_byte(0xFE00), 0x5A);
+        assert_eq!(rustboy.read_byte(0xFE9F), 0x77);
+    }
+
+    #[test]
+    fn ly_after_lcd_off() {
+        // Turn the LCD off at line 64, then read LY in the next instruction.
+        let mut rustboy = rustboy(&[
+            0xF0, 0x44, 0xFE, 0x40, 0x20, 0xFA, // Wait for LY = 64
+            0xAF, 0xE0, 0x40,                   // LCDC = 0
+            0xF0, 0x44, 0xEA, 0x00, 0xC0,       // Store LY at 0xC000
+            0x18, 0xFE
+        ]);
+        rustboy.write_byte(0xFF40, 0x91);
+        rustboy.write_byte(0xC000, 0xFF);
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(2, &mut frame);
+
+        assert_eq!(rustboy.read_byte(0xFF40), 0x00);
+        assert_eq!(rustboy.read_byte(0xC000), 0);
+        assert_eq!(rustboy.read_byte(0xFF44), 0);
+    }
+
+    #[test]
+    fn post_processor() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.set_post_processor(Box::new(|frame, width, height| {
+            assert_eq!((width, height), (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32));
+            for pixel in frame.chunks_exact_mut(4) {
+                for colour in &mut pixel[0..3] {
+                    *colour = 255 - *colour;
+                }
+            }
+        }));
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+
+        let mut raw = vec![0; FRAME_SIZE_BYTES];
+        rustboy.peek_frame(&mut raw);
+        assert!(raw.chunks_exact(4).all(|pixel| pixel == [255, 255, 255, 255]));
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn sprite_debug_overlay() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.set_sprite_debug_overlay(true);
+        rustboy.write_byte(0xFF40, 0x00);
+        // 11 sprites on lines 10-17, 10 pixels apart.
+        for n in 0..11 {
+            let addr = 0xFE00 + n * 4;
+            rustboy.write_byte(addr, 16 + 10);
+            rustboy.write_byte(addr + 1, 8 + 20 + (n as u8) * 10);
+        }
+        rustboy.write_byte(0xFF40, 0x93);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        let pixel = |x: usize, y: usize| &frame[((y * SCREEN_WIDTH + x) * 4)..((y * SCREEN_WIDTH + x) * 4 + 4)];
+
+        let green = [0, 255, 0, 255];
+        let white = [255, 255, 255, 255];
+        for (x, y) in [(20, 10), (27, 10), (20, 17), (27, 17), (23, 10), (20, 13)] {
+            assert_eq!(pixel(x, y), green, "({}, {})", x, y);
+        }
+        for (x, y) in [(19, 10), (23, 13), (20, 9), (20, 18)] {
+            assert_eq!(pixel(x, y), white, "({}, {})", x, y);
+        }
+        // Past the limit of 10 per line.
+        assert_eq!(pixel(120, 10), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut rustboy = RustBoy::new_test_pattern();
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        for _ in 0..2 {
+            rustboy.frame(&mut frame);
+            let pixel = |x: usize, y: usize| frame[((y * SCREEN_WIDTH + x) * 4)..][..4].to_vec();
+            // Colour bars: 20 pixels each.
+            assert_eq!(pixel(0, 0), [255, 255, 255, 255]);
+            assert_eq!(pixel(20, 100), [255, 255, 0, 255]);
+            assert_eq!(pixel(159, 143), [0, 0, 0, 255]);
+
+            rustboy.frame(&mut frame);
+            let pixel = |x: usize, y: usize| frame[((y * SCREEN_WIDTH + x) * 4)..][..4].to_vec();
+            assert_eq!(pixel(0, 0), [255, 255, 255, 255]);
+            assert_eq!(pixel(8, 0), [0, 0, 0, 255]);
+            assert_eq!(pixel(8, 8), [255, 255, 255, 255]);
+
+            rustboy.frame(&mut frame);
+            let pixel = |x: usize, y: usize| frame[((y * SCREEN_WIDTH + x) * 4)..][..4].to_vec();
+            assert_eq!(pixel(0, 50), [0, 0, 0, 255]);
+            assert_eq!(pixel(159, 50), [255, 255, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn sprite_overflow_lines() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.write_byte(0xFF40, 0x00);
+        // 11 sprites on lines 10-17, and 10 on lines 40-47.
+        for n in 0..21 {
+            let addr = 0xFE00 + n * 4;
+            rustboy.write_byte(addr, if n < 11 {16 + 10} else {16 + 40});
+            rustboy.write_byte(addr + 1, 8 + (n as u8 % 11) * 10);
+        }
+        rustboy.write_byte(0xFF40, 0x93);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        assert_eq!(rustboy.sprite_overflow_lines(), (10..=17).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn model() {
+        let mut rom = testutil::rom(&[0x18, 0xFE]);
+        assert_eq!(RustBoy::new_in_memory(rom.clone(), None, UserPalette::Default).model(), Model::Dmg);
+        rom[0x143] = 0x80;
+        assert_eq!(RustBoy::new_in_memory(rom.clone(), None, UserPalette::Default).model(), Model::Cgb);
+        assert_eq!(RustBoy::new_in_memory(rom, None, UserPalette::Greyscale).model(), Model::Dmg);
+    }
+
+    // Count timer interrupts at 262144Hz over 3 frames on a CGB, optionally in double speed mode.
+    fn timer_interrupts(double_speed: bool) -> u8 {
+        let mut code = if double_speed {
+            vec![0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]    // Prepare the speed switch, STOP
+        } else {
+            vec![]
+        };
+        code.extend_from_slice(&[
+            0x3E, 0x05, 0xE0, 0x07,             // TAC = 262144Hz
+            0xAF, 0xE0, 0x0F,                   // Clear IF
+            0x21, 0x00, 0xC0, 0x77,             // Count at 0xC000
+            0xF0, 0x0F, 0xE6, 0x04, 0x28, 0xFA, // Wait for the timer flag
+            0x34, 0xAF, 0xE0, 0x0F, 0x18, 0xF4  // Count it and clear IF
+        ]);
+        let mut rom = testutil::rom(&code);
+        rom[0x143] = 0x80;
+        let mut rustboy = RustBoy::new_in_memory(rom, None, UserPalette::Default);
+        rustboy.run_until_vblank();
+        let start = rustboy.read_byte(0xC000);
+        for _ in 0..3 {
+            rustboy.run_until_vblank();
+        }
+        assert_eq!(test_bit!(rustboy.read_byte(0xFF4D), 7), double_speed);
+        rustboy.read_byte(0xC000) - start
+    }
+
+    #[test]
+    fn double_speed_timer() {
+        // 4096 machine cycles per overflow, so about 17 per frame at normal speed.
+        let normal = timer_interrupts(false);
+        let double = timer_interrupts(true);
+        assert!((50..=52).contains(&normal), "{} interrupts", normal);
+        assert!((normal * 2 - 1..=normal * 2 + 1).contains(&double), "{} interrupts", double);
+    }
+
+    #[test]
+    fn tile_data_select() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for addr in 0x9000..0x9010 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        for addr in 0x9800..0x9C00 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        rustboy.write_byte(0xFF47, 0xE4);
+
+        // Tile 0 is at 0x8000 with LCDC bit 4 set, and 0x9000 without.
+        rustboy.write_byte(0xFF40, 0x91);
+        assert_eq!(top_left_pixel(&mut rustboy), [0, 0, 0, 255]);
+        rustboy.write_byte(0xFF40, 0x81);
+        assert_eq!(top_left_pixel(&mut rustboy), [255, 255, 255, 255]);
+        rustboy.write_byte(0xFF40, 0x91);
+        assert_eq!(top_left_pixel(&mut rustboy), [0, 0, 0, 255]);
+    }
+
+    // Draw a white background with the window at WX. The window's first tile is black, the rest are white.
+    // Returns the colour of each pixel of the top line, as true for black.
+    fn window_line(window_x: u8, scroll_x: u8) -> Vec<bool> {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        for addr in 0x8010..0x8020 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for addr in 0x9800..0xA000 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        rustboy.write_byte(0x9C00, 0x01);
+        rustboy.write_byte(0xFF47, 0xE4);
+        rustboy.write_byte(0xFF43, scroll_x);
+        rustboy.write_byte(0xFF4A, 0);
+        rustboy.write_byte(0xFF4B, window_x);
+        rustboy.write_byte(0xFF40, 0xF1);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        frame.chunks_exact(4).take(SCREEN_WIDTH).map(|pixel| pixel[0] == 0).collect()
+    }
+
+    #[test]
+    fn window_position() {
+        // WX=7 is the left edge.
+        let line = window_line(7, 0);
+        assert!(line[0..8].iter().all(|&black| black));
+        assert!(!line[8]);
+
+        // WX=166 hides the window.
+        assert!(window_line(166, 0).iter().all(|&black| !black));
+        let line = window_line(165, 0);
+        assert!(line[158] && line[159]);
+        assert!(!line[157]);
+
+        // DMG glitch: WX=0 skips the fine scroll amount of extra window pixels.
+        let line = window_line(0, 0);
+        assert!(line[0]);
+        assert!(!line[1]);
+        assert!(window_line(0, 3).iter().all(|&black| !black));
+    }
+
+    #[test]
+    fn window_toggled_mid_frame() {
+        // Hide the window on lines 16-31, by polling LY.
+        let code = [
+            0xF0, 0x44, 0xFE, 0x10, 0x20, 0xFA, 0x3E, 0xD1, 0xE0, 0x40,
+            0xF0, 0x44, 0xFE, 0x20, 0x20, 0xFA, 0x3E, 0xF1, 0xE0, 0x40,
+            0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x18, 0xE4,
+        ];
+        let mut rustboy = rustboy(&code);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        for addr in 0x8010..0x8020 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for addr in 0x9800..0xA000 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        // Window tile rows 0 and 2 are black, the rest are white.
+        for addr in (0x9C00..0x9C20).chain(0x9C40..0x9C60) {
+            rustboy.write_byte(addr, 0x01);
+        }
+        rustboy.write_byte(0xFF47, 0xE4);
+        rustboy.write_byte(0xFF4A, 0);
+        rustboy.write_byte(0xFF4B, 7);
+        rustboy.write_byte(0xFF40, 0xF1);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        let black_lines = frame.chunks_exact(SCREEN_WIDTH * 4)
+            .map(|line| line[0] == 0)
+            .collect::<Vec<_>>();
+
+        // The window's line counter pauses while it is hidden, so tile row 2 is drawn from line 32.
+        let expected = (0..SCREEN_HEIGHT).map(|y| y < 8 || (32..40).contains(&y)).collect::<Vec<_>>();
+        assert_eq!(black_lines, expected);
+    }
+
+    #[test]
+    fn frame_channel() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        let receiver = rustboy.frame_channel();
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        for _ in 0..3 {
+            rustboy.frame(&mut frame);
+        }
+        assert_eq!(receiver.try_iter().count(), 3);
+
+        // Frames are dropped when the receiver falls behind.
+        for _ in 0..(FRAME_CHANNEL_SIZE + 2) {
+            rustboy.frame(&mut frame);
+        }
+        let frames = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(frames.len(), FRAME_CHANNEL_SIZE);
+        assert_eq!(frames[0], frame);
+    }
+
+    #[test]
+    fn background_dump() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        for addr in 0x8010..0x8020 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        rustboy.write_byte(0x9800 + (8 * 32) + 8, 0x01);
+        rustboy.write_byte(0xFF47, 0xE4);
+        rustboy.write_byte(0xFF40, 0x91);
+
+        let background = rustboy.dump_background();
+        let pixel = |x: usize, y: usize| &background[((y * 256) + x) * 4..][..4];
+        assert_eq!(background.len(), 256 * 256 * 4);
+        assert_eq!(pixel(64, 64), [0, 0, 0, 255]);
+        assert_eq!(pixel(71, 71), [0, 0, 0, 255]);
+        assert_eq!(pixel(72, 64), [255, 255, 255, 255]);
+        assert_eq!(pixel(200, 200), [255, 255, 255, 255]);
+
+        // Viewport outline.
+        assert_eq!(pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(159, 143), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn vram_bank_dump() {
+        let mut rom = testutil::rom(&[0x18, 0xFE]);
+        rom[0x143] = 0x80;
+        let mut cgb = RustBoy::new_in_memory(rom, None, UserPalette::Default);
+        cgb.write_byte(0xFF40, 0x00);
+        for (bank, val) in [(0, 0x12), (1, 0x34)] {
+            cgb.write_byte(0xFF4F, bank);
+            cgb.write_byte(0x8001, val);
+            cgb.write_byte(0x9C00, val);
+        }
+
+        let tiles = [cgb.dump_tiles(0), cgb.dump_tiles(1)];
+        assert_eq!(tiles[0].len(), 0x1800);
+        assert_eq!(tiles[1].len(), 0x1800);
+        assert_ne!(tiles[0], tiles[1]);
+        assert_eq!((tiles[0][1], tiles[1][1]), (0x12, 0x34));
+
+        let maps = [cgb.dump_tilemap(0), cgb.dump_tilemap(1)];
+        assert_eq!((maps[0][0x400], maps[1][0x400]), (0x12, 0x34));
+
+        // DMG only has bank 0.
+        let dmg = rustboy(&[0x18, 0xFE]);
+        assert!(dmg.dump_tiles(1).is_empty());
+        assert!(dmg.dump_tilemap(1).is_empty());
+    }
+
+    #[test]
+    fn run_until_vblank() {
+        let mut rustboy = rustboy(&[0xF0, 0x04, 0xE0, 0x47, 0x18, 0xFA]).fast_boot().deterministic();
+        assert!(rustboy.run_until_vblank());
+        assert_eq!(rustboy.read_byte(0xFF44), 144);
+
+        // Peeking doesn't run the emulator.
+        let div = rustboy.read_byte(0xFF04);
+        let mut frames = [vec![0; FRAME_SIZE_BYTES], vec![0; FRAME_SIZE_BYTES]];
+        rustboy.peek_frame(&mut frames[0]);
+        rustboy.peek_frame(&mut frames[1]);
+        assert!(frames[0] == frames[1]);
+        assert_eq!(rustboy.read_byte(0xFF04), div);
+        assert_eq!(rustboy.read_byte(0xFF44), 144);
+
+        rustboy.set_paused(true);
+        assert!(!rustboy.run_until_vblank());
+        assert_eq!(rustboy.read_byte(0xFF04), div);
+    }
+
+    #[test]
+    fn run_frames() {
+        let code = [0xF0, 0x04, 0xE0, 0x47, 0x18, 0xFA];
+        let new = || rustboy(&code).fast_boot().deterministic();
+        let mut fast = new();
+        let mut normal = new();
+
+        let processed = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = processed.clone();
+        fast.set_post_processor(Box::new(move |_, _, _| counter.set(counter.get() + 1)));
+        let receiver = fast.frame_channel();
+
+        // Only the last frame is output, but it matches running each frame.
+        let mut frames = [vec![0; FRAME_SIZE_BYTES], vec![0; FRAME_SIZE_BYTES]];
+        fast.run_frames(5, &mut frames[0]);
+        for _ in 0..5 {
+            normal.frame(&mut frames[1]);
+        }
+        assert!(frames[0] == frames[1]);
+        assert_eq!(fast.read_byte(0xFF04), normal.read_byte(0xFF04));
+        assert_eq!(processed.get(), 1);
+        assert_eq!(receiver.try_iter().count(), 1);
+
+        // No frames: the last frame is copied without running.
+        let div = fast.read_byte(0xFF04);
+        fast.run_frames(0, &mut frames[0]);
+        assert_eq!(fast.read_byte(0xFF04), div);
+        assert_eq!(processed.get(), 1);
+
+        // Audio is generated for the skipped frames.
+        let mut rustboy = rustboy(&code).fast_boot();
+        let audio = rustboy.enable_audio(44100).unwrap();
+        rustboy.run_frames(5, &mut frames[0]);
+        assert!(audio.latency_samples() >= audio.samples_per_frame() * 5);
+    }
+
+    #[test]
+    fn handle_key() {
+        // Store the buttons at 0xC000, forever.
+        let mut rustboy = rustboy(&[0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF5]);
+        let mut map = ButtonMap::default();
+        map.insert_hotkey(keycode::X, Modifiers::CTRL, Hotkey::Pause);
+        rustboy.set_button_map(map);
+
+        // The reset combo is returned, and isn't a button press.
+        assert_eq!(rustboy.handle_key(keycode::R, Modifiers::SHIFT, true), Some((Hotkey::Reset, true)));
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000) & 0xF, 0xF);
+        assert_eq!(rustboy.handle_key(keycode::R, Modifiers::SHIFT, false), Some((Hotkey::Reset, false)));
+
+        // Fast-forward is held with Tab. The release is reported even if Shift was pressed in the meantime.
+        assert_eq!(rustboy.handle_key(keycode::TAB, Modifiers::empty(), true), Some((Hotkey::FastForward, true)));
+        assert_eq!(rustboy.handle_key(keycode::TAB, Modifiers::empty(), true), Some((Hotkey::FastForward, true)));
+        assert_eq!(rustboy.handle_key(keycode::TAB, Modifiers::SHIFT, false), Some((Hotkey::FastForward, false)));
+        assert_eq!(rustboy.handle_key(keycode::TAB, Modifiers::empty(), false), None);
+
+        // Game keys without modifiers press buttons.
+        assert_eq!(rustboy.handle_key(keycode::X, Modifiers::empty(), true), None);
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000) & 0xF, 0xE);
+        // Released, even with a modifier held.
+        assert_eq!(rustboy.handle_key(keycode::X, Modifiers::CTRL, false), None);
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000) & 0xF, 0xF);
+
+        // Pause is toggled here, once per press.
+        assert_eq!(rustboy.handle_key(keycode::X, Modifiers::CTRL, true), Some((Hotkey::Pause, true)));
+        assert!(!rustboy.run_until_vblank());
+        rustboy.handle_key(keycode::X, Modifiers::CTRL, true);
+        assert!(!rustboy.run_until_vblank());
+        assert_eq!(rustboy.handle_key(keycode::X, Modifiers::CTRL, false), Some((Hotkey::Pause, false)));
+        rustboy.handle_key(keycode::X, Modifiers::CTRL, true);
+        assert!(rustboy.run_until_vblank());
+    }
+
+    #[test]
+    fn button_wakes_stop() {
+        // Select the buttons, STOP, then store 0x42 at 0xC000.
+        let mut rustboy = rustboy(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let states = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let record = states.clone();
+        rustboy.set_power_state_callback(Box::new(move |state| record.borrow_mut().push(state)));
+
+        for _ in 0..3 {
+            rustboy.run_until_vblank();
+        }
+        assert_eq!(rustboy.read_byte(0xC000), 0);
+        assert_eq!(*states.borrow(), [PowerState::Stopped]);
+
+        // A direction isn't selected, so it doesn't wake the CPU.
+        rustboy.set_button(Button::Down, true);
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000), 0);
+
+        rustboy.set_button(Button::Start, true);
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000), 0x42);
+        assert_eq!(*states.borrow(), [PowerState::Stopped, PowerState::Running]);
+    }
+
+    #[test]
+    fn run_to_serial_output() {
+        // Send each character, waiting for the transfer to finish.
+        let mut code = Vec::new();
+        for &c in b"Passed" {
+            code.extend_from_slice(&[0x3E, c, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA]);
+        }
+        code.extend_from_slice(&[0x18, 0xFE]);
+        let mut rustboy = rustboy(&code).fast_boot();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        rustboy.connect_serial(Box::new(CaptureSerialPort::new(output.clone())));
+
+        assert_eq!(rustboy.run_to_serial_output(10), "Passed");
+        assert!(output.borrow().is_empty());
+
+        // The original port is connected again.
+        rustboy.write_byte(0xFF01, b'!');
+        rustboy.write_byte(0xFF02, 0x81);
+        rustboy.run_until_vblank();
+        assert_eq!(*output.borrow(), b"!");
+    }
+
+    #[test]
+    fn initial_buttons() {
+        // Select the buttons, and store the first joypad read at 0xC000.
+        let mut rustboy = rustboy(&[0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        rustboy.set_initial_buttons(ButtonState::new(&[Button::A, Button::Down]));
+        rustboy.run_until_vblank();
+
+        assert_eq!(rustboy.read_byte(0xC000), 0xDE);
+        assert_eq!(rustboy.read_byte(0xFF0F) & 0x10, 0);    // No joypad interrupt
+    }
+
+    #[test]
+    fn samples_per_frame() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        assert_eq!(rustboy.enable_audio(44100).unwrap().samples_per_frame(), 735);
+        assert_eq!(rustboy.enable_audio(48000).unwrap().samples_per_frame(), 800);
+    }
+
+    #[test]
+    fn audio_latency() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]).fast_boot();
+        let mut audio = rustboy.enable_audio(44100).unwrap();
+        let packet_samples = audio.samples_per_frame();
+        let mut packet = vec![0.0; packet_samples * 2];
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+
+        // Producing frames without taking packets queues up audio.
+        let mut latency = vec![audio.latency_samples()];
+        for _ in 0..4 {
+            rustboy.frame(&mut frame);
+            latency.push(audio.latency_samples());
+        }
+        for pair in latency.windows(2) {
+            assert!(pair[1] > pair[0], "{:?}", latency);
+        }
+        assert!(latency[4] >= latency[0] + packet_samples * 3, "{:?}", latency);
+
+        // Taking packets drains it again.
+        for _ in 0..3 {
+            audio.get_audio_packet(&mut packet);
+        }
+        assert!(audio.latency_samples() < latency[4] - packet_samples * 2);
+    }
+
+    #[test]
+    fn invalid_sample_rate() {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        assert!(matches!(rustboy.enable_audio(0), Err(RustBoyError::InvalidSampleRate(0))));
+        assert!(matches!(rustboy.enable_audio(MAX_SAMPLE_RATE + 1), Err(RustBoyError::InvalidSampleRate(_))));
+        assert!(rustboy.enable_audio(MAX_SAMPLE_RATE).is_ok());
+    }
+
+    #[test]
+    fn frame_interval() {
+        let rate = 1.0 / rustboy(&[0x18, 0xFE]).recommended_frame_interval().as_secs_f64();
+        assert!((rate - 59.73).abs() < 0.01);
+    }
+
+    #[test]
+    fn perf_callback() {
+        let mut rustboy = rustboy(&[0x00, 0x18, 0xFD]).fast_boot();
+        let timings = Rc::new(RefCell::new(Vec::new()));
+        let callback_timings = timings.clone();
+        rustboy.set_perf_callback(Box::new(move |timing| callback_timings.borrow_mut().push(timing)));
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        for _ in 0..5 {
+            rustboy.frame(&mut frame);
+        }
+
+        // Each frame ends at the first instruction after V-blank starts.
+        let timings = timings.borrow();
+        assert_eq!(timings.len(), 5);
+        for timing in timings.iter().skip(1) {
+            assert!((timing.cycles as i64 - FRAME_CYCLES as i64).abs() <= 12, "{} cycles", timing.cycles);
+            assert!(timing.instructions > 0);
+            assert_eq!(timing.target_time, rustboy.recommended_frame_interval());
+        }
+        let total: u64 = timings.iter().skip(1).map(|timing| timing.cycles).sum();
+        assert!((total as i64 - 4 * FRAME_CYCLES as i64).abs() <= 12);
+    }
+
+    #[test]
+    fn cheats() {
+        let mut rom = testutil::rom(&[0x18, 0xFE]);
+        rom[0x4A17] = 0xC8;
+        rom[0x4A18] = 0xC8;
+        let mut rustboy = RustBoy::new_in_memory(rom, None, UserPalette::Greyscale).fast_boot();
+
+        // The Game Genie patch only applies if the ROM matches the compare value.
+        let genie = rustboy.add_cheat("00A-17B-C49").unwrap();
+        assert_eq!(rustboy.read_byte(0x4A17), 0x00);
+        assert_eq!(rustboy.read_byte(0x4A18), 0xC8);
+        rustboy.add_cheat("00A-18B-D49").unwrap();
+        assert_eq!(rustboy.read_byte(0x4A18), 0xC8);
+
+        // The GameShark code writes to RAM every frame.
+        let shark = rustboy.add_cheat("014200C0").unwrap();
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.frame(&mut frame);
+        assert_eq!(rustboy.read_byte(0xC000), 0x42);
+        rustboy.write_byte(0xC000, 0x00);
+        rustboy.frame(&mut frame);
+        assert_eq!(rustboy.read_byte(0xC000), 0x42);
+
+        rustboy.remove_cheat(genie);
+        assert_eq!(rustboy.read_byte(0x4A17), 0xC8);
+        rustboy.remove_cheat(shark);
+        rustboy.write_byte(0xC000, 0x00);
+        rustboy.frame(&mut frame);
+        assert_eq!(rustboy.read_byte(0xC000), 0x00);
+
+        rustboy.add_cheat("00A-17B").unwrap();
+        assert_eq!(rustboy.read_byte(0x4A17), 0x00);
+        rustboy.clear_cheats();
+        assert_eq!(rustboy.read_byte(0x4A17), 0xC8);
+        assert!(rustboy.add_cheat("not a cheat").is_err());
+    }
+
+    #[test]
+    fn deterministic() {
+        // Copy the divider to the background palette, forever.
+        let code = [0xF0, 0x04, 0xE0, 0x47, 0x18, 0xFA];
+        let new = || RustBoy::new_in_memory(testutil::rom(&code), None, UserPalette::Greyscale).fast_boot().deterministic();
+        let mut rustboys = [new(), new()];
+        assert!(matches!(rustboys[0].enable_audio(44100), Err(RustBoyError::AudioDisabled)));
+        rustboys[0].set_perf_callback(Box::new(|timing| assert_eq!(timing.emulation_time, Duration::default())));
+
+        let mut frames = [vec![0; FRAME_SIZE_BYTES], vec![0; FRAME_SIZE_BYTES]];
+        let mut changed = false;
+        for i in 0..120 {
+            let last_frame = frames[0].clone();
+            for (rustboy, frame) in rustboys.iter_mut().zip(frames.iter_mut()) {
+                rustboy.set_button(Button::A, (i % 3) == 0);
+                rustboy.frame(frame);
+            }
+            assert!(frames[0] == frames[1], "frame {} differs", i);
+            changed = changed || (frames[0] != last_frame);
+        }
+        assert!(changed);
+    }
+
+    #[test]
+    fn lockstep() {
+        // Copy the buttons to RAM, forever.
+        let code = [0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF5];
+        let new = || RustBoy::new_in_memory(testutil::rom(&code), None, UserPalette::Greyscale).deterministic();
+        let mut rustboys = [new(), new(), new(), new()];
+        let a = ButtonState::new(&[Button::A]);
+        let start = ButtonState::new(&[Button::Start]);
+
+        for _ in 0..3 {
+            let hashes = [
+                rustboys[0].advance_lockstep(a, start, 0),
+                rustboys[1].advance_lockstep(start, a, 1),
+                rustboys[2].advance_lockstep(a, ButtonState::default(), 0),
+                // Both peers claim to be player 0.
+                rustboys[3].advance_lockstep(start, a, 0),
+            ];
+            assert_eq!(hashes[0], hashes[1]);
+            assert_ne!(hashes[0], hashes[2]);
+            assert_ne!(hashes[0], hashes[3]);
+        }
+    }
+
+    #[test]
+    fn gbs() {
+        let mut data = vec![0; 0x70];
+        data[0..16].copy_from_slice(&[b'G', b'B', b'S', 1, 2, 1, 0x00, 0x04, 0x00, 0x04, 0x10, 0x04, 0xFE, 0xFF, 0x00, 0x00]);
+        // Init: store the track at 0xC000 and start square 1.
+        data.extend_from_slice(&[0xEA, 0x00, 0xC0, 0x3E, 0xF0, 0xE0, 0x12, 0x3E, 0x87, 0xE0, 0x14, 0xC9, 0, 0, 0, 0]);
+        // Play: count calls at 0xC001.
+        data.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+
+        let mut rustboy = RustBoy::new_gbs(&data).unwrap();
+        for _ in 0..10 {
+            rustboy.run_until_vblank();
+        }
+        assert_eq!(rustboy.read_byte(0xC000), 0);
+        assert!((9..=10).contains(&rustboy.read_byte(0xC001)));
+        assert!(rustboy.scope_buffers()[0].iter().any(|&sample| sample != 0));
+
+        rustboy.next_track().unwrap();
+        rustboy.run_until_vblank();
+        assert_eq!(rustboy.read_byte(0xC000), 1);
+        assert!(rustboy.play_track(2).is_err());
+    }
+
+    #[test]
+    fn paused() {
+        // Play a tone, and copy the divider to the background palette forever.
+        let code = [0x3E, 0xF0, 0xE0, 0x12, 0x3E, 0x87, 0xE0, 0x14, 0xF0, 0x04, 0xE0, 0x47, 0x18, 0xFA];
+        let mut rustboy = RustBoy::new_in_memory(testutil::rom(&code), None, UserPalette::Greyscale).fast_boot();
+        let mut audio = rustboy.enable_audio(44100).unwrap();
+        audio.set_highpass(false);
+        let mut packet = vec![0.0; audio.samples_per_frame() * 2];
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        for _ in 0..10 {
+            rustboy.frame(&mut frame);
+        }
+        audio.get_audio_packet(&mut packet);
+        assert!(packet.iter().any(|&sample| sample.abs() > 0.01));
+
+        rustboy.set_paused(true);
+        let paused_frame = frame.clone();
+        for _ in 0..30 {
+            rustboy.frame(&mut frame);
+            assert_eq!(frame, paused_frame);
+        }
+
+        // The audio fades out, and stays silent.
+        for _ in 0..35 {
+            audio.get_audio_packet(&mut packet);
+        }
+        assert!(packet.iter().all(|&sample| sample.abs() < 0.001));
+    }
+
+    // Play a tone for a few frames with the master volume set, and return the first packet.
+    fn tone_packet(gain: f32) -> Vec<f32> {
+        let code = [0x3E, 0xF0, 0xE0, 0x12, 0x3E, 0x87, 0xE0, 0x14, 0x18, 0xFE];
+        let mut rustboy = RustBoy::new_in_memory(testutil::rom(&code), None, UserPalette::Greyscale).fast_boot();
+        let mut audio = rustboy.enable_audio(44100).unwrap();
+        audio.set_highpass(false);
+        audio.set_master_volume(gain);
+        let mut packet = vec![0.0; audio.samples_per_frame() * 2];
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        for _ in 0..10 {
+            rustboy.frame(&mut frame);
+        }
+        audio.get_audio_packet(&mut packet);
+        packet
+    }
+
+    #[test]
+    fn master_volume() {
+        let full = tone_packet(1.0);
+        assert!(full.iter().any(|&sample| sample.abs() > 0.01));
+
+        let half = tone_packet(0.5);
+        for (&h, &f) in half.iter().zip(full.iter()) {
+            assert!((h - f * 0.5).abs() < 1e-6, "{} is not half of {}", h, f);
+        }
+
+        assert!(tone_packet(0.0).iter().all(|&sample| sample == 0.0));
+
+        // Out of range gains are clamped.
+        assert_eq!(tone_packet(2.0), full);
+        assert!(tone_packet(-1.0).iter().all(|&sample| sample == 0.0));
+        assert!(tone_packet(f32::NAN).iter().all(|&sample| sample == 0.0));
+    }
+
+    // Draw a background of 4 pixel wide stripes scrolled from SCX 0 to the new scroll, and return the last frame.
+    fn scrolled_frame(smooth_scroll: bool, scroll_x: u8) -> Vec<u8> {
+        let mut rustboy = rustboy(&[0x18, 0xFE]);
+        rustboy.set_smooth_scroll(smooth_scroll);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0xF0);
+        }
+        for addr in 0x9800..0x9C00 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        rustboy.write_byte(0xFF47, 0xE4);
+        rustboy.write_byte(0xFF43, 0);
+        rustboy.write_byte(0xFF40, 0x91);
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        rustboy.run_frames(3, &mut frame);
+        rustboy.write_byte(0xFF43, scroll_x);
+        rustboy.frame(&mut frame);
+        frame
+    }
+
+    #[test]
+    fn smooth_scroll() {
+        assert_ne!(scrolled_frame(false, 1), scrolled_frame(false, 2));
+        assert_eq!(scrolled_frame(true, 2), scrolled_frame(false, 1));
+        // Jumps aren't smoothed.
+        assert_eq!(scrolled_frame(true, 20), scrolled_frame(false, 20));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn current_instruction() {
+        let mut rustboy = rustboy(&[0x3E, 0x42, 0x18, 0xFE]);
+        rustboy.step();
+        assert_eq!(rustboy.current_instruction(), (testutil::CODE_START, "LD A,$42".to_string()));
+        rustboy.step();
+        assert_eq!(rustboy.current_instruction(), (testutil::CODE_START + 2, "JR -2".to_string()));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn redirect_execution() {
+        let routine = testutil::CODE_START + 4;
+        let mut rustboy = rustboy(&[0x3E, 0x01, 0x18, 0xFE, 0x3E, 0x42, 0xC9]);
+        rustboy.set_pc(routine);
+        rustboy.step();
+        assert_eq!(rustboy.get_state().a, 0x42);
+        assert_eq!(rustboy.get_state().pc, routine + 2);
+
+        rustboy.set_pc(testutil::CODE_START);
+        rustboy.set_sp(0xD000);
+        rustboy.jump_to(routine);
+        assert_eq!(rustboy.get_state().sp, 0xCFFE);
+        rustboy.step();
+        rustboy.step();
+        assert_eq!(rustboy.get_state().pc, testutil::CODE_START);
+        assert_eq!(rustboy.get_state().sp, 0xD000);
+    }
+
+    // Show a black screen, then switch the LCD off and on at LY 50.
+    // Returns the STAT mode after, and the top and bottom pixels of the next two frames.
+    fn lcd_on_frames(quirk: bool) -> (u8, Vec<Vec<u8>>) {
+        let mut rustboy = rustboy(&[0x18, 0xFE]).lcd_on_quirk(quirk);
+        rustboy.write_byte(0xFF40, 0x00);
+        for addr in 0x8000..0x8010 {
+            rustboy.write_byte(addr, 0xFF);
+        }
+        for addr in 0x9800..0x9C00 {
+            rustboy.write_byte(addr, 0x00);
+        }
+        rustboy.write_byte(0xFF47, 0xE4);
+        rustboy.write_byte(0xFF40, 0x91);
+        assert_eq!(top_left_pixel(&mut rustboy), [0, 0, 0, 255]);
+
+        while rustboy.read_byte(0xFF44) != 50 {
+            rustboy.cpu.step();
+        }
+        rustboy.write_byte(0xFF40, 0x11);
+        rustboy.write_byte(0xFF40, 0x91);
+        assert_eq!(rustboy.read_byte(0xFF44), 0);
+        let mode = rustboy.read_byte(0xFF41) & 0x3;
+
+        let mut frame = vec![0; FRAME_SIZE_BYTES];
+        let mut pixels = Vec::new();
+        for _ in 0..2 {
+            rustboy.run_until_vblank();
+            rustboy.peek_frame(&mut frame);
+            pixels.push(frame[0..4].to_vec());
+            pixels.push(frame[(FRAME_SIZE_BYTES - 4)..].to_vec());
+        }
+        (mode, pixels)
+    }
+
+    #[test]
+    fn lcd_on_quirk() {
+        let white: &[u8] = &[255, 255, 255, 255];
+        let black: &[u8] = &[0, 0, 0, 255];
+
+        // The first frame after switching on is blank.
+        let (mode, pixels) = lcd_on_frames(true);
+        assert_eq!(mode, 0);
+        assert_eq!(pixels, [white, white, black, black]);
+
+        let (mode, pixels) = lcd_on_frames(false);
+        assert_eq!(mode, 2);
+        assert_eq!(pixels, [black, black, black, black]);
+    }
+
+    #[test]
+    fn in_memory_save() {
+        // MBC1+RAM+BATTERY, 8KB RAM.
+        let rom = testutil::cart_rom(0x03, 4, 0x02, &[0x18, 0xFE]);
+        let mut rustboy = RustBoy::new_in_memory(rom.clone(), None, UserPalette::Greyscale);
+        rustboy.write_byte(0x0000, 0x0A);
+        rustboy.write_byte(0xA000, 0x12);
+        rustboy.write_byte(0xBFFF, 0x34);
+        assert_eq!(rustboy.read_byte(0xA000), 0x12);
+
+        let save = rustboy.save_data().unwrap();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!((save[0], save[0x1FFF]), (0x12, 0x34));
+
+        // The exported data loads back in.
+        let mut rustboy = RustBoy::new_in_memory(rom, Some(save), UserPalette::Greyscale);
+        rustboy.write_byte(0x0000, 0x0A);
+        assert_eq!(rustboy.read_byte(0xA000), 0x12);
+        assert_eq!(rustboy.read_byte(0xBFFF), 0x34);
+    }
+}
@@ -5,23 +5,59 @@ use crossbeam_channel::Sender;
 use crate::{
     video::{
         sgbpalettes::*,
+        ColourCorrection,
         VideoDevice
     },
     audio::{
         AudioDevice,
+        AudioEvent,
         SamplePacket
     },
     timer::Timer,
     joypad::*,
-    interrupt::InterruptFlags
+    interrupt::InterruptFlags,
+    cheats::{Cheat, CheatId, RamPoke}
 };
 
-use std::sync::{
-    Arc, Mutex
+use std::{
+    hash::Hasher,
+    ops::RangeInclusive,
+    sync::{
+        Arc, Mutex
+    }
 };
 
-use super::cartridge::{Cartridge, ROMType};
-use super::{MemDevice, WriteableMem};
+use super::cartridge::{BankState, CartFeatureSet, Cartridge, DisabledRamRead, ROMType, SaveType};
+use super::{IoHandler, IrTransceiver, MemDevice, NullSerialPort, SerialPort, WriteableMem};
+
+// IO register values left by the boot ROM.
+// Channel 1 is left playing the end of the boot sound, but isn't triggered here.
+// The APU is powered on (NR52) first, as the other audio registers can't be written while it is off.
+const POST_BOOT_IO: [(u16, u8); 25] = [
+    (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8), (0xFF0F, 0xE1),
+    (0xFF26, 0xF1), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3),
+    (0xFF14, 0x3F), (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF19, 0x3F),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1E, 0x3F),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0x3F),
+    (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF40, 0x91), (0xFF47, 0xFC),
+    (0xFF4A, 0x00),
+];
+const POST_BOOT_DIV: u16 = 0xABCC;
+
+// Machine cycles per serial bit: 8192Hz, or 262144Hz with the CGB fast clock.
+// The serial clock follows the CPU clock, so this is the same in double speed mode.
+const SERIAL_BIT_CYCLES: u32 = 128;
+const SERIAL_FAST_BIT_CYCLES: u32 = 4;
+
+// IO registers used by the emulated hardware. Custom IO handlers can't be mapped over these.
+const RESERVED_IO: [RangeInclusive<u16>; 6] = [
+    0xFF00..=0xFF0F,    // Joypad, serial, timer and interrupts
+    0xFF10..=0xFF3F,    // Audio
+    0xFF40..=0xFF56,    // Video, OAM DMA, CGB speed switch, VRAM bank, HDMA and IR
+    0xFF68..=0xFF6B,    // CGB palettes
+    0xFF70..=0xFF70,    // CGB WRAM bank
+    0xFF72..=0xFF77,    // Undocumented CGB registers
+];
 
 pub struct MemBus {
     cart:               Cartridge,
@@ -40,6 +76,10 @@ pub struct MemBus {
     // DMA
     dma_addr:           u16,
     dma_active:         bool,
+    dma_reg:            u8,     // Last value written to 0xFF46.
+    dma_delay:          u8,     // Machine cycles until a requested transfer starts.
+    dma_byte:           u8,     // Last byte copied.
+    dma_bus_conflicts:  bool,   // Restrict CPU reads during OAM DMA.
 
     // CGB
     cgb_ram_offset:     u16,
@@ -47,17 +87,32 @@ pub struct MemBus {
     cgb_dma_dst:        u16,
     cgb_dma_len:        u16,
     cgb_dma_hblank_len: Option<u16>,
+    ir_control:         u8,
+    ir_transceiver:     Option<Box<dyn IrTransceiver>>,
+    serial_data:        u8,
+    serial_control:     u8,
+    serial_cycles:      u32,
+    serial_port:        Box<dyn SerialPort>,
+    cgb_scratch:        [u8; 4],    // Undocumented registers 0xFF72-0xFF75.
+
+    cgb_mode:           bool,
+
+    // Custom IO
+    io_handlers:        Vec<(RangeInclusive<u16>, Box<dyn IoHandler>)>,
 
-    cgb_mode:           bool
+    // GameShark cheats, applied at the start of each frame.
+    ram_pokes:          Vec<(CheatId, RamPoke)>,
 }
 
 impl MemBus {
-    pub fn new(rom: ROMType, save_file: &str, user_palette: UserPalette) -> MemBus {
-        let cart = match Cartridge::new(rom, save_file) {
+    pub fn new(rom: ROMType, save_type: SaveType, user_palette: UserPalette) -> MemBus {
+        let cart = match Cartridge::new(rom, save_type) {
             Ok(r) => r,
             Err(s) => panic!("Could not construct ROM: {}", s),
         };
 
+        // DMG games use the static palettes, with separate object palettes.
+        // With the default palette these come from the CGB compatibility lookup.
         let palette = match user_palette {
             UserPalette::Default => if let Some(cart_hash) = cart.cart_name_hash() {
                 lookup_sgb_palette(cart_hash.0, cart_hash.1)
@@ -68,6 +123,7 @@ impl MemBus {
             UserPalette::Classic => CLASSIC_PALETTE
         };
 
+        // The effective model: this decides which renderer and palettes are used, not just the cart flag.
         let cgb_mode = (user_palette == UserPalette::Default) && cart.cgb_cart();
 
         MemBus {
@@ -80,19 +136,34 @@ impl MemBus {
             interrupt_enable:   InterruptFlags::default(),
 
             video_device:       VideoDevice::new(palette, cgb_mode),
-            audio_device:       AudioDevice::new(),
+            audio_device:       AudioDevice::new(cgb_mode),
             timer:              Timer::new(),
             joypad:             Joypad::new(),
 
             dma_addr:           0,
             dma_active:         false,
+            dma_reg:            0,
+            dma_delay:          0,
+            dma_byte:           0xFF,
+            dma_bus_conflicts:  true,
 
             cgb_ram_offset:     0x1000,
             cgb_dma_src:        0x0FF0,
             cgb_dma_dst:        0x8FF0,
             cgb_dma_len:        0,
             cgb_dma_hblank_len: None,
-            cgb_mode:           cgb_mode
+            ir_control:         0,
+            ir_transceiver:     None,
+            serial_data:        0,
+            serial_control:     0,
+            serial_cycles:      0,
+            serial_port:        Box::new(NullSerialPort),
+            cgb_scratch:        [0; 4],
+            cgb_mode:           cgb_mode,
+
+            io_handlers:        Vec::new(),
+
+            ram_pokes:          Vec::new(),
         }
     }
 
@@ -102,26 +173,89 @@ impl MemBus {
         if self.joypad.check_interrupt() {
             self.interrupt_flag.insert(InterruptFlags::JOYPAD);
         }
+
+        for i in 0..self.ram_pokes.len() {
+            let (_, poke) = self.ram_pokes[i];
+            self.write(poke.addr, poke.val);
+        }
+    }
+
+    pub fn add_cheat(&mut self, id: CheatId, cheat: Cheat) {
+        match cheat {
+            Cheat::GameGenie(patch) => self.cart.add_rom_patch(id, patch),
+            Cheat::GameShark(poke) => self.ram_pokes.push((id, poke)),
+        }
+    }
+
+    pub fn remove_cheat(&mut self, id: CheatId) {
+        self.cart.remove_rom_patch(id);
+        self.ram_pokes.retain(|(poke_id, _)| *poke_id != id);
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cart.clear_rom_patches();
+        self.ram_pokes.clear();
+    }
+
+    // Output a frame of silent audio, without updating anything else.
+    pub fn paused_frame(&mut self, cycles: u32) {
+        self.audio_device.clock_silence(cycles);
     }
 
     pub fn enable_audio(&mut self, sender: Sender<SamplePacket>) {
         self.audio_device.enable_audio(sender);
     }
 
+    pub fn enable_audio_null(&mut self) {
+        self.audio_device.enable_audio_null();
+    }
+
+    pub fn drain_audio_events(&mut self) -> Vec<AudioEvent> {
+        self.audio_device.drain_events()
+    }
+
+    pub fn scope_buffers(&self) -> [Vec<i8>; 4] {
+        self.audio_device.scope_buffers()
+    }
+
+    pub fn get_wave_ram(&self) -> [u8; 16] {
+        self.audio_device.get_wave_ram()
+    }
+
+    pub fn set_wave_ram(&mut self, data: [u8; 16]) {
+        self.audio_device.set_wave_ram(data);
+    }
+
     // Clock memory: update timer and DMA transfers.
     // Return true if CGB DMA is active.
     pub fn clock(&mut self, cycles: u32) -> bool {
         self.audio_device.clock(cycles);
 
-        if self.timer.update(cycles) {
+        // The timer is clocked by the CPU, so it runs twice as fast in double speed mode.
+        if self.timer.update(4) {
             self.interrupt_flag.insert(InterruptFlags::TIMER);
         }
+        if self.serial_cycles > 0 {
+            self.serial_tick();
+        }
+        // OAM DMA is driven by the CPU clock: one byte per machine cycle at either speed.
+        // So it takes 160 machine cycles, which is half the real time in double speed mode.
+        if self.dma_delay > 0 {
+            self.dma_delay -= 1;
+            if self.dma_delay == 0 {
+                self.dma_addr = make_16!(self.dma_reg, 0);
+                self.dma_active = true;
+            }
+        }
         if self.dma_active {
             self.dma_tick();
         }
+        // CGB DMA runs at a fixed rate: 2 bytes per machine cycle in single speed, 1 in double speed.
+        // So H-blank DMA still transfers 16 bytes per H-blank at either speed.
         if self.cgb_dma_len > 0 {
+            let single_speed = cycles == 4;
             self.cgb_dma_tick();
-            if cycles == 4 && self.cgb_dma_len > 0 {    // In single speed mode, transfer 2 bytes per instruction.
+            if single_speed && self.cgb_dma_len > 0 {
                 self.cgb_dma_tick();
             }
 
@@ -140,6 +274,34 @@ impl MemBus {
         ret
     }
 
+    pub fn dump_background(&self) -> Vec<u8> {
+        self.video_device.dump_background()
+    }
+
+    pub fn dump_tiles(&self, bank: u8) -> Vec<u8> {
+        self.video_device.dump_tiles(bank)
+    }
+
+    pub fn dump_tilemap(&self, bank: u8) -> Vec<u8> {
+        self.video_device.dump_tilemap(bank)
+    }
+
+    pub fn set_smooth_scroll(&mut self, enabled: bool) {
+        self.video_device.set_smooth_scroll(enabled);
+    }
+
+    pub fn sprite_overflow_lines(&self) -> Vec<u8> {
+        self.video_device.sprite_overflow_lines()
+    }
+
+    pub fn set_sprite_debug(&mut self, enabled: bool) {
+        self.video_device.set_sprite_debug(enabled);
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.video_device.set_colour_correction(correction);
+    }
+
     // Gets any interrupts that have been triggered and are enabled.
     pub fn get_interrupts(&self) -> InterruptFlags {
         self.interrupt_flag & self.interrupt_enable
@@ -158,6 +320,15 @@ impl MemBus {
         self.joypad.set_direction(direction, val);
     }
 
+    // True if any input in the selected groups is held.
+    pub fn joypad_pressed(&self) -> bool {
+        (self.joypad.read() & 0xF) != 0xF
+    }
+
+    pub fn set_initial_input(&mut self, buttons: Buttons, directions: Directions) {
+        self.joypad.set_initial(buttons, directions);
+    }
+
     // Flush the battery-backed RAM to disk.
     pub fn flush_cart(&mut self) {
         self.cart.flush_ram();
@@ -167,6 +338,119 @@ impl MemBus {
         self.cart.name()
     }
 
+    pub fn cart_title_bytes(&self) -> Vec<u8> {
+        self.cart.title_bytes()
+    }
+
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.cart.save_data()
+    }
+
+    pub fn current_banks(&self) -> BankState {
+        self.cart.current_banks()
+    }
+
+    pub fn take_rumble_state(&mut self) -> bool {
+        self.cart.take_rumble_state()
+    }
+
+    pub fn cart_features(&self) -> CartFeatureSet {
+        self.cart.features()
+    }
+
+    // Zero work RAM and high RAM, fix the cart clock, and draw on the emulation thread.
+    pub fn set_deterministic(&mut self) {
+        self.ram = WriteableMem::new(0x8000);
+        self.high_ram = WriteableMem::new(0x7F);
+        self.cart.set_fixed_clock();
+        self.video_device.disable_render_thread();
+    }
+
+    pub fn set_dma_bus_conflicts(&mut self, enabled: bool) {
+        self.dma_bus_conflicts = enabled;
+    }
+
+    // Read memory from the CPU. While OAM DMA is copying, the CPU can only read IO and HRAM:
+    // other reads see the byte being transferred, and OAM reads 0xFF.
+    pub fn cpu_read(&self, loc: u16) -> u8 {
+        match loc {
+            0x0000..=0xFDFF if self.dma_bus_conflicts && self.dma_active => self.dma_byte,
+            0xFE00..=0xFEFF if self.dma_active => 0xFF,
+            _ => self.read(loc),
+        }
+    }
+
+    pub fn set_lcd_on_quirk(&mut self, enabled: bool) {
+        self.video_device.set_lcd_on_quirk(enabled);
+    }
+
+    pub fn set_mbc1_multicart(&mut self, multicart: bool) {
+        self.cart.set_mbc1_multicart(multicart);
+    }
+
+    pub fn set_disabled_ram_read(&mut self, policy: DisabledRamRead) {
+        self.cart.set_disabled_ram_read(policy);
+    }
+
+    // Set up IO registers as the boot ROM leaves them. VRAM and OAM are already clear.
+    // On CGB, all colour palettes are white.
+    pub fn fast_boot(&mut self) {
+        // Palettes are written first, before the LCD is drawing.
+        if self.cgb_mode {
+            self.write(0xFF68, 0x80);
+            self.write(0xFF6A, 0x80);
+            for _ in 0..32 {
+                for val in [0xFF, 0x7F].iter() {
+                    self.write(0xFF69, *val);
+                    self.write(0xFF6B, *val);
+                }
+            }
+        } else {
+            self.write(0xFF48, 0xFF);
+            self.write(0xFF49, 0xFF);
+        }
+
+        for (loc, val) in POST_BOOT_IO.iter() {
+            self.write(*loc, *val);
+        }
+        self.timer.set_divider(POST_BOOT_DIV);
+    }
+
+    // Hash the contents of RAM, for detecting when two emulators have diverged.
+    pub fn hash_state<H: Hasher>(&self, state: &mut H) {
+        state.write(&self.ram.mem);
+        state.write(&self.high_ram.mem);
+        if let Some(save_data) = self.save_data() {
+            state.write(&save_data);
+        }
+        state.write(&[self.interrupt_flag.bits(), self.interrupt_enable.bits()]);
+    }
+
+    // Map a custom handler into unused IO space.
+    pub fn register_io_handler(&mut self, range: RangeInclusive<u16>, handler: Box<dyn IoHandler>) -> Result<(), String> {
+        let overlaps = |other: &RangeInclusive<u16>| (range.start() <= other.end()) && (other.start() <= range.end());
+
+        if range.is_empty() || (*range.start() < 0xFF00) || (*range.end() > 0xFF7F) {
+            Err(format!("IO handler range {:04X}-{:04X} is outside of IO space", range.start(), range.end()))
+        } else if RESERVED_IO.iter().any(overlaps) {
+            Err(format!("IO handler range {:04X}-{:04X} overlaps hardware registers", range.start(), range.end()))
+        } else if self.io_handlers.iter().any(|(r, _)| overlaps(r)) {
+            Err(format!("IO handler range {:04X}-{:04X} overlaps another handler", range.start(), range.end()))
+        } else {
+            self.io_handlers.push((range, handler));
+            Ok(())
+        }
+    }
+
+    pub fn set_ir_transceiver(&mut self, transceiver: Box<dyn IrTransceiver>) {
+        self.ir_transceiver = Some(transceiver);
+    }
+
+    // Returns the port that was connected before.
+    pub fn connect_serial(&mut self, port: Box<dyn SerialPort>) -> Box<dyn SerialPort> {
+        std::mem::replace(&mut self.serial_port, port)
+    }
+
     // See if the memory is in CGB mode.
     pub fn is_cgb(&self) -> bool {
         self.cgb_mode
@@ -176,14 +460,17 @@ impl MemBus {
 // Internal functions
 impl MemBus {
     // Direct memory access for object memory.
+    // The first byte is copied 2 machine cycles after the write: the cycle after it is spent setting up.
+    // A transfer already running continues until then.
     fn start_dma(&mut self, val: u8) {
-        self.dma_addr = make_16!(val, 0);
-        self.dma_active = true;
+        self.dma_reg = val;
+        self.dma_delay = 2;
     }
 
     fn dma_tick(&mut self) {
         let dest_addr = make_16!(0xFE, lo_16!(self.dma_addr));
         let byte = self.read(self.dma_addr);
+        self.dma_byte = byte;
         self.video_device.write(dest_addr, byte);
         self.dma_addr += 1;
 
@@ -264,9 +551,90 @@ impl MemBus {
     fn get_cgb_ram_bank(&self) -> u8 {
         (self.cgb_ram_offset / 0x1000) as u8
     }
+
+    // Infrared port. Bit 1 is low when light is received, if reading is enabled.
+    fn read_ir(&self) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+
+        let reading = (self.ir_control & 0xC0) == 0xC0;
+        let receiving = reading && self.ir_transceiver.as_ref().is_some_and(|t| t.receiving());
+        self.ir_control | 0x3C | if receiving {0} else {bit!(1)}
+    }
+
+    fn write_ir(&mut self, val: u8) {
+        if self.cgb_mode {
+            self.ir_control = val & 0xC1;
+            if let Some(transceiver) = &mut self.ir_transceiver {
+                transceiver.set_light(test_bit!(val, 0));
+            }
+        }
+    }
+
+    // Serial control. Unused bits read high.
+    fn read_serial_control(&self) -> u8 {
+        let unused = if self.cgb_mode {0x7C} else {0x7E};
+        self.serial_control | unused
+    }
+
+    // A transfer starts when bit 7 is set. Only the internal clock is emulated:
+    // with the external clock selected, the transfer waits forever.
+    fn write_serial_control(&mut self, val: u8) {
+        self.serial_control = val & if self.cgb_mode {0x83} else {0x81};
+
+        let internal_clock = test_bit!(val, 0);
+        self.serial_cycles = if test_bit!(val, 7) && internal_clock {
+            let bit_cycles = if self.cgb_mode && test_bit!(val, 1) {SERIAL_FAST_BIT_CYCLES} else {SERIAL_BIT_CYCLES};
+            8 * bit_cycles
+        } else {
+            0
+        };
+    }
+
+    // Called every machine cycle during a transfer.
+    // After 8 bits, SB holds the received byte, SC bit 7 clears and the interrupt is raised.
+    fn serial_tick(&mut self) {
+        self.serial_cycles -= 1;
+        if self.serial_cycles == 0 {
+            self.serial_port.send_byte(self.serial_data);
+            self.serial_data = self.serial_port.recv_byte().unwrap_or(0xFF);
+            self.serial_control &= !bit!(7);
+            self.interrupt_flag.insert(InterruptFlags::SERIAL);
+        }
+    }
+
+    // Undocumented CGB registers. 0xFF72-0xFF74 are fully readable and writable,
+    // 0xFF75 only has bits 4-6. The channel amplitude registers (0xFF76-0xFF77) aren't emulated and read 0.
+    fn read_cgb_scratch(&self, loc: u16) -> u8 {
+        match loc {
+            0xFF72..=0xFF74 => self.cgb_scratch[(loc - 0xFF72) as usize],
+            0xFF75          => self.cgb_scratch[3] | 0x8F,
+            _               => 0,
+        }
+    }
+
+    fn write_cgb_scratch(&mut self, loc: u16, val: u8) {
+        let index = (loc - 0xFF72) as usize;
+        self.cgb_scratch[index] = if loc == 0xFF75 {val & 0x70} else {val};
+    }
+
+    // Custom IO handlers.
+    fn read_io_handler(&self, loc: u16) -> u8 {
+        self.io_handlers.iter()
+            .find(|(range, _)| range.contains(&loc))
+            .map_or(0xFF, |(_, handler)| handler.read(loc))
+    }
+
+    fn write_io_handler(&mut self, loc: u16, val: u8) {
+        if let Some((_, handler)) = self.io_handlers.iter_mut().find(|(range, _)| range.contains(&loc)) {
+            handler.write(loc, val);
+        }
+    }
 }
 
 impl MemDevice for MemBus {
+    // OAM reads 0xFF during OAM DMA. Other areas aren't restricted here: see cpu_read.
     fn read(&self, loc: u16) -> u8 {
         match loc {
             0x0000..=0x7FFF => self.cart.read(loc),
@@ -274,24 +642,28 @@ impl MemDevice for MemBus {
             0xA000..=0xBFFF => self.cart.read(loc),
             0xC000..=0xCFFF => self.ram.read(loc - 0xC000),
             0xD000..=0xDFFF => self.ram.read((loc - 0xD000) + self.cgb_ram_offset),
+            // Echo RAM mirrors 0xC000-0xDDFF, including the selected CGB bank. OAM starts at 0xFE00.
             0xE000..=0xEFFF => self.ram.read(loc - 0xE000),
             0xF000..=0xFDFF => self.ram.read((loc - 0xF000) + self.cgb_ram_offset),
-            0xFE00..=0xFE9F => self.video_device.read(loc),
+            0xFE00..=0xFEFF => self.video_device.read(loc),
             0xFF00          => self.joypad.read(),
-            0xFF01..=0xFF02 => 0,
+            0xFF01          => self.serial_data,
+            0xFF02          => self.read_serial_control(),
             0xFF03..=0xFF07 => self.timer.read(loc),
             0xFF0F          => self.interrupt_flag.bits(),
             0xFF10..=0xFF3F => self.audio_device.read(loc),
             0xFF40..=0xFF45 => self.video_device.read(loc),
-            0xFF46          => (self.dma_addr >> 8) as u8,
+            0xFF46          => self.dma_reg,
             0xFF47..=0xFF4B => self.video_device.read(loc),
             0xFF4F          => self.video_device.read(loc),
             0xFF55          => self.get_cgb_len(),
+            0xFF56          => self.read_ir(),
             0xFF68..=0xFF6B => self.video_device.read(loc),
             0xFF70          => self.get_cgb_ram_bank(),
+            0xFF72..=0xFF77 if self.cgb_mode => self.read_cgb_scratch(loc),
             0xFF80..=0xFFFE => self.high_ram.read(loc - 0xFF80),
             0xFFFF          => self.interrupt_enable.bits(),
-            _ => 0xFF,
+            _ => self.read_io_handler(loc),
         }
     }
 
@@ -306,6 +678,8 @@ impl MemDevice for MemBus {
             0xF000..=0xFDFF => self.ram.write((loc - 0xF000) + self.cgb_ram_offset, val),
             0xFE00..=0xFE9F => self.video_device.write(loc, val),
             0xFF00          => self.joypad.write(val),
+            0xFF01          => self.serial_data = val,
+            0xFF02          => self.write_serial_control(val),
             0xFF03..=0xFF07 => self.timer.write(loc, val),
             0xFF0F          => self.interrupt_flag = InterruptFlags::from_bits_truncate(val),
             0xFF10..=0xFF3F => self.audio_device.write(loc, val),
@@ -317,11 +691,292 @@ impl MemDevice for MemBus {
             0xFF53          => self.set_cgb_dma_upper_dst(val),
             0xFF54          => self.set_cgb_dma_lower_dst(val),
             0xFF55          => self.start_cgb_dma(val),
+            0xFF56          => self.write_ir(val),
             0xFF68..=0xFF6B => self.video_device.write(loc, val),
             0xFF70          => self.set_cgb_ram_bank(val),
+            0xFF72..=0xFF75 if self.cgb_mode => self.write_cgb_scratch(loc, val),
             0xFF80..=0xFFFE => self.high_ram.write(loc - 0xFF80, val),
             0xFFFF          => self.interrupt_enable = InterruptFlags::from_bits_truncate(val),
-            _ => {},
+            _ => self.write_io_handler(loc, val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // A register that reads back the inverse of the last write.
+    struct Inverter(u8);
+
+    impl IoHandler for Inverter {
+        fn read(&self, _loc: u16) -> u8 {
+            !self.0
+        }
+
+        fn write(&mut self, _loc: u16, val: u8) {
+            self.0 = val;
+        }
+    }
+
+    #[test]
+    fn io_handler() {
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        assert!(mem.register_io_handler(0xFF40..=0xFF40, Box::new(Inverter(0))).is_err());
+        assert!(mem.register_io_handler(0xFF7F..=0xFF80, Box::new(Inverter(0))).is_err());
+        assert!(mem.register_io_handler(0xFF7F..=0xFF7F, Box::new(Inverter(0))).is_ok());
+        assert!(mem.register_io_handler(0xFF7F..=0xFF7F, Box::new(Inverter(0))).is_err());
+
+        mem.write(0xFF7F, 0x12);
+        assert_eq!(mem.read(0xFF7F), 0xED);
+    }
+
+    // Run OAM DMA to completion, clocking the given cycles per machine cycle. Returns the clock cycles taken.
+    fn dma_cycles(cycles: u32) -> u32 {
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.write(0xC09F, 0x5A);
+        mem.write(0xFF46, 0xC0);
+
+        let mut total = 0;
+        while mem.dma_active || mem.dma_delay > 0 {
+            mem.clock(cycles);
+            total += cycles;
         }
+        assert_eq!(mem.read(0xFE9F), 0x5A);
+        total
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn double_speed_dma() {
+        assert!(dma_cycles(4) >= 160 * 4);
+        assert_eq!(dma_cycles(2), dma_cycles(4) / 2);
+    }
+
+    #[test]
+    fn oam_read_during_dma() {
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.write(0xFF40, 0x00);    // OAM is always accessible with the LCD off
+        mem.write(0xFE9F, 0x77);
+        mem.write(0xC000, 0x5A);
+        mem.write(0xFF46, 0xC0);
+        mem.clock(4);
+        mem.clock(4);
+        assert!(mem.dma_active);
+        assert_eq!(mem.cpu_read(0xFE00), 0xFF);
+        assert_eq!(mem.cpu_read(0xFE9F), 0xFF);
+
+        // Other readers see OAM as it is.
+        assert_eq!(mem.read(0xFE00), 0x5A);
+        assert_eq!(mem.read(0xFE9F), 0x77);
+
+        while mem.dma_active {
+            mem.clock(4);
+        }
+        assert_eq!(mem.cpu_read(0xFE00), 0x5A);
+        assert_eq!(mem.cpu_read(0xFE9F), 0x00);
+    }
+
+    #[test]
+    fn echo_ram_boundary() {
+        let mut rom = testutil::rom(&[]);
+        rom[0x143] = 0x80;
+        let mut mem = testutil::mem(rom);
+        mem.write(0xFF40, 0x00);    // OAM is always accessible with the LCD off
+        mem.write(0xFF70, 3);
+
+        // The top of echo RAM mirrors the top of the switchable WRAM bank.
+        mem.write(0xDDFF, 0x12);
+        mem.write(0xFE00, 0x34);
+        assert_eq!(mem.read(0xFDFF), 0x12);
+        assert_eq!(mem.read(0xFE00), 0x34);
+
+        mem.write(0xFDFF, 0x56);
+        assert_eq!(mem.read(0xDDFF), 0x56);
+        assert_eq!(mem.read(0xFE00), 0x34);
+
+        mem.write(0xFF70, 1);
+        assert_eq!(mem.read(0xFDFF), 0x00);
+    }
+
+    #[test]
+    fn cgb_scratch_registers() {
+        let mut rom = testutil::rom(&[]);
+        rom[0x143] = 0x80;
+        let mut mem = testutil::mem(rom);
+        for loc in 0xFF72..=0xFF74 {
+            mem.write(loc, 0xA5);
+            assert_eq!(mem.read(loc), 0xA5);
+        }
+
+        // Only bits 4-6 of 0xFF75 are writable. The others read set.
+        assert_eq!(mem.read(0xFF75), 0x8F);
+        mem.write(0xFF75, 0xFF);
+        assert_eq!(mem.read(0xFF75), 0xFF);
+        mem.write(0xFF75, 0x50);
+        assert_eq!(mem.read(0xFF75), 0xDF);
+        mem.write(0xFF75, 0x00);
+        assert_eq!(mem.read(0xFF75), 0x8F);
+        assert_eq!(mem.read(0xFF76), 0x00);
+
+        // Not present on DMG.
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.write(0xFF72, 0xA5);
+        assert_eq!(mem.read(0xFF72), 0xFF);
+        assert_eq!(mem.read(0xFF75), 0xFF);
+    }
+
+    #[test]
+    fn dma_bus_conflicts() {
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.write(0xC000, 0x5A);
+        mem.write(0xC100, 0x12);
+        mem.write(0xFF80, 0x34);
+        mem.write(0xFF46, 0xC0);
+        mem.clock(4);
+        mem.clock(4);
+        assert!(mem.dma_active);
+
+        // The CPU sees the byte being copied, except in IO and HRAM.
+        assert_eq!(mem.cpu_read(0x0150), 0x5A);
+        assert_eq!(mem.cpu_read(0xC100), 0x5A);
+        assert_eq!(mem.cpu_read(0xFF80), 0x34);
+        assert_eq!(mem.cpu_read(0xFF46), 0xC0);
+        assert_eq!(mem.cpu_read(0xFE00), 0xFF);
+
+        // Other readers aren't restricted.
+        assert_eq!(mem.read(0xC100), 0x12);
+        assert_eq!(mem.read(0xFE00), 0x5A);
+
+        mem.set_dma_bus_conflicts(false);
+        assert_eq!(mem.cpu_read(0xC100), 0x12);
+        assert_eq!(mem.cpu_read(0xFE00), 0xFF);
+    }
+
+    // Receives its own light, like a mirror in front of the port.
+    struct Mirror(bool);
+
+    impl IrTransceiver for Mirror {
+        fn set_light(&mut self, on: bool) {
+            self.0 = on;
+        }
+
+        fn receiving(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn infrared() {
+        let mut rom = testutil::rom(&[]);
+        rom[0x143] = 0x80;
+        let mut mem = testutil::mem(rom);
+
+        // No transceiver: no light received, even with reading enabled.
+        assert_eq!(mem.read(0xFF56), 0x3E);
+        mem.write(0xFF56, 0xC1);
+        assert_eq!(mem.read(0xFF56), 0xFF);
+
+        mem.set_ir_transceiver(Box::new(Mirror(false)));
+        mem.write(0xFF56, 0xC1);
+        assert_eq!(mem.read(0xFF56), 0xFD);
+        mem.write(0xFF56, 0xC0);
+        assert_eq!(mem.read(0xFF56), 0xFE);
+    }
+
+    #[test]
+    fn fast_boot_registers() {
+        // Documented DMG values after the boot ROM, and the bits of each register that are emulated.
+        // Unused and write-only bits aren't, and channel 1 isn't left playing.
+        const DOCUMENTED: [(u16, u8, u8); 30] = [
+            (0xFF04, 0xAB, 0xFF), (0xFF05, 0x00, 0xFF), (0xFF06, 0x00, 0xFF), (0xFF07, 0xF8, 0x07),
+            (0xFF0F, 0xE1, 0x1F), (0xFF10, 0x80, 0xFF), (0xFF11, 0xBF, 0xFF), (0xFF12, 0xF3, 0xFF),
+            (0xFF14, 0xBF, 0x7F), (0xFF16, 0x3F, 0xFF), (0xFF17, 0x00, 0xFF), (0xFF19, 0xBF, 0x7F),
+            (0xFF1A, 0x7F, 0xFF), (0xFF1B, 0xFF, 0xFF), (0xFF1C, 0x9F, 0xFF), (0xFF1E, 0xBF, 0x7F),
+            (0xFF20, 0xFF, 0xFF), (0xFF21, 0x00, 0xFF), (0xFF22, 0x00, 0xFF), (0xFF23, 0xBF, 0x40),
+            (0xFF24, 0x77, 0xFF), (0xFF25, 0xF3, 0xFF), (0xFF26, 0xF1, 0xF0), (0xFF40, 0x91, 0xFF),
+            (0xFF42, 0x00, 0xFF), (0xFF43, 0x00, 0xFF), (0xFF45, 0x00, 0xFF), (0xFF47, 0xFC, 0xFF),
+            (0xFF4A, 0x00, 0xFF), (0xFF4B, 0x00, 0xFF),
+        ];
+
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.write(0xFF26, 0x00);
+        mem.fast_boot();
+        for &(loc, val, mask) in DOCUMENTED.iter() {
+            assert_eq!(mem.read(loc) & mask, val & mask, "{:04X}", loc);
+        }
+    }
+
+    // Replies to each byte with the byte plus one.
+    struct Incrementer(Option<u8>);
+
+    impl SerialPort for Incrementer {
+        fn send_byte(&mut self, b: u8) {
+            self.0 = Some(b.wrapping_add(1));
+        }
+
+        fn recv_byte(&mut self) -> Option<u8> {
+            self.0.take()
+        }
+    }
+
+    // Start a transfer of a byte with the internal clock, and run it to completion.
+    fn serial_transfer(mem: &mut MemBus, val: u8) -> u8 {
+        mem.clear_interrupt_flag(InterruptFlags::SERIAL);
+        mem.write(0xFF01, val);
+        mem.write(0xFF02, 0x81);
+        for _ in 0..(8 * SERIAL_BIT_CYCLES) {
+            mem.clock(4);
+        }
+        assert!(mem.interrupt_flag.contains(InterruptFlags::SERIAL));
+        mem.read(0xFF01)
+    }
+
+    #[test]
+    fn serial_port() {
+        // Nothing connected: 0xFF is received.
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        assert_eq!(serial_transfer(&mut mem, 0x42), 0xFF);
+
+        mem.connect_serial(Box::new(Incrementer(None)));
+        assert_eq!(serial_transfer(&mut mem, 0x42), 0x43);
+        assert_eq!(serial_transfer(&mut mem, 0x10), 0x11);
+    }
+
+    // Start a transfer with the given control value, and return the machine cycles until the interrupt.
+    fn serial_interrupt_cycles(mem: &mut MemBus, control: u8) -> Option<u32> {
+        mem.clear_interrupt_flag(InterruptFlags::SERIAL);
+        mem.write(0xFF01, 0x42);
+        mem.write(0xFF02, control);
+        for cycles in 1..=(8 * SERIAL_BIT_CYCLES * 2) {
+            assert!(test_bit!(mem.read(0xFF02), 7));
+            mem.clock(4);
+            if mem.interrupt_flag.contains(InterruptFlags::SERIAL) {
+                // SC bit 7 clears, and SB holds the received byte.
+                assert!(!test_bit!(mem.read(0xFF02), 7));
+                assert_eq!(mem.read(0xFF01), 0x43);
+                return Some(cycles);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn serial_interrupt_timing() {
+        let mut mem = testutil::mem(testutil::rom(&[]));
+        mem.connect_serial(Box::new(Incrementer(None)));
+        assert_eq!(serial_interrupt_cycles(&mut mem, 0x81), Some(8 * SERIAL_BIT_CYCLES));
+        assert_eq!(mem.read(0xFF02), 0x7F);
+        // The fast clock bit is ignored on DMG.
+        assert_eq!(serial_interrupt_cycles(&mut mem, 0x83), Some(8 * SERIAL_BIT_CYCLES));
+        // With an external clock and nothing driving it, the transfer never completes.
+        assert_eq!(serial_interrupt_cycles(&mut mem, 0x80), None);
+
+        let mut rom = testutil::rom(&[]);
+        rom[0x143] = 0x80;
+        let mut mem = testutil::mem(rom);
+        mem.connect_serial(Box::new(Incrementer(None)));
+        assert_eq!(serial_interrupt_cycles(&mut mem, 0x81), Some(8 * SERIAL_BIT_CYCLES));
+        assert_eq!(serial_interrupt_cycles(&mut mem, 0x83), Some(8 * SERIAL_FAST_BIT_CYCLES));
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::upper_case_acronyms)]
 enum BankingMode {
     ROM,
     RAM
@@ -7,17 +8,29 @@ pub struct MBC1 {
     upper_select:   u8,
     lower_select:   u8,
     banking_mode:   BankingMode,
+
+    // Multicart (MBC1M) wiring: only 4 bits of the lower select are connected,
+    // so the upper select picks a 256KB game.
+    multicart:      bool,
 }
 
 impl MBC1 {
     pub fn new() -> Self {
+        // The lower register powers on as 0, which selects bank 1 like any write of 0.
+        // So writing only the upper register selects bank 0x21, not 0x20.
         MBC1 {
             upper_select:   0,
-            lower_select:   0,
+            lower_select:   1,
             banking_mode:   BankingMode::ROM,
+
+            multicart:      false,
         }
     }
 
+    pub fn set_multicart(&mut self, multicart: bool) {
+        self.multicart = multicart;
+    }
+
     pub fn set_lower(&mut self, val: u8) {
         match val & 0x1F {
             0 => self.lower_select = 1,
@@ -36,10 +49,29 @@ impl MBC1 {
         }
     }
 
+    // Bank mapped at 0x4000-0x7FFF. The upper bits are used in both banking modes.
     pub fn get_rom_bank(&self) -> u8 {
+        if self.multicart {
+            (self.upper_select << 4) | (self.lower_select & 0xF)
+        } else {
+            (self.upper_select << 5) | self.lower_select
+        }
+    }
+
+    // Bank mapped at 0x0000-0x3FFF. In RAM banking mode the upper bits apply here too,
+    // which only matters for 1MB or larger ROMs. Multicarts use this to switch to the start of a game.
+    pub fn get_rom_bank_0(&self) -> u8 {
+        match (&self.banking_mode, self.multicart) {
+            (BankingMode::ROM, _)       => 0,
+            (BankingMode::RAM, true)    => self.upper_select << 4,
+            (BankingMode::RAM, false)   => self.upper_select << 5,
+        }
+    }
+
+    pub fn is_ram_banking_mode(&self) -> bool {
         match self.banking_mode {
-            BankingMode::ROM => (self.upper_select << 5) | self.lower_select,
-            BankingMode::RAM => self.lower_select,
+            BankingMode::ROM => false,
+            BankingMode::RAM => true,
         }
     }
 
@@ -50,3 +82,47 @@ impl MBC1 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_on_lower_bank() {
+        let mut mbc1 = MBC1::new();
+        assert_eq!(mbc1.get_rom_bank(), 1);
+        mbc1.set_upper(1);
+        assert_eq!(mbc1.get_rom_bank(), 0x21);
+    }
+
+    #[test]
+    fn standard_banks() {
+        let mut mbc1 = MBC1::new();
+        mbc1.set_lower(0x3F);   // Only 5 bits are used.
+        mbc1.set_upper(0x03);
+        assert_eq!((mbc1.get_rom_bank_0(), mbc1.get_rom_bank(), mbc1.get_ram_bank()), (0, 0x7F, 0));
+        mbc1.set_lower(0x20);   // Masks to 0, which selects 1.
+        assert_eq!(mbc1.get_rom_bank(), 0x61);
+
+        // The upper bits also select the RAM bank, and the bank mapped at 0x0000.
+        mbc1.mem_type_select(0x01);
+        mbc1.set_lower(0x12);
+        assert_eq!((mbc1.get_rom_bank_0(), mbc1.get_rom_bank(), mbc1.get_ram_bank()), (0x60, 0x72, 3));
+    }
+
+    #[test]
+    fn multicart_banks() {
+        let mut mbc1 = MBC1::new();
+        mbc1.set_multicart(true);
+        mbc1.set_lower(0x13);   // Only 4 bits are connected.
+        mbc1.set_upper(0x02);
+        assert_eq!((mbc1.get_rom_bank_0(), mbc1.get_rom_bank(), mbc1.get_ram_bank()), (0, 0x23, 0));
+
+        // The upper bits also select the game mapped at 0x0000.
+        mbc1.mem_type_select(0x01);
+        assert_eq!((mbc1.get_rom_bank_0(), mbc1.get_rom_bank(), mbc1.get_ram_bank()), (0x20, 0x23, 2));
+        // 0x10 isn't translated to 1, so the first bank of the game is mapped at 0x4000 too.
+        mbc1.set_lower(0x10);
+        assert_eq!(mbc1.get_rom_bank(), 0x20);
+    }
+}
@@ -3,19 +3,68 @@
 mod ram;
 mod rom;
 mod mbc1;
+#[cfg(feature = "zip")]
+mod zip;
 
 use ram::*;
 use rom::*;
 use mbc1::MBC1;
 
 use super::MemDevice;
+use crate::cheats::{CheatId, RomPatch};
 
 pub enum ROMType {
     File(String),
     Data(Vec<u8>),
 }
 
+// Where battery-backed RAM is saved.
+pub enum SaveType {
+    File(String),               // Save to a file on disk.
+    Memory(Option<Vec<u8>>),    // Keep in memory only, with optional initial contents.
+}
+
+// What reading cart RAM returns while it is disabled.
+// All the emulated mappers (MBC1, 2, 3 and 5) leave the bus floating, so Open is the default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisabledRamRead {
+    Zero,   // Always read 0x00.
+    Open,   // Open bus: always read 0xFF.
+}
+
+// Currently mapped banks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BankState {
+    pub rom_bank:       u16,    // Bank mapped at 0x4000-0x7FFF.
+    pub ram_bank:       u8,     // For MBC3, this may select an RTC register (0x8-0xC).
+    pub mbc1_ram_mode:  bool,   // MBC1 banking mode: upper bits select the RAM bank instead of ROM.
+}
+
+// Hardware on the cartridge, from the header type byte.
+// This reports what the header claims, even for mappers that aren't emulated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CartFeatureSet {
+    pub battery:    bool,   // RAM (or RTC) contents are saved.
+    pub rtc:        bool,   // Real-time clock.
+    pub rumble:     bool,   // Rumble motor (MBC5).
+    pub infrared:   bool,   // IR port on the cartridge (HuC1 and HuC3).
+    pub camera:     bool,   // Game Boy Camera.
+}
+
+impl CartFeatureSet {
+    fn from_cart_type(cart_type: u8) -> Self {
+        CartFeatureSet {
+            battery:    matches!(cart_type, 0x3 | 0x6 | 0x9 | 0xD | 0xF | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF),
+            rtc:        matches!(cart_type, 0xF | 0x10 | 0xFE),
+            rumble:     matches!(cart_type, 0x1C..=0x1E | 0x22),
+            infrared:   matches!(cart_type, 0xFE | 0xFF),
+            camera:     cart_type == 0xFC,
+        }
+    }
+}
+
 // Cartridge Memory Bank type
+#[allow(clippy::upper_case_acronyms)]
 enum MBC {
     _0,
     _1(MBC1),
@@ -24,6 +73,19 @@ enum MBC {
     _5(u16),
 }
 
+impl MBC {
+    // The number of ROM banks the mapper can address.
+    fn max_rom_banks(&self) -> usize {
+        match self {
+            MBC::_0     => 2,
+            MBC::_1(_)  => 128,
+            MBC::_2     => 16,
+            MBC::_3     => 128,
+            MBC::_5(_)  => 512,
+        }
+    }
+}
+
 // Cartridge extra features
 enum CartFeatures {
     None,
@@ -36,17 +98,31 @@ pub struct Cartridge {
     ram:        Box<dyn RAM>,
 
     mem_bank:   MBC,
-    ram_enable: bool
+    ram_enable: bool,
+    disabled_ram_read:  DisabledRamRead,
+
+    rom_bank:   u16,
+    ram_bank:   u8,
+    rom_banks:  u16,    // Banks that can be selected. Higher bank numbers wrap around.
+
+    rumble:         bool,   // MBC5 rumble motor, wired to bit 3 of the RAM bank select.
+    rumble_on:      bool,
+    rumble_latch:   bool,   // Set if the motor was turned on since the state was last taken.
+
+    rom_patches:    Vec<(CheatId, RomPatch)>,
 }
 
 impl Cartridge {
-    pub fn new(rom_type: ROMType, save_file_name: &str) -> Result<Cartridge, String> {
+    pub fn new(rom_type: ROMType, save_type: SaveType) -> Result<Cartridge, String> {
         let rom = match rom_type {
+            #[cfg(feature = "zip")]
+            ROMType::File(file_name) if zip::is_zip_file(&file_name) => ROMData::new(&zip::load_rom(&file_name)?) as Box<dyn ROM>,
             ROMType::File(file_name) => ROMFile::new(&file_name)? as Box<dyn ROM>,
             ROMType::Data(data) => ROMData::new(&data) as Box<dyn ROM>,
         };
 
-        let (bank_type, features) = match rom.read(0x147) {
+        let rom_type_byte = rom.read(0x147);
+        let (bank_type, features) = match rom_type_byte {
             0x1 | 0x2           => (MBC::_1(MBC1::new()), CartFeatures::None),
             0x3                 => (MBC::_1(MBC1::new()), CartFeatures::Battery),
             0x5                 => (MBC::_2,              CartFeatures::None),
@@ -69,32 +145,114 @@ impl Cartridge {
             _               => 0,
         };
 
+        // Banks past what the mapper can address are unreachable.
+        let max_banks = bank_type.max_rom_banks();
+        if rom.bank_count() > max_banks {
+            log::warn!("ROM has {} banks, but the mapper can only address {}", rom.bank_count(), max_banks);
+        }
+        let rom_banks = rom.bank_count().clamp(1, max_banks) as u16;
+
         let ram: Box<dyn RAM> = match features {
             CartFeatures::None      => Box::new(BankedRAM::new(ram_size)),
-            CartFeatures::Battery   => Box::new(BatteryRAM::new(ram_size, save_file_name)?),
-            CartFeatures::Timer     => Box::new(ClockRAM::new(ram_size, save_file_name)?)
+            CartFeatures::Battery   => Box::new(BatteryRAM::new(ram_size, save_type)?),
+            CartFeatures::Timer     => Box::new(ClockRAM::new(ram_size, save_type)?)
         };
 
         let mut ret = Cartridge {
             rom:                rom,
             ram:                ram,
             mem_bank:           bank_type,
-            ram_enable:         false
+            ram_enable:         false,
+            disabled_ram_read:  DisabledRamRead::Open,
+
+            rom_bank:           0,
+            ram_bank:           0,
+            rom_banks:          rom_banks,
+
+            rumble:             CartFeatureSet::from_cart_type(rom_type_byte).rumble,
+            rumble_on:          false,
+            rumble_latch:       false,
+
+            rom_patches:        Vec::new(),
         };
 
         ret.swap_rom_bank(1);
 
+        if ret.is_multicart() {
+            ret.set_mbc1_multicart(true);
+        }
+
         Ok(ret)
     }
 
+    // Switch MBC1 carts between standard and multicart (MBC1M) bank wiring.
+    // Multicarts are detected automatically, but this can override the detection.
+    pub fn set_mbc1_multicart(&mut self, multicart: bool) {
+        if let MBC::_1(mb) = &mut self.mem_bank {
+            mb.set_multicart(multicart);
+            let rom_bank = mb.get_rom_bank() as u16;
+            let rom_bank_0 = mb.get_rom_bank_0() as u16;
+            self.swap_rom_bank(rom_bank);
+            self.swap_rom_bank_0(rom_bank_0);
+        }
+    }
+
+    pub fn set_disabled_ram_read(&mut self, policy: DisabledRamRead) {
+        self.disabled_ram_read = policy;
+    }
+
+    pub fn add_rom_patch(&mut self, id: CheatId, patch: RomPatch) {
+        self.rom_patches.push((id, patch));
+    }
+
+    pub fn remove_rom_patch(&mut self, id: CheatId) {
+        self.rom_patches.retain(|(patch_id, _)| *patch_id != id);
+    }
+
+    pub fn clear_rom_patches(&mut self) {
+        self.rom_patches.clear();
+    }
+
     pub fn flush_ram(&mut self) {
         self.ram.flush();
     }
 
-    // Get the ROM name.
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.ram.save_data()
+    }
+
+    pub fn set_fixed_clock(&mut self) {
+        self.ram.set_fixed_clock();
+    }
+
+    pub fn current_banks(&self) -> BankState {
+        BankState {
+            rom_bank:       self.rom_bank,
+            ram_bank:       self.ram_bank,
+            mbc1_ram_mode:  if let MBC::_1(mb) = &self.mem_bank {mb.is_ram_banking_mode()} else {false},
+        }
+    }
+
+    // True if the rumble motor is on, or was turned on since the last call.
+    // Games pulse the motor to vary its strength, so it may be switched many times per frame.
+    pub fn take_rumble_state(&mut self) -> bool {
+        let rumble = self.rumble_on || self.rumble_latch;
+        self.rumble_latch = false;
+        rumble
+    }
+
+    pub fn features(&self) -> CartFeatureSet {
+        CartFeatureSet::from_cart_type(self.read(0x147))
+    }
+
+    // Get the ROM name. Invalid characters are replaced rather than failing.
     pub fn name(&self) -> String {
-        use std::str::FromStr;
+        String::from_utf8_lossy(&self.title_bytes()).into_owned()
+    }
 
+    // Get the raw title from the header, up to the first zero byte.
+    // On newer carts, the title region is shorter.
+    pub fn title_bytes(&self) -> Vec<u8> {
         let old_code = self.read(0x014B);
         let title_end = if old_code == 0x33 {
             0x13E
@@ -112,7 +270,7 @@ impl Cartridge {
             }
         }
 
-        String::from_str(std::str::from_utf8(&name_bytes).unwrap()).unwrap()
+        name_bytes
     }
 
     // Get the cart name hash values for SGB palette lookup.
@@ -154,11 +312,33 @@ impl Cartridge {
 // Internal swapping methods.
 impl Cartridge {
     fn swap_rom_bank(&mut self, bank: u16) {
-        self.rom.set_bank(bank);
+        self.rom_bank = bank % self.rom_banks;
+        self.rom.set_bank(self.rom_bank);
+    }
+
+    fn swap_rom_bank_0(&mut self, bank: u16) {
+        self.rom.set_bank_0(bank % self.rom_banks);
+    }
+
+    // 1MB MBC1 multicarts have a header (with the Nintendo logo) at the start of each 256KB game.
+    // The second game is checked, since the menu is in the first.
+    fn is_multicart(&mut self) -> bool {
+        const MULTICART_GAME_BANK: u16 = 0x10;
+
+        if !matches!(self.mem_bank, MBC::_1(_)) || self.rom_banks != 64 {
+            return false;
+        }
+
+        self.rom.set_bank(MULTICART_GAME_BANK);
+        let logo_matches = (0x104..0x134).all(|loc| self.rom.read(loc) == self.rom.read(loc + 0x4000));
+        self.rom.set_bank(self.rom_bank);
+
+        logo_matches
     }
 
     #[inline]
     fn swap_ram_bank(&mut self, bank: u8) {
+        self.ram_bank = bank;
         self.ram.set_bank(bank, 0);
     }
 
@@ -167,10 +347,22 @@ impl Cartridge {
         if self.ram_enable {
             self.ram.read(loc)
         } else {
-            0
+            match self.disabled_ram_read {
+                DisabledRamRead::Zero => 0,
+                DisabledRamRead::Open => 0xFF,
+            }
         }
     }
 
+    // The first matching patch replaces the byte.
+    fn read_patched_rom(&self, loc: u16) -> u8 {
+        let val = self.rom.read(loc);
+        self.rom_patches.iter()
+            .map(|(_, patch)| patch)
+            .find(|patch| patch.addr == loc && patch.compare.is_none_or(|c| c == val))
+            .map_or(val, |patch| patch.val)
+    }
+
     #[inline]
     fn write_ram(&mut self, loc: u16, val: u8) {
         if self.ram_enable {
@@ -185,19 +377,21 @@ impl Cartridge {
 impl MemDevice for Cartridge {
     fn read(&self, loc: u16) -> u8 {
         match loc {
-            0x0..=0x7FFF    => self.rom.read(loc),
+            0x0..=0x7FFF if self.rom_patches.is_empty() => self.rom.read(loc),
+            0x0..=0x7FFF    => self.read_patched_rom(loc),
             0xA000..=0xBFFF => self.read_ram(loc - 0xA000),
             _ => unreachable!()
         }
     }
 
     fn write(&mut self, loc: u16, val: u8) {
-        if (loc >= 0xA000) && (loc < 0xC000) {
+        if (0xA000..0xC000).contains(&loc) {
             self.write_ram(loc - 0xA000, val);
         } else {
             match self.mem_bank {
                 MBC::_1(ref mut mb) => {
                     let old_rom_bank = mb.get_rom_bank();
+                    let old_rom_bank_0 = mb.get_rom_bank_0();
                     let old_ram_bank = mb.get_ram_bank();
                     match loc {
                         0x0000..=0x1FFF => self.ram_enable = (val & 0xA) == 0xA,
@@ -207,11 +401,15 @@ impl MemDevice for Cartridge {
                     }
 
                     let new_rom_bank = mb.get_rom_bank();
+                    let new_rom_bank_0 = mb.get_rom_bank_0();
                     let new_ram_bank = mb.get_ram_bank();
 
                     if new_rom_bank != old_rom_bank {
                         self.swap_rom_bank(new_rom_bank as u16);
                     }
+                    if new_rom_bank_0 != old_rom_bank_0 {
+                        self.swap_rom_bank_0(new_rom_bank_0 as u16);
+                    }
                     if new_ram_bank != old_ram_bank {
                         self.swap_ram_bank(new_ram_bank);
                     }
@@ -225,7 +423,11 @@ impl MemDevice for Cartridge {
                     (0x0000..=0x1FFF, _)    => self.ram_enable = (val & 0xF) == 0xA,
                     (0x2000..=0x3FFF, 0)    => self.swap_rom_bank(1),
                     (0x2000..=0x3FFF, _)    => self.swap_rom_bank((val & 0x7F) as u16),
-                    (0x4000..=0x7FFF, _)    => self.ram.set_bank(val, loc),
+                    (0x4000..=0x5FFF, _)    => {
+                        self.ram_bank = val;
+                        self.ram.set_bank(val, loc);
+                    },
+                    (0x6000..=0x7FFF, _)    => self.ram.set_bank(val, loc),
                     _ => unreachable!(),
                 },
                 MBC::_5(ref mut rom) => match (loc, val) {
@@ -242,6 +444,11 @@ impl MemDevice for Cartridge {
                         let rom_bank = *rom;
                         self.swap_rom_bank(rom_bank);
                     },
+                    (0x4000..=0x5FFF, _) if self.rumble => {
+                        self.rumble_on = test_bit!(val, 3);
+                        self.rumble_latch = self.rumble_latch || self.rumble_on;
+                        self.swap_ram_bank(val & 0x7);
+                    },
                     (0x4000..=0x5FFF, _)    => self.swap_ram_bank(val),
                     _ => {},
                 },
@@ -249,4 +456,143 @@ impl MemDevice for Cartridge {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn cart(cart_type: u8, banks: usize, ram_size: u8) -> Cartridge {
+        Cartridge::new(ROMType::Data(testutil::cart_rom(cart_type, banks, ram_size, &[])), SaveType::Memory(None)).unwrap()
+    }
+
+    #[test]
+    fn invalid_title() {
+        let mut rom = testutil::rom(&[]);
+        rom[0x134..0x139].copy_from_slice(&[b'T', 0xFF, b'E', 0xC0, b'X']);
+        let cart = Cartridge::new(ROMType::Data(rom), SaveType::Memory(None)).unwrap();
+        assert_eq!(cart.title_bytes(), [b'T', 0xFF, b'E', 0xC0, b'X']);
+        assert_eq!(cart.name(), "T\u{FFFD}E\u{FFFD}X");
+    }
+
+    #[test]
+    fn mbc3_banks() {
+        let mut cart = cart(0x13, 8, 0x03);
+        cart.write(0x2000, 0x05);
+        cart.write(0x4000, 0x02);
+        assert_eq!(cart.read(0x4000), 5);
+        assert_eq!(cart.current_banks(), BankState {rom_bank: 5, ram_bank: 2, mbc1_ram_mode: false});
+
+        cart.write(0x4000, 0x08);
+        assert_eq!(cart.current_banks().ram_bank, 0x08);
+    }
+
+    #[test]
+    fn mbc1_banks() {
+        let mut cart = cart(0x03, 128, 0x03);
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.current_banks().rom_bank, 1);
+        cart.write(0x2000, 0x01);
+        cart.write(0x4000, 0x01);
+        assert_eq!(cart.current_banks(), BankState {rom_bank: 0x21, ram_bank: 0, mbc1_ram_mode: false});
+        assert_eq!(cart.read(0x4000), 0x21);
+
+        // In RAM banking mode the upper bits still apply to 0x4000-0x7FFF, and to 0x0000-0x3FFF too.
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.current_banks(), BankState {rom_bank: 0x21, ram_bank: 1, mbc1_ram_mode: true});
+        assert_eq!(cart.read(0x4000), 0x21);
+        assert_eq!(cart.read(0x0000), 0x20);
+
+        // Below 1MB, the upper bits only select the RAM bank.
+        let mut small = self::cart(0x03, 8, 0x03);
+        small.write(0x2000, 0x01);
+        small.write(0x4000, 0x01);
+        small.write(0x6000, 0x01);
+        assert_eq!(small.current_banks(), BankState {rom_bank: 0x01, ram_bank: 1, mbc1_ram_mode: true});
+        assert_eq!(small.read(0x4000), 0x01);
+        assert_eq!(small.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn bank_select_masked() {
+        // 4MB image on an MBC1, which only addresses 2MB: the extra banks are unreachable.
+        let mut mbc1 = cart(0x01, 256, 0x00);
+        mbc1.write(0x4000, 0x03);
+        mbc1.write(0x2000, 0x1F);
+        assert_eq!(mbc1.read(0x4000), 0x7F);
+
+        // 128KB image on an MBC3: bank selects wrap around.
+        let mut mbc3 = cart(0x11, 8, 0x00);
+        mbc3.write(0x2000, 13);
+        assert_eq!(mbc3.current_banks().rom_bank, 5);
+        assert_eq!(mbc3.read(0x4000), 5);
+
+        // 64KB image on an MBC5, selecting the highest bank.
+        let mut mbc5 = cart(0x19, 4, 0x00);
+        mbc5.write(0x2000, 0xFF);
+        mbc5.write(0x3000, 0x01);
+        assert_eq!(mbc5.read(0x4000), 3);
+    }
+
+    #[test]
+    fn mbc5_rumble() {
+        // MBC5+RUMBLE+RAM+BATTERY with 4 RAM banks.
+        let mut rumble = cart(0x1E, 4, 0x03);
+        rumble.write(0x0000, 0x0A);
+        rumble.write(0x4000, 0x02);
+        rumble.write(0xA000, 0x42);
+        assert!(!rumble.take_rumble_state());
+
+        // Bit 3 switches the motor without leaving the selected bank.
+        rumble.write(0x4000, 0x0A);
+        assert_eq!(rumble.current_banks().ram_bank, 2);
+        assert_eq!(rumble.read(0xA000), 0x42);
+        assert!(rumble.take_rumble_state());
+
+        rumble.write(0x4000, 0x08);
+        assert_eq!(rumble.current_banks().ram_bank, 0);
+        assert!(rumble.take_rumble_state());
+
+        rumble.write(0x4000, 0x02);
+        assert_eq!(rumble.current_banks().ram_bank, 2);
+        assert_eq!(rumble.read(0xA000), 0x42);
+        assert!(!rumble.take_rumble_state());
+
+        // Without rumble, bit 3 is part of the RAM bank.
+        let mut plain = cart(0x1B, 4, 0x04);
+        plain.write(0x4000, 0x08);
+        assert_eq!(plain.current_banks().ram_bank, 8);
+        assert!(!plain.take_rumble_state());
+    }
+
+    #[test]
+    fn disabled_ram_read() {
+        let mut cart = cart(0x1B, 4, 0x03);
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x42);
+        cart.write(0x0000, 0x00);
+
+        // Open bus by default.
+        assert_eq!(cart.read(0xA000), 0xFF);
+        cart.set_disabled_ram_read(DisabledRamRead::Zero);
+        assert_eq!(cart.read(0xA000), 0x00);
+        cart.set_disabled_ram_read(DisabledRamRead::Open);
+        assert_eq!(cart.read(0xA000), 0xFF);
+
+        // Writes are still dropped.
+        cart.write(0xA000, 0x11);
+        cart.write(0x0000, 0x0A);
+        assert_eq!(cart.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn features() {
+        // MBC3+TIMER+RAM+BATTERY
+        assert_eq!(cart(0x10, 4, 0x03).features(), CartFeatureSet {battery: true, rtc: true, ..Default::default()});
+        // MBC3+RAM
+        assert_eq!(cart(0x12, 4, 0x03).features(), CartFeatureSet::default());
+        // MBC5+RUMBLE+RAM+BATTERY
+        assert_eq!(cart(0x1E, 4, 0x03).features(), CartFeatureSet {battery: true, rumble: true, ..Default::default()});
+    }
+}
@@ -2,6 +2,7 @@
 use chrono::{
     DateTime,
     Duration,
+    TimeZone,
     Utc
 };
 
@@ -9,9 +10,13 @@ use std::{
     io::{
         BufReader,
         BufWriter,
+        Cursor,
         Read,
+        Seek,
+        SeekFrom,
         Write
     },
+    ops::Range,
     fs::{
         File,
         OpenOptions
@@ -19,10 +24,62 @@ use std::{
 };
 
 use crate::mem::MemDevice;
+use super::SaveType;
 
+#[allow(clippy::upper_case_acronyms)]
 pub trait RAM: MemDevice {
     fn set_bank(&mut self, bank: u8, loc: u16);
     fn flush(&mut self) {}
+
+    // Get the battery-backed contents, in the same format as the save file.
+    fn save_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Stop any real-time clock from following the system time.
+    fn set_fixed_clock(&mut self) {}
+}
+
+// Save file name (if any), and the existing save data (if any).
+type SaveSource = (Option<String>, Option<Box<dyn Read>>);
+
+// Open existing save data for reading.
+// If the save file doesn't exist it is created with the provided size.
+fn open_save(save_type: SaveType, save_size: usize) -> Result<SaveSource, String> {
+    match save_type {
+        SaveType::File(save_file_name) => if let Ok(file) = File::open(&save_file_name) {
+            Ok((Some(save_file_name), Some(Box::new(BufReader::new(file)))))
+        } else {
+            let file = File::create(&save_file_name).map_err(|e| e.to_string())?;
+            file.set_len(save_size as u64).map_err(|e| e.to_string())?;
+            Ok((Some(save_file_name), None))
+        },
+        SaveType::Memory(data) => Ok((None, data.map(|d| Box::new(Cursor::new(d)) as Box<dyn Read>)))
+    }
+}
+
+// Write save data to an existing save file, starting at the offset.
+// If a file size is given, the file is cut or extended to it, so no old data is left at the end.
+fn write_save(save_file_name: &str, offset: usize, data: &[u8], file_size: Option<usize>) -> std::io::Result<()> {
+    let mut save_f = OpenOptions::new()
+        .write(true)
+        .open(save_file_name)?;
+    if let Some(size) = file_size {
+        save_f.set_len(size as u64)?;
+    }
+    save_f.seek(SeekFrom::Start(offset as u64))?;
+
+    let mut bufwriter = BufWriter::new(save_f);
+    bufwriter.write_all(data)?;
+    bufwriter.flush()
+}
+
+// Extend the range of RAM that has changed since the last flush.
+fn mark_dirty(dirty: &mut Option<Range<usize>>, pos: usize) {
+    *dirty = Some(match dirty.take() {
+        Some(range) => range.start.min(pos)..range.end.max(pos + 1),
+        None => pos..(pos + 1)
+    });
 }
 
 // Banked RAM
@@ -58,29 +115,26 @@ impl RAM for BankedRAM {
 
 // Battery backed RAM
 pub struct BatteryRAM {
-    save_file:  String,
+    save_file:  Option<String>,
     offset:     usize,
     ram:        Vec<u8>,
-    dirty:      bool,
+    dirty:      Option<Range<usize>>,   // Region of RAM that needs writing to the save file.
 }
 
 impl BatteryRAM {
-    pub fn new(ram_size: usize, save_file_name: &str) -> Result<Self, String> {
+    pub fn new(ram_size: usize, save_type: SaveType) -> Result<Self, String> {
         let mut ram = vec![0; ram_size];
 
-        if let Ok(file) = File::open(save_file_name) {
-            let mut save_reader = BufReader::new(file);
+        let (save_file, save_data) = open_save(save_type, ram_size)?;
+        if let Some(mut save_reader) = save_data {
             save_reader.read_exact(&mut ram).map_err(|e| e.to_string())?;
-        } else {
-            let file = File::create(save_file_name).map_err(|e| e.to_string())?;
-            file.set_len(ram_size as u64).map_err(|e| e.to_string())?;
         }
 
         Ok(BatteryRAM {
-            save_file:  save_file_name.to_string(),
+            save_file:  save_file,
             offset:     0,
             ram:        ram,
-            dirty:      false
+            dirty:      None
         })
     }
 }
@@ -95,7 +149,7 @@ impl MemDevice for BatteryRAM {
 
         self.ram[pos] = val;
 
-        self.dirty = true;
+        mark_dirty(&mut self.dirty, pos);
     }
 }
 
@@ -105,25 +159,31 @@ impl RAM for BatteryRAM {
     }
 
     fn flush(&mut self) {
-        if self.dirty {
-            let save_f = OpenOptions::new()
-                .write(true)
-                .open(self.save_file.as_str())
-                .expect("Couldn't open file");
-
-            let mut bufwriter = BufWriter::new(save_f);
-
-            bufwriter.write_all(&self.ram).expect("Couldn't write to file");
-
-            self.dirty = false;
+        if let Some(range) = self.dirty.take() {
+            if let Some(save_file) = &self.save_file {
+                if let Err(e) = write_save(save_file, range.start, &self.ram[range], None) {
+                    log::error!("Couldn't write save file {}: {}", save_file, e);
+                }
+            }
         }
     }
+
+    fn save_data(&self) -> Option<Vec<u8>> {
+        Some(self.ram.clone())
+    }
 }
 
 // Battery backed RAM with real-time clock
 
+// Clock data at the end of the save: the 5 clock registers, then the Unix time they were recorded at (i64, little-endian).
+const CLOCK_SAVE_SIZE: usize = 5 + 8;
+
+// Source of the current time for the real-time clock.
+pub type ClockSource = Box<dyn Fn() -> DateTime<Utc>>;
+
 // What maps to the area of cart RAM.
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum RamMap {
     RAM,    // RAM
     S,      // Seconds
@@ -134,10 +194,10 @@ enum RamMap {
 }
 
 pub struct ClockRAM {
-    save_file:  String,
+    save_file:  Option<String>,
     offset:     usize,
     ram:        Vec<u8>,
-    dirty:      bool,
+    dirty:      Option<Range<usize>>,   // Region of RAM that needs writing to the save file.
     ram_map:    RamMap,
 
     seconds:        u8,
@@ -145,60 +205,89 @@ pub struct ClockRAM {
     hours:          u8,
     days:           u16,
     microseconds:   usize,
-    time:           DateTime<Utc>,
-    latch:          bool,
+    time:           DateTime<Utc>,  // When the registers above were last brought up to date.
+    halted:         bool,           // Clock is stopped by the halt flag.
+    latched:        [u8; 5],        // Registers as read by the game: S, M, H, DL, DH.
+    latch_write:    u8,             // Last value written to the latch register.
+    loaded_save:    bool,   // The clock was loaded from a save, so time is when the save was made.
+    fixed_time:     Option<DateTime<Utc>>,  // If set, the clock doesn't advance with the system time.
+    clock:          ClockSource,
+    clock_dirty:    bool,   // Clock registers need writing to the save file.
 }
 
 impl ClockRAM {
-    pub fn new(ram_size: usize, save_file_name: &str) -> Result<Self, String> {
+    pub fn new(ram_size: usize, save_type: SaveType) -> Result<Self, String> {
+        Self::with_clock(ram_size, save_type, Box::new(Utc::now))
+    }
+
+    // Create with a custom source for the current time instead of the system time.
+    pub fn with_clock(ram_size: usize, save_type: SaveType, clock: ClockSource) -> Result<Self, String> {
         let mut ram = vec![0; ram_size];
-        let now = Utc::now();
-        let timer_size = 5 + now.to_rfc3339().len();
-        let mut timer = vec![0; timer_size];
 
-        let mut microseconds = 0;
+        let mut time = clock();
         let mut seconds = 0;
         let mut minutes = 0;
         let mut hours = 0;
         let mut days = 0;
+        let mut halted = false;
+        let mut loaded_save = false;
+        let mut legacy_save = false;
 
-        if let Ok(file) = File::open(save_file_name) {
-            let mut save_reader = BufReader::new(file);
+        let (save_file, save_data) = open_save(save_type, ram_size + CLOCK_SAVE_SIZE)?;
+        if let Some(mut save_reader) = save_data {
             save_reader.read_exact(&mut ram).map_err(|e| e.to_string())?;
 
-            // Calc difference in time since last time this was saved.
-            save_reader.read_exact(&mut timer).map_err(|e| e.to_string())?;
+            let mut timer = Vec::new();
+            save_reader.read_to_end(&mut timer).map_err(|e| e.to_string())?;
+            if timer.len() < CLOCK_SAVE_SIZE {
+                return Err(format!("Clock data is too short: {} bytes", timer.len()));
+            }
 
+            if timer[0] > 0x3F || timer[1] > 0x3F || timer[2] > 0x1F || (timer[4] & 0x3E) != 0 {
+                return Err(format!("Invalid clock registers: {:?}", &timer[0..5]));
+            }
             seconds = timer[0];
             minutes = timer[1];
             hours = timer[2];
-            days = timer[3] as u16 | ((timer[4] as u16) << 8);
-
-            let time_string = String::from_utf8(timer[5..].to_vec()).expect(&format!("Couldn't read time: {:?}", &timer[5..]));
-            let old_time = chrono::DateTime::parse_from_rfc3339(&time_string).expect(&format!("Couldn't parse time: {}", time_string));
-            let diff = now.signed_duration_since(old_time);
-
-            update_times(&diff, &mut microseconds, &mut seconds, &mut minutes, &mut hours, &mut days);
-        } else {
-            let file = File::create(save_file_name).map_err(|e| e.to_string())?;
-            file.set_len((ram_size + timer_size) as u64).map_err(|e| e.to_string())?;
+            days = timer[3] as u16 | (((timer[4] & 0x81) as u16) << 8);
+            halted = (timer[4] & 0x40) != 0;
+
+            // The time since the save was made is applied when the clock is next read.
+            time = match read_legacy_save_time(&timer[5..]) {
+                Some(time) => {
+                    legacy_save = true;
+                    time
+                },
+                None => read_save_time(&timer[5..])?
+            };
+            loaded_save = true;
         }
 
-        Ok(ClockRAM {
-            save_file:  save_file_name.to_string(),
+        let mut clock_ram = ClockRAM {
+            save_file:  save_file,
             offset:     0,
             ram:        ram,
-            dirty:      false,
+            dirty:      None,
             ram_map:    RamMap::RAM,
 
             seconds:        seconds,
             minutes:        minutes,
             hours:          hours,
             days:           days,
-            microseconds:   microseconds,
-            time:           now,
-            latch:          false
-        })
+            microseconds:   0,
+            time:           time,
+            halted:         halted,
+            latched:        [0; 5],
+            latch_write:    0xFF,
+            loaded_save:    loaded_save,
+            fixed_time:     None,
+            clock:          clock,
+            // Older saves are converted to the current format on the first flush.
+            clock_dirty:    legacy_save,
+        };
+
+        clock_ram.latch_clock();
+        Ok(clock_ram)
     }
 }
 
@@ -207,56 +296,49 @@ impl MemDevice for ClockRAM {
         use RamMap::*;
         match self.ram_map {
             RAM => self.ram[self.offset + (loc as usize)],
-            _ => {
-                let mut microseconds = self.microseconds;
-                let mut seconds = self.seconds;
-                let mut minutes = self.minutes;
-                let mut hours = self.hours;
-                let mut days = self.days;
-
-                if !self.latch {
-                    let now = Utc::now();
-                    update_times(&now.signed_duration_since(self.time), &mut microseconds, &mut seconds, &mut minutes, &mut hours, &mut days);
-                }
-
-                match self.ram_map {
-                    S => seconds,
-                    M => minutes,
-                    H => hours,
-                    DL => days as u8,
-                    DH => (days >> 8) as u8,
-                    _ => unreachable!()
-                }
-            },
+            // The clock registers always read the last latched value.
+            S => self.latched[0],
+            M => self.latched[1],
+            H => self.latched[2],
+            DL => self.latched[3],
+            DH => self.latched[4],
         }
     }
 
+    // Clock register writes set the running clock. They also show up in the latched registers straight away.
     fn write(&mut self, loc: u16, val: u8) {
         use RamMap::*;
 
-        match self.ram_map {
-            RAM => {
-                let pos = self.offset + (loc as usize);
+        if let RAM = self.ram_map {
+            let pos = self.offset + (loc as usize);
+
+            self.ram[pos] = val;
+            mark_dirty(&mut self.dirty, pos);
+            return;
+        }
 
-                self.ram[pos] = val;
+        self.update_clock();
+        match self.ram_map {
+            S => {
+                self.seconds = val & 0x3F;
+                self.microseconds = 0;
             },
-            S => self.seconds = val,
-            M => self.minutes = val,
-            H => self.hours = val,
+            M => self.minutes = val & 0x3F,
+            H => self.hours = val & 0x1F,
             DL => {
                 self.days &= 0xFF00;
                 self.days |= val as u16;
             },
             DH => {
-                if (val & 0x40) != 0 {
-                    println!("Stop the clocks!");
-                }
+                self.halted = (val & 0x40) != 0;
                 self.days &= 0xFF;
-                self.days |= ((val & 1) as u16) << 8;
+                self.days |= ((val & 0x81) as u16) << 8;
             },
+            RAM => unreachable!()
         }
+        self.latch_clock();
 
-        self.dirty = true;
+        self.clock_dirty = true;
     }
 }
 
@@ -273,44 +355,105 @@ impl RAM for ClockRAM {
                 0xB => DL,
                 _   => DH
             };
-        } else if bank == 1 { // Latch the clock.
-            self.latch = !self.latch;
-
-            let now = Utc::now();
-            update_times(&now.signed_duration_since(self.time), &mut self.microseconds, &mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days);
-
-            self.time = now;
+        } else {
+            // Writing 0 then 1 latches the clock.
+            if self.latch_write == 0 && bank == 1 {
+                self.update_clock();
+                self.latch_clock();
+            }
+            self.latch_write = bank;
         }
     }
 
+    // The changed region of RAM is written, followed by the clock data.
     fn flush(&mut self) {
-        if self.dirty {
-            let save_f = OpenOptions::new()
-                .write(true)
-                .open(self.save_file.as_str())
-                .expect("Couldn't open file");
+        if self.dirty.is_some() || self.clock_dirty {
+            self.update_clock();
+            if let Some(save_file) = &self.save_file {
+                let start = self.dirty.as_ref().map_or(self.ram.len(), |range| range.start);
+                let data = self.clock_save_data();
+                if let Err(e) = write_save(save_file, start, &data[start..], Some(data.len())) {
+                    log::error!("Couldn't write save file {}: {}", save_file, e);
+                }
+            }
 
-            let mut bufwriter = BufWriter::new(save_f);
+            self.dirty = None;
+            self.clock_dirty = false;
+        }
+    }
 
-            let old_time = self.time;
-            self.time = Utc::now();
-            update_times(&self.time.signed_duration_since(old_time), &mut self.microseconds, &mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days);
+    fn save_data(&self) -> Option<Vec<u8>> {
+        Some(self.clock_save_data())
+    }
 
-            let time = [
-                self.seconds, self.minutes, self.hours,
-                self.days as u8,
-                (self.days >> 8) as u8
-            ];
+    // The clock stops at the time the save was made, or the Unix epoch without a save.
+    fn set_fixed_clock(&mut self) {
+        if !self.loaded_save {
+            self.time = Utc.timestamp_opt(0, 0).unwrap();
+        }
+        self.fixed_time = Some(self.time);
+    }
+}
 
-            bufwriter.write_all(&self.ram).expect("Couldn't write to file");
-            bufwriter.write(&time).expect("Couldn't write time to file");
-            bufwriter.write(&self.time.to_rfc3339().as_bytes()).expect("Couldn't write utc to file");
+impl ClockRAM {
+    fn now(&self) -> DateTime<Utc> {
+        self.fixed_time.unwrap_or_else(|| (self.clock)())
+    }
 
-            self.dirty = false;
+    // Bring the running clock registers up to the current time, unless halted.
+    fn update_clock(&mut self) {
+        let now = self.now();
+        if !self.halted {
+            update_times(&now.signed_duration_since(self.time), &mut self.microseconds, &mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days);
         }
+        self.time = now;
+    }
+
+    // Copy the running clock registers into the latched registers.
+    fn latch_clock(&mut self) {
+        self.latched = [
+            self.seconds, self.minutes, self.hours,
+            self.days as u8,
+            self.high_days()
+        ];
+    }
+
+    // DH register: day bit 8, halt flag and day carry.
+    fn high_days(&self) -> u8 {
+        let halt = if self.halted {bit!(6)} else {0};
+        (self.days >> 8) as u8 | halt
+    }
+
+    // RAM, followed by the clock data.
+    fn clock_save_data(&self) -> Vec<u8> {
+        let time = [
+            self.seconds, self.minutes, self.hours,
+            self.days as u8,
+            self.high_days()
+        ];
+
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&time);
+        data.extend_from_slice(&self.time.timestamp().to_le_bytes());
+        data
     }
 }
 
+// Read the time clock data was recorded at, from the first 8 bytes.
+// Anything after that is ignored: older versions could leave the end of a legacy save behind it.
+fn read_save_time(data: &[u8]) -> Result<DateTime<Utc>, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    let seconds = i64::from_le_bytes(bytes);
+    Utc.timestamp_opt(seconds, 0).single().ok_or_else(|| format!("Invalid save time: {}", seconds))
+}
+
+// Older saves store the time as an RFC 3339 string instead, which may be followed by zero padding.
+fn read_legacy_save_time(data: &[u8]) -> Option<DateTime<Utc>> {
+    let time_string = std::str::from_utf8(data).ok()?.trim_end_matches('\0');
+    DateTime::parse_from_rfc3339(time_string).ok().map(|time| time.with_timezone(&Utc))
+}
+
 // Read in a duration and update time registers.
 fn update_times(time_diff: &Duration, microseconds: &mut usize, seconds: &mut u8, minutes: &mut u8, hours: &mut u8, days: &mut u16) {
     let new_microseconds = (*microseconds as i64) + time_diff.num_microseconds().unwrap_or(0);
@@ -323,8 +466,136 @@ fn update_times(time_diff: &Duration, microseconds: &mut usize, seconds: &mut u8
     *seconds = (new_seconds % 60) as u8;
     *minutes = (new_minutes % 60) as u8;
     *hours = (new_hours % 24) as u8;
+    // The carry stays set until the game clears it.
+    let carry = (*days & 0x8000) != 0 || new_days > 511;
     *days = (new_days % 512) as u16;
-    if new_days > 511 {
+    if carry {
         *days |= 0x8000;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_clock_save() {
+        let save = vec![0xFF; 0x2000 + 64];
+        assert!(ClockRAM::new(0x2000, SaveType::Memory(Some(save))).is_err());
+    }
+
+    #[test]
+    fn fixed_clock_save() {
+        let mut clock_ram = ClockRAM::new(0x2000, SaveType::Memory(None)).unwrap();
+        clock_ram.set_fixed_clock();
+        clock_ram.set_bank(0x08, 0x4000);
+        clock_ram.write(0, 30);
+
+        let save = clock_ram.save_data().unwrap();
+        assert_eq!(save.len(), 0x2000 + CLOCK_SAVE_SIZE);
+        assert_eq!(&save[0x2000..], [30, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut clock_ram = ClockRAM::new(0x2000, SaveType::Memory(Some(save.clone()))).unwrap();
+        clock_ram.set_fixed_clock();
+        assert_eq!(clock_ram.save_data().unwrap(), save);
+    }
+
+    #[test]
+    fn clock_latch() {
+        use std::{cell::Cell, rc::Rc};
+
+        let seconds = Rc::new(Cell::new(1_000_000));
+        let clock_seconds = seconds.clone();
+        let clock = Box::new(move || Utc.timestamp_opt(clock_seconds.get(), 0).unwrap());
+        let mut clock_ram = ClockRAM::with_clock(0x2000, SaveType::Memory(None), clock).unwrap();
+        clock_ram.set_bank(0x08, 0x4000);
+
+        // The live clock advances, but reads stay at the latched value.
+        seconds.set(1_000_005);
+        clock_ram.update_clock();
+        assert_eq!(clock_ram.seconds, 5);
+        assert_eq!(clock_ram.read(0), 0);
+
+        // Writing 0 then 1 latches the current time.
+        clock_ram.set_bank(0, 0x6000);
+        clock_ram.set_bank(1, 0x6000);
+        assert_eq!(clock_ram.read(0), 5);
+
+        seconds.set(1_000_070);
+        assert_eq!(clock_ram.read(0), 5);
+        clock_ram.set_bank(0x09, 0x4000);
+        assert_eq!(clock_ram.read(0), 0);
+
+        // Writing 1 again without a 0 first doesn't latch.
+        clock_ram.set_bank(1, 0x6000);
+        assert_eq!(clock_ram.read(0), 0);
+
+        clock_ram.set_bank(0, 0x6000);
+        clock_ram.set_bank(1, 0x6000);
+        assert_eq!(clock_ram.read(0), 1);
+        clock_ram.set_bank(0x08, 0x4000);
+        assert_eq!(clock_ram.read(0), 10);
+    }
+
+    #[test]
+    fn legacy_clock_save() {
+        let mut save = vec![0; 0x2000];
+        save.extend_from_slice(&[1, 2, 3, 4, 0]);
+        save.extend_from_slice(b"1970-01-01T00:00:00+00:00");
+        save.extend_from_slice(&[0; 10]);
+
+        let mut clock_ram = ClockRAM::new(0x2000, SaveType::Memory(Some(save))).unwrap();
+        clock_ram.set_fixed_clock();
+        assert_eq!(&clock_ram.save_data().unwrap()[0x2000..], [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn legacy_clock_save_flush() {
+        let save_path = std::env::temp_dir().join(format!("rustboy-legacy-clock-{}.sav", std::process::id()));
+        let save_type = || SaveType::File(save_path.to_str().unwrap().to_string());
+        let saved_at = Utc.timestamp_opt(1_577_836_800, 0).unwrap();    // 2020-01-01T00:00:00Z
+        let clock = || Box::new(move || saved_at + Duration::seconds(10)) as ClockSource;
+
+        let mut save = vec![0; 0x2000];
+        save.extend_from_slice(&[1, 2, 3, 4, 0]);
+        save.extend_from_slice(b"2020-01-01T00:00:00+00:00");
+        std::fs::write(&save_path, &save).unwrap();
+
+        // The first flush rewrites the clock data in the new format, and removes the old string.
+        let mut clock_ram = ClockRAM::with_clock(0x2000, save_type(), clock()).unwrap();
+        clock_ram.flush();
+        assert_eq!(std::fs::metadata(&save_path).unwrap().len(), (0x2000 + CLOCK_SAVE_SIZE) as u64);
+
+        let clock_ram = ClockRAM::with_clock(0x2000, save_type(), clock()).unwrap();
+        let mut expected = vec![11, 2, 3, 4, 0];
+        expected.extend_from_slice(&(saved_at + Duration::seconds(10)).timestamp().to_le_bytes());
+        assert_eq!(&clock_ram.save_data().unwrap()[0x2000..], expected.as_slice());
+
+        // Saves written over a legacy save without truncating still load.
+        save.truncate(0x2000);
+        save.extend_from_slice(&expected);
+        save.extend_from_slice(b"0:00+00:00");
+        std::fs::write(&save_path, &save).unwrap();
+        let clock_ram = ClockRAM::with_clock(0x2000, save_type(), clock()).unwrap();
+        std::fs::remove_file(&save_path).unwrap();
+        assert_eq!(&clock_ram.save_data().unwrap()[0x2000..], expected.as_slice());
+    }
+
+    #[test]
+    fn partial_flush() {
+        let save_path = std::env::temp_dir().join(format!("rustboy-flush-{}.sav", std::process::id()));
+        std::fs::write(&save_path, vec![0xAA; 0x8000]).unwrap();
+        let mut battery_ram = BatteryRAM::new(0x8000, SaveType::File(save_path.to_str().unwrap().to_string())).unwrap();
+
+        // Change the file behind the RAM's back: only the flushed region should be overwritten.
+        std::fs::write(&save_path, vec![0x00; 0x8000]).unwrap();
+        battery_ram.set_bank(1, 0x4000);
+        battery_ram.write(0x0234, 0x77);
+        battery_ram.flush();
+
+        let save = std::fs::read(&save_path).unwrap();
+        std::fs::remove_file(save_path).unwrap();
+        assert_eq!(save[0x2234], 0x77);
+        assert!(save.iter().enumerate().all(|(i, &b)| i == 0x2234 || b == 0));
+    }
+}
@@ -11,9 +11,16 @@ use std::{
     fs::File
 };
 
+const BANK_SIZE: usize = 0x4000;
+
+#[allow(clippy::upper_case_acronyms)]
 pub trait ROM {
     fn read(&self, loc: u16) -> u8;
     fn set_bank(&mut self, bank: u16);
+    // Bank mapped at 0x0000-0x3FFF. This is only switchable on some mappers.
+    fn set_bank_0(&mut self, bank: u16);
+    // Number of 16KB banks in the image.
+    fn bank_count(&self) -> usize;
 }
 
 // A local file.
@@ -21,6 +28,8 @@ pub struct ROMFile {
     bank_0:         [u8; 0x4000],
     bank_cache:     HashMap<usize, Vec<u8>>,
     bank_offset:    usize,
+    bank_0_offset:  usize,
+    bank_count:     usize,
 
     file:           BufReader<File>,
 }
@@ -28,6 +37,7 @@ pub struct ROMFile {
 impl ROMFile {
     pub fn new(file_name: &str) -> Result<Box<Self>, String> {
         let f = File::open(file_name).map_err(|e| e.to_string())?;
+        let size = f.metadata().map_err(|e| e.to_string())?.len() as usize;
 
         let mut reader = BufReader::new(f);
         let mut buf = [0_u8; 0x4000];
@@ -38,6 +48,8 @@ impl ROMFile {
             bank_0:         buf,
             bank_cache:     HashMap::new(),
             bank_offset:    0,
+            bank_0_offset:  0,
+            bank_count:     size.div_ceil(BANK_SIZE),
             file:           reader,
         }))
     }
@@ -46,7 +58,8 @@ impl ROMFile {
 impl ROM for ROMFile {
     fn read(&self, loc: u16) -> u8 {
         match loc {
-            0x0..=0x3FFF    => self.bank_0[loc as usize],
+            0x0..=0x3FFF if self.bank_0_offset == 0 => self.bank_0[loc as usize],
+            0x0..=0x3FFF    => self.bank_cache.get(&self.bank_0_offset).expect("Bank not loaded!")[loc as usize],
             0x4000..=0x7FFF => self.bank_cache.get(&self.bank_offset).expect("Bank not loaded!")[(loc - 0x4000) as usize],
             _ => unreachable!()
         }
@@ -54,17 +67,33 @@ impl ROM for ROMFile {
 
     fn set_bank(&mut self, bank: u16) {
         self.bank_offset = (bank as usize) * 0x4000;
+        self.load_bank(self.bank_offset);
+    }
 
-        if self.bank_cache.get(&self.bank_offset).is_none() {
+    fn set_bank_0(&mut self, bank: u16) {
+        self.bank_0_offset = (bank as usize) * 0x4000;
+        if self.bank_0_offset != 0 {
+            self.load_bank(self.bank_0_offset);
+        }
+    }
+
+    fn bank_count(&self) -> usize {
+        self.bank_count
+    }
+}
+
+impl ROMFile {
+    fn load_bank(&mut self, offset: usize) {
+        if !self.bank_cache.contains_key(&offset) {
             let mut rom_bank = vec![0; 0x4000];
 
-            self.file.seek(SeekFrom::Start(self.bank_offset as u64))
+            self.file.seek(SeekFrom::Start(offset as u64))
                 .expect("Couldn't swap in bank");
 
             self.file.read_exact(&mut rom_bank)
-                .expect(&format!("Couldn't swap in bank at pos {}-{}", self.bank_offset, self.bank_offset + 0x3FFF));
+                .unwrap_or_else(|_| panic!("Couldn't swap in bank at pos {}-{}", offset, offset + 0x3FFF));
 
-            self.bank_cache.insert(self.bank_offset, rom_bank);
+            self.bank_cache.insert(offset, rom_bank);
         }
     }
 }
@@ -73,6 +102,7 @@ impl ROM for ROMFile {
 pub struct ROMData {
     data:           Vec<u8>,
     bank_offset:    usize,
+    bank_0_offset:  usize,
 }
 
 impl ROMData {
@@ -80,6 +110,7 @@ impl ROMData {
         Box::new(ROMData {
             data:           Vec::from(data),
             bank_offset:    0,
+            bank_0_offset:  0,
         })
     }
 }
@@ -87,7 +118,7 @@ impl ROMData {
 impl ROM for ROMData {
     fn read(&self, loc: u16) -> u8 {
         match loc {
-            0x0..=0x3FFF    => self.data[loc as usize],
+            0x0..=0x3FFF    => self.data[self.bank_0_offset + loc as usize],
             0x4000..=0x7FFF => self.data[self.bank_offset + (loc - 0x4000) as usize],
             _ => unreachable!()
         }
@@ -96,6 +127,14 @@ impl ROM for ROMData {
     fn set_bank(&mut self, bank: u16) {
         self.bank_offset = (bank as usize) * 0x4000;
     }
+
+    fn set_bank_0(&mut self, bank: u16) {
+        self.bank_0_offset = (bank as usize) * 0x4000;
+    }
+
+    fn bank_count(&self) -> usize {
+        self.data.len().div_ceil(BANK_SIZE)
+    }
 }
 
 // TODO: remote loading.
\ No newline at end of file
@@ -0,0 +1,445 @@
+// Loading ROMs from ZIP archives.
+// Supports stored and deflated entries, which covers archives made by common tools.
+use std::{
+    fs::File,
+    io::Read
+};
+
+use crate::utils::crc32;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034B50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014B50;
+const END_OF_DIR_SIG: u32 = 0x06054B50;
+const END_OF_DIR_SIZE: usize = 22;
+
+// The largest Game Boy ROM is 8MB. Anything bigger is rejected before decompressing.
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+// Check the file signature, so ZIPs are detected regardless of extension.
+pub fn is_zip_file(file_name: &str) -> bool {
+    let mut sig = [0_u8; 4];
+    File::open(file_name)
+        .and_then(|mut f| f.read_exact(&mut sig))
+        .map(|_| read_u32(&sig, 0) == Some(LOCAL_HEADER_SIG))
+        .unwrap_or(false)
+}
+
+// Extract the only .gb or .gbc file in the archive.
+pub fn load_rom(file_name: &str) -> Result<Vec<u8>, String> {
+    let data = std::fs::read(file_name).map_err(|e| e.to_string())?;
+    extract_rom(&data)
+}
+
+pub fn extract_rom(data: &[u8]) -> Result<Vec<u8>, String> {
+    let entries = read_directory(data)?;
+    let mut roms = entries.iter().filter(|e| is_rom_name(&e.name));
+
+    let entry = roms.next().ok_or("No .gb or .gbc file in archive")?;
+    if let Some(other) = roms.next() {
+        return Err(format!("Archive contains multiple ROMs: {} and {}", entry.name, other.name));
+    }
+
+    if entry.size > MAX_ROM_SIZE {
+        return Err(format!("{} is too big for a ROM: {} bytes", entry.name, entry.size));
+    }
+
+    let header = entry.local_offset;
+    if read_u32(data, header) != Some(LOCAL_HEADER_SIG) {
+        return Err("Invalid local file header".to_string());
+    }
+    let name_len = read_u16(data, header + 26).ok_or("Truncated archive")? as usize;
+    let extra_len = read_u16(data, header + 28).ok_or("Truncated archive")? as usize;
+    let start = header + 30 + name_len + extra_len;
+    let compressed = data.get(start..(start + entry.compressed_size)).ok_or("Truncated archive")?;
+
+    let rom = match entry.method {
+        METHOD_STORED   => compressed.to_vec(),
+        METHOD_DEFLATE  => inflate(compressed, entry.size)?,
+        m               => return Err(format!("Unsupported compression method {}", m)),
+    };
+
+    if rom.len() != entry.size || crc32(&rom) != entry.crc {
+        return Err(format!("{} is corrupt", entry.name));
+    }
+
+    Ok(rom)
+}
+
+struct Entry {
+    name:               String,
+    method:             u16,
+    crc:                u32,
+    compressed_size:    usize,
+    size:               usize,
+    local_offset:       usize,
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    lower.ends_with(".gb") || lower.ends_with(".gbc")
+}
+
+// Read the central directory, found from the record at the end of the file.
+fn read_directory(data: &[u8]) -> Result<Vec<Entry>, String> {
+    let end = (0..=data.len().saturating_sub(END_OF_DIR_SIZE)).rev()
+        .find(|&i| read_u32(data, i) == Some(END_OF_DIR_SIG))
+        .ok_or("Not a ZIP archive")?;
+
+    let count = read_u16(data, end + 10).ok_or("Truncated archive")? as usize;
+    let mut offset = read_u32(data, end + 16).ok_or("Truncated archive")? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if read_u32(data, offset) != Some(CENTRAL_HEADER_SIG) {
+            return Err("Invalid central directory".to_string());
+        }
+        let field = |at: usize| read_u32(data, offset + at).ok_or("Truncated archive");
+        let short_field = |at: usize| read_u16(data, offset + at).ok_or("Truncated archive");
+
+        let name_len = short_field(28)? as usize;
+        let extra_len = short_field(30)? as usize;
+        let comment_len = short_field(32)? as usize;
+        let name = data.get((offset + 46)..(offset + 46 + name_len)).ok_or("Truncated archive")?;
+
+        entries.push(Entry {
+            name:               String::from_utf8_lossy(name).into_owned(),
+            method:             short_field(10)?,
+            crc:                field(16)?,
+            compressed_size:    field(20)? as usize,
+            size:               field(24)? as usize,
+            local_offset:       field(42)? as usize,
+        });
+
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..(at + 2)).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..(at + 4)).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// Deflate decompression (RFC 1951).
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order that code length code lengths are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Decompress data that should be size bytes long. Decompression stops if the output grows past this,
+// as the size comes from the archive and can't be trusted.
+fn inflate(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut bits = BitReader::new(data);
+    let mut out = Vec::with_capacity(size.min(MAX_ROM_SIZE));
+
+    loop {
+        let last = bits.read(1)? == 1;
+        match bits.read(2)? {
+            0 => {
+                bits.align();
+                let len = bits.read(16)?;
+                let nlen = bits.read(16)?;
+                if len != !nlen & 0xFFFF {
+                    return Err("Invalid stored block".to_string());
+                }
+                if out.len() + (len as usize) > size {
+                    return Err("Decompressed data is too long".to_string());
+                }
+                for _ in 0..len {
+                    out.push(bits.read(8)? as u8);
+                }
+            },
+            1 => {
+                let (lit, dist) = fixed_codes();
+                inflate_block(&mut bits, &mut out, size, &lit, &dist)?;
+            },
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, size, &lit, &dist)?;
+            },
+            _ => return Err("Invalid block type".to_string()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn inflate_block(bits: &mut BitReader, out: &mut Vec<u8>, size: usize, lit: &Huffman, dist: &Huffman) -> Result<(), String> {
+    loop {
+        if out.len() > size {
+            return Err("Decompressed data is too long".to_string());
+        }
+        let symbol = lit.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let len_code = symbol - 257;
+                if len_code >= LENGTH_BASE.len() {
+                    return Err("Invalid length code".to_string());
+                }
+                let len = (LENGTH_BASE[len_code] as usize) + (bits.read(LENGTH_EXTRA[len_code])? as usize);
+
+                let dist_code = dist.decode(bits)? as usize;
+                if dist_code >= DIST_BASE.len() {
+                    return Err("Invalid distance code".to_string());
+                }
+                let distance = (DIST_BASE[dist_code] as usize) + (bits.read(DIST_EXTRA[dist_code])? as usize);
+                if distance > out.len() {
+                    return Err("Distance too far back".to_string());
+                }
+
+                // Copies can overlap the bytes being written.
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0_u8; 288];
+    lengths[0..144].iter_mut().for_each(|l| *l = 8);
+    lengths[144..256].iter_mut().for_each(|l| *l = 9);
+    lengths[256..280].iter_mut().for_each(|l| *l = 7);
+    lengths[280..288].iter_mut().for_each(|l| *l = 8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let lit_count = (bits.read(5)? as usize) + 257;
+    let dist_count = (bits.read(5)? as usize) + 1;
+    let code_count = (bits.read(4)? as usize) + 4;
+
+    let mut code_lengths = [0_u8; 19];
+    for &i in CODE_LENGTH_ORDER.iter().take(code_count) {
+        code_lengths[i] = bits.read(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(lit_count + dist_count);
+    while lengths.len() < lit_count + dist_count {
+        let (len, repeat) = match code_lengths.decode(bits)? {
+            l @ 0..=15 => (l as u8, 1),
+            16 => (*lengths.last().ok_or("Repeat with no previous length")?, 3 + bits.read(2)?),
+            17 => (0, 3 + bits.read(3)?),
+            18 => (0, 11 + bits.read(7)?),
+            _ => return Err("Invalid code length".to_string()),
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() > lit_count + dist_count {
+        return Err("Too many code lengths".to_string());
+    }
+
+    Ok((Huffman::new(&lengths[..lit_count]), Huffman::new(&lengths[lit_count..])))
+}
+
+// Canonical Huffman code, stored as the number of codes of each length
+// and the symbols ordered by code.
+struct Huffman {
+    counts:     [u16; MAX_BITS + 1],
+    symbols:    Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0_u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0_u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Huffman {
+            counts:     counts,
+            symbols:    symbols,
+        }
+    }
+
+    // Read a bit at a time until the code matches one of the given length.
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0_i32;
+        let mut first = 0_i32;
+        let mut index = 0_i32;
+        for len in 1..=MAX_BITS {
+            code |= bits.read(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Invalid Huffman code".to_string())
+    }
+}
+
+// Reads bits from the least significant end of each byte.
+struct BitReader<'a> {
+    data:       &'a [u8],
+    pos:        usize,
+    bit:        u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data:   data,
+            pos:    0,
+            bit:    0,
+        }
+    }
+
+    fn read(&mut self, count: u8) -> Result<u32, String> {
+        let mut val = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or("Unexpected end of compressed data")?;
+            val |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(val)
+    }
+
+    // Skip to the next byte boundary.
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    // testutil::rom(&[]) as a dynamic Huffman block.
+    const DYNAMIC_ROM: [u8; 58] = [
+        0xED, 0xDB, 0x41, 0x01, 0x00, 0x00, 0x04, 0x04, 0xB0, 0xD3, 0x50, 0x67, 0xC5, 0xC4, 0xF0, 0xB0,
+        0xF5, 0x58, 0xF2, 0xDB, 0x74, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0xAE, 0xD9, 0xEF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x70, 0x6F, 0x01
+    ];
+
+    // Build an archive with each entry: (name, method, uncompressed data, stored data).
+    fn zip(entries: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, contents, stored) in entries {
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);  // Time and date
+            fields.extend_from_slice(&crc32(contents).to_le_bytes());
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);  // Extra field length
+
+            directory.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);  // Comment length, disk, attributes
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            data.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0]);
+            data.extend_from_slice(&fields);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(stored);
+        }
+
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&END_OF_DIR_SIG.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    #[test]
+    fn stored_block() {
+        let mut data = vec![0x01, 0x05, 0x00, 0xFA, 0xFF];
+        data.extend_from_slice(b"Hello");
+        assert_eq!(inflate(&data, 5).unwrap(), b"Hello");
+
+        data[3] = 0x00;
+        assert!(inflate(&data, 5).is_err());
+    }
+
+    #[test]
+    fn fixed_huffman_block() {
+        // Uses a back-reference for the repeated text.
+        let data = [0xF3, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0x70, 0x4F, 0xCC, 0x4D, 0x55, 0x70, 0xCA, 0xAF, 0x54, 0x54, 0xF0, 0x40, 0x13, 0x00, 0x00];
+        assert_eq!(inflate(&data, 33).unwrap(), b"Hello, Game Boy! Hello, Game Boy!");
+        assert!(inflate(&data[..10], 33).is_err());
+    }
+
+    #[test]
+    fn dynamic_huffman_block() {
+        assert_eq!(inflate(&DYNAMIC_ROM, 0x8000).unwrap(), testutil::rom(&[]));
+    }
+
+    #[test]
+    fn oversized_output() {
+        // The archive says the data is smaller than it really is.
+        assert!(inflate(&DYNAMIC_ROM, 0x100).is_err());
+    }
+
+    #[test]
+    fn load_zip() {
+        let rom = testutil::rom(&[]);
+        let archive = zip(&[
+            ("readme.txt", METHOD_STORED, b"Read me", b"Read me"),
+            ("Game.GB", METHOD_DEFLATE, &rom, &DYNAMIC_ROM)
+        ]);
+        assert_eq!(extract_rom(&archive).unwrap(), rom);
+
+        let stored = zip(&[("game.gbc", METHOD_STORED, &rom, &rom)]);
+        assert_eq!(extract_rom(&stored).unwrap(), rom);
+
+        let mut corrupt = rom.clone();
+        corrupt[0x4000] = 2;
+        assert!(extract_rom(&zip(&[("game.gb", METHOD_STORED, &rom, &corrupt)])).is_err());
+        assert!(extract_rom(&zip(&[("a.gb", METHOD_STORED, &rom, &rom), ("b.gb", METHOD_STORED, &rom, &rom)])).is_err());
+        assert!(extract_rom(&zip(&[("readme.txt", METHOD_STORED, b"Read me", b"Read me")])).is_err());
+    }
+}
@@ -4,13 +4,80 @@ mod bus;
 mod cartridge;
 
 pub use bus::MemBus;
-pub use cartridge::ROMType;
+pub use cartridge::{
+    BankState,
+    CartFeatureSet,
+    DisabledRamRead,
+    ROMType,
+    SaveType
+};
+
+use std::{
+    cell::RefCell,
+    rc::Rc
+};
 
 pub trait MemDevice {
     fn read(&self, loc: u16) -> u8;
     fn write(&mut self, loc: u16, val: u8);
 }
 
+// Custom hardware registers, mapped into unused IO space.
+pub trait IoHandler {
+    fn read(&self, loc: u16) -> u8;
+    fn write(&mut self, loc: u16, val: u8);
+}
+
+// CGB infrared port.
+pub trait IrTransceiver {
+    // Called when the game turns its LED on or off.
+    fn set_light(&mut self, on: bool);
+    // Return true if light is being received.
+    fn receiving(&self) -> bool;
+}
+
+// Link cable. Bytes are exchanged when this Game Boy drives the transfer clock.
+pub trait SerialPort {
+    // Called with the byte shifted out at the end of a transfer.
+    fn send_byte(&mut self, b: u8);
+    // Return the byte shifted in, or None if nothing is connected.
+    fn recv_byte(&mut self) -> Option<u8>;
+}
+
+// With no cable connected, the input line is pulled high.
+pub struct NullSerialPort;
+
+impl SerialPort for NullSerialPort {
+    fn send_byte(&mut self, _b: u8) {}
+
+    fn recv_byte(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// Records bytes sent over the link cable, e.g. test ROM output.
+pub struct CaptureSerialPort {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureSerialPort {
+    pub fn new(output: Rc<RefCell<Vec<u8>>>) -> Self {
+        CaptureSerialPort {
+            output: output,
+        }
+    }
+}
+
+impl SerialPort for CaptureSerialPort {
+    fn send_byte(&mut self, b: u8) {
+        self.output.borrow_mut().push(b);
+    }
+
+    fn recv_byte(&mut self) -> Option<u8> {
+        None
+    }
+}
+
 pub struct WriteableMem {
     mem: Vec<u8>,
 }
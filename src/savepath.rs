@@ -0,0 +1,80 @@
+// Choosing where to store battery-backed save RAM for a ROM file.
+use std::{
+    fs::File,
+    io::Read,
+    path::Path
+};
+
+const HEADER_SIZE: usize = 0x150;
+
+pub enum SaveNaming {
+    SameDir,            // Next to the ROM, with the extension replaced.
+    ByTitle(String),    // In the provided directory, named after the cart title.
+    ByHash(String),     // In the provided directory, named after the cart title and global checksum.
+}
+
+pub fn save_path(rom_path: &str, naming: SaveNaming) -> Result<String, String> {
+    let path = match naming {
+        SaveNaming::SameDir => Path::new(rom_path).with_extension("sav"),
+        SaveNaming::ByTitle(dir) => {
+            let header = read_header(rom_path)?;
+            Path::new(&dir).join(format!("{}.sav", title(&header)))
+        },
+        SaveNaming::ByHash(dir) => {
+            let header = read_header(rom_path)?;
+            let checksum = make_16!(header[0x14E], header[0x14F]);
+            Path::new(&dir).join(format!("{}-{:04X}.sav", title(&header), checksum))
+        },
+    };
+
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn read_header(rom_path: &str) -> Result<Vec<u8>, String> {
+    let mut header = vec![0; HEADER_SIZE];
+    let mut rom_file = File::open(rom_path).map_err(|e| e.to_string())?;
+    rom_file.read_exact(&mut header).map_err(|e| e.to_string())?;
+    Ok(header)
+}
+
+// Cart title, with any characters that aren't safe in file names replaced.
+fn title(header: &[u8]) -> String {
+    let title_end = if header[0x14B] == 0x33 {0x13F} else {0x144};
+    let name = header[0x134..title_end].iter()
+        .take_while(|c| **c != 0)
+        .map(|c| if c.is_ascii_alphanumeric() {*c as char} else {'_'})
+        .collect::<String>();
+
+    if name.is_empty() {
+        "UNTITLED".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn naming_strategies() {
+        let mut rom = testutil::rom(&[]);
+        rom[0x134..0x13D].copy_from_slice(b"TEST GAME");
+        rom[0x14E] = 0xAB;
+        rom[0x14F] = 0xCD;
+        let rom_path = std::env::temp_dir().join(format!("rustboy-savepath-{}.gb", std::process::id()));
+        std::fs::write(&rom_path, &rom).unwrap();
+        let rom_path = rom_path.to_str().unwrap();
+
+        let same_dir = save_path(rom_path, SaveNaming::SameDir);
+        let by_title = save_path(rom_path, SaveNaming::ByTitle("saves".to_string()));
+        let by_hash = save_path(rom_path, SaveNaming::ByHash("saves".to_string()));
+        std::fs::remove_file(rom_path).unwrap();
+
+        assert_eq!(same_dir.unwrap(), rom_path.replace(".gb", ".sav"));
+        assert_eq!(by_title.unwrap(), Path::new("saves").join("TEST_GAME.sav").to_str().unwrap());
+        assert_eq!(by_hash.unwrap(), Path::new("saves").join("TEST_GAME-ABCD.sav").to_str().unwrap());
+        assert!(save_path("/nonexistent/rom.gb", SaveNaming::ByTitle("saves".to_string())).is_err());
+    }
+}
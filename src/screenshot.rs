@@ -0,0 +1,126 @@
+// PNG encoding for screenshots.
+// The image data is stored uncompressed, so no compression library is needed.
+use crate::{
+    utils::crc32,
+    SCREEN_WIDTH,
+    SCREEN_HEIGHT
+};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// Encode an RGBA frame as a PNG. Alpha is forced to opaque.
+pub fn encode_png(frame: &[u8]) -> Vec<u8> {
+    let mut png = PNG_SIGNATURE.to_vec();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(SCREEN_HEIGHT as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);    // 8-bit RGBA, no interlacing.
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Each row starts with a filter type (0: none).
+    let mut image = Vec::with_capacity(SCREEN_HEIGHT * (SCREEN_WIDTH * 4 + 1));
+    for row in frame.chunks(SCREEN_WIDTH * 4).take(SCREEN_HEIGHT) {
+        image.push(0);
+        for pixel in row.chunks(4) {
+            image.extend_from_slice(&pixel[0..3]);
+            image.push(255);
+        }
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&image));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wrap data in a zlib stream of uncompressed deflate blocks. The data must not be empty.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK);
+    for (i, block) in data.chunks(MAX_STORED_BLOCK).enumerate() {
+        let last = if i == blocks - 1 {1} else {0};
+        let len = block.len() as u16;
+        out.push(last);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1_u32, 0_u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn decode_png() {
+        let frame = (0..(SCREEN_WIDTH * SCREEN_HEIGHT * 4)).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let png = encode_png(&frame);
+        assert_eq!(png[0..8], PNG_SIGNATURE);
+
+        // Split into chunks, checking each CRC.
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let crc = u32::from_be_bytes([rest[len + 8], rest[len + 9], rest[len + 10], rest[len + 11]]);
+            assert_eq!(crc32(&rest[4..(len + 8)]), crc);
+            chunks.push((&rest[4..8], &rest[8..(len + 8)]));
+            rest = &rest[(len + 12)..];
+        }
+        assert_eq!(chunks.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(png[(png.len() - 4)..], [0xAE, 0x42, 0x60, 0x82]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 160, 0, 0, 0, 144, 8, 6, 0, 0, 0]);
+
+        // Unpack the stored deflate blocks.
+        let zlib = chunks[1].1;
+        assert_eq!(((zlib[0] as u16) << 8 | zlib[1] as u16) % 31, 0);
+        let mut image = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1;
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]);
+            assert_eq!(!len, u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]));
+            image.extend_from_slice(&zlib[(pos + 5)..(pos + 5 + len as usize)]);
+            pos += 5 + len as usize;
+            if last == 1 {
+                break;
+            }
+        }
+        assert_eq!(zlib[pos..], adler32(&image).to_be_bytes());
+
+        // Each row is the filter type, then the pixels with alpha set.
+        for (row, pixels) in image.chunks(SCREEN_WIDTH * 4 + 1).zip(frame.chunks(SCREEN_WIDTH * 4)) {
+            assert_eq!(row[0], 0);
+            for (out, pixel) in row[1..].chunks(4).zip(pixels.chunks(4)) {
+                assert_eq!(out, [pixel[0], pixel[1], pixel[2], 255]);
+            }
+        }
+        assert_eq!(image.len(), SCREEN_HEIGHT * (SCREEN_WIDTH * 4 + 1));
+    }
+}
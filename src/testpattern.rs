@@ -0,0 +1,62 @@
+// Synthetic frames, for testing front-ends without a ROM.
+use crate::{
+    SCREEN_WIDTH,
+    SCREEN_HEIGHT
+};
+
+const CHECKER_SIZE: usize = 8;
+
+// Test patterns, shown in this order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TestPattern {
+    ColourBars,     // 8 vertical bars: white, yellow, cyan, green, magenta, red, blue, black.
+    Checkerboard,   // 8x8 black and white squares, starting with white in the top left.
+    Gradient,       // Horizontal greyscale ramp, from black on the left to white on the right.
+}
+
+const PATTERNS: [TestPattern; 3] = [
+    TestPattern::ColourBars,
+    TestPattern::Checkerboard,
+    TestPattern::Gradient
+];
+
+const BAR_COLOURS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+// The pattern shown for a frame number.
+pub fn pattern_for_frame(frame_count: usize) -> TestPattern {
+    PATTERNS[frame_count % PATTERNS.len()]
+}
+
+// Draw a pattern into an RGBA frame.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn draw(pattern: TestPattern, target: &mut [u8]) {
+    for (i, pixel) in target.chunks_mut(4).take(SCREEN_WIDTH * SCREEN_HEIGHT).enumerate() {
+        let x = i % SCREEN_WIDTH;
+        let y = i / SCREEN_WIDTH;
+
+        let colour = match pattern {
+            TestPattern::ColourBars => BAR_COLOURS[x * BAR_COLOURS.len() / SCREEN_WIDTH],
+            TestPattern::Checkerboard => if ((x / CHECKER_SIZE) + (y / CHECKER_SIZE)) % 2 == 0 {
+                [255, 255, 255]
+            } else {
+                [0, 0, 0]
+            },
+            TestPattern::Gradient => {
+                let level = (x * 255 / (SCREEN_WIDTH - 1)) as u8;
+                [level, level, level]
+            }
+        };
+
+        pixel[0..3].copy_from_slice(&colour);
+        pixel[3] = 255;
+    }
+}
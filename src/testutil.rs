@@ -0,0 +1,72 @@
+// Test ROMs and emulators built from them.
+
+use std::sync::{Mutex, Once};
+
+use crate::{
+    mem::{MemBus, ROMType, SaveType},
+    video::UserPalette
+};
+
+// Where test code is placed. The entry point jumps here, past the header.
+pub const CODE_START: u16 = 0x150;
+
+// A 32KB ROM with no mapper, running the code.
+pub fn rom(code: &[u8]) -> Vec<u8> {
+    cart_rom(0x00, 2, 0x00, code)
+}
+
+// A ROM with the cart type and RAM size bytes set in the header.
+// The first byte of each bank from 1 up is the bank number, to check which bank is mapped.
+pub fn cart_rom(cart_type: u8, banks: usize, ram_size: u8, code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; banks * 0x4000];
+    for bank in 1..banks {
+        rom[bank * 0x4000] = bank as u8;
+    }
+
+    rom[0x100..0x103].copy_from_slice(&[0xC3, lo_16!(CODE_START), hi_16!(CODE_START)]);   // JP CODE_START
+    rom[0x147] = cart_type;
+    rom[0x149] = ram_size;
+
+    let start = CODE_START as usize;
+    rom[start..(start + code.len())].copy_from_slice(code);
+    rom
+}
+
+// Memory for a DMG, or a CGB if the ROM is a CGB game.
+pub fn mem(rom: Vec<u8>) -> MemBus {
+    let palette = if rom[0x143] == 0x80 {UserPalette::Default} else {UserPalette::Greyscale};
+    MemBus::new(ROMType::Data(rom), SaveType::Memory(None), palette)
+}
+
+// Records error messages from every test in the binary. The logger can only be set once.
+struct ErrorLog(Mutex<Vec<String>>);
+
+impl log::Log for ErrorLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() == log::Level::Error
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static ERROR_LOG: ErrorLog = ErrorLog(Mutex::new(Vec::new()));
+static SET_LOGGER: Once = Once::new();
+
+// Start recording error messages, if not already.
+pub fn capture_errors() {
+    SET_LOGGER.call_once(|| {
+        log::set_logger(&ERROR_LOG).expect("Another logger was set");
+        log::set_max_level(log::LevelFilter::Error);
+    });
+}
+
+// Check if an error was logged since capture_errors was first called, by any test.
+pub fn error_logged(message: &str) -> bool {
+    ERROR_LOG.0.lock().unwrap().iter().any(|msg| msg == message)
+}
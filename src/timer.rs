@@ -23,10 +23,12 @@ impl Timer {
         }
     }
 
+    // DIV is the high byte of the internal 16-bit divider.
     pub fn read(&self, loc: u16) -> u8 {
         match loc {
             0xFF03 => (self.divider & 0xFF) as u8,
             0xFF04 => (self.divider >> 8) as u8,
+            // For the cycle between overflow and reload, TIMA reads as 0x00.
             0xFF05 => self.timer_counter,
             0xFF06 => self.timer_modulo,
             0xFF07 => {
@@ -44,18 +46,29 @@ impl Timer {
             0xFF05 => self.timer_counter = val,
             0xFF06 => self.timer_modulo = val,
             0xFF07 => {
+                // The timer increments on a falling edge of the selected divider bit.
+                // So disabling it or changing the clock can cause an extra increment.
+                let old_signal = self.timer_signal();
                 self.timer_enable = test_bit!(val, 2);
                 self.clock_select = val & 0b11;
+                if old_signal && !self.timer_signal() {
+                    self.increment();
+                }
             },
             _ => {},
         }
     }
 
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
+    }
+
     // Call this every cycle. Returns true if an interrupt is triggered (after 1 cycle delay).
+    #[allow(clippy::needless_return)]
     pub fn update(&mut self, cycles: u32) -> bool {
         let trigger = self.trigger;
 
-        self.divider = (self.divider as u32 + cycles) as u16;    // TODO: check this is ok for CGB.
+        self.divider = (self.divider as u32 + cycles) as u16;
 
         if self.timer_enable {
             let inc = match self.clock_select {
@@ -66,10 +79,7 @@ impl Timer {
                 _ => false,
             };
             if inc {
-                self.timer_counter = self.timer_counter.wrapping_add(1);
-                if self.timer_counter == 0 {
-                    self.trigger = true;
-                }
+                self.increment();
             }
         }
 
@@ -80,4 +90,66 @@ impl Timer {
 
         return trigger;
     }
+
+    // The divider bit selected as the timer clock, if the timer is enabled.
+    fn timer_signal(&self) -> bool {
+        let bit = match self.clock_select {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.timer_enable && ((self.divider >> bit) & 1) != 0
+    }
+
+    fn increment(&mut self) {
+        self.timer_counter = self.timer_counter.wrapping_add(1);
+        if self.timer_counter == 0 {
+            self.trigger = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_reload() {
+        let mut timer = Timer::new();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF07, 0x05);  // Increment every 16 cycles.
+
+        for _ in 0..3 {
+            assert!(!timer.update(4));
+            assert_eq!(timer.read(0xFF05), 0xFF);
+        }
+        // Overflow: TIMA reads 0 for a machine cycle, then TMA is loaded and the interrupt fires.
+        assert!(!timer.update(4));
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(timer.update(4));
+        assert_eq!(timer.read(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn tac_disable_glitch() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);  // Clocked by divider bit 3.
+
+        // Bit 3 is low: disabling doesn't increment.
+        timer.update(4);
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 0x00);
+
+        // Bit 3 is high: disabling is a falling edge, so TIMA increments.
+        timer.write(0xFF07, 0x05);
+        timer.update(4);
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 0x01);
+
+        // Nothing happens while disabled.
+        timer.update(8);
+        assert_eq!(timer.read(0xFF05), 0x01);
+    }
 }
@@ -33,4 +33,38 @@ macro_rules! lo_16 {
     ($val:expr) => {
         $val as u8
     };
-}
\ No newline at end of file
+}
+
+// FNV-1a hash. Unlike the std hasher, the output is the same across platforms and Rust versions,
+// as long as values are written as explicit bytes.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+impl std::hash::Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ (*byte as u64)).wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// CRC-32 as used by PNG and ZIP.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFF_FFFF_u32, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u32), |c, _| if (c & 1) != 0 {
+            (c >> 1) ^ 0xEDB8_8320
+        } else {
+            c >> 1
+        })
+    })
+}
+
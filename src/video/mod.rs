@@ -5,7 +5,6 @@ mod regs;
 #[cfg(feature = "threads")]
 mod renderer_threads;
 
-#[cfg(not(feature = "threads"))]
 mod renderer_nothreads;
 
 pub mod sgbpalettes;
@@ -28,22 +27,32 @@ use regs::VideoRegs;
 
 pub use types::{
     Colour,
+    ColourCorrection,
     PaletteColours
 };
 
 use vram::VRAM;
 
 #[cfg(feature = "threads")]
-use renderer_threads::*;
+use renderer_threads::ThreadedRenderer;
 
-#[cfg(not(feature = "threads"))]
-use renderer_nothreads::*;
+use renderer_nothreads::InlineRenderer;
 
 use std::sync::{
     Arc,
     Mutex
 };
 
+pub type RenderTarget = Arc<Mutex<[u8]>>;
+
+// Draws lines into the frame.
+trait Renderer {
+    fn start_frame(&mut self, target: RenderTarget);
+    fn draw_line_gb(&mut self, regs: VideoRegs);
+    fn draw_line_cgb(&mut self, regs: VideoRegs);
+    fn clear_line(&mut self, regs: VideoRegs);
+}
+
 pub use sgbpalettes::UserPalette;
 
 // Modes
@@ -70,7 +79,7 @@ pub struct VideoDevice {
     vram:           Arc<Mutex<VRAM>>,
     regs:           VideoRegs,
 
-    renderer:       Renderer,
+    renderer:       Box<dyn Renderer>,
 
     // CGB things
     cgb_mode:       bool,
@@ -78,6 +87,11 @@ pub struct VideoDevice {
 
     // Misc
     cycle_count:    u32,
+
+    // After the LCD is switched on, the first line has no OAM scan, and the first frame is blank.
+    lcd_on_quirk:   bool,
+    first_line:     bool,
+    skip_frame:     bool,
 }
 
 impl VideoDevice {
@@ -85,7 +99,10 @@ impl VideoDevice {
         let vram = Arc::new(Mutex::new(VRAM::new(palette, cgb_mode)));
 
         // Spin off video thread.
-        let renderer = Renderer::new(vram.clone());
+        #[cfg(feature = "threads")]
+        let renderer: Box<dyn Renderer> = Box::new(ThreadedRenderer::new(vram.clone()));
+        #[cfg(not(feature = "threads"))]
+        let renderer: Box<dyn Renderer> = Box::new(InlineRenderer::new(vram.clone()));
 
         VideoDevice {
             vram:           vram,
@@ -99,6 +116,10 @@ impl VideoDevice {
 
             // Misc
             cycle_count:    0,
+
+            lcd_on_quirk:   true,
+            first_line:     false,
+            skip_frame:     false,
         }
     }
 
@@ -107,6 +128,65 @@ impl VideoDevice {
         self.renderer.start_frame(render_target);
     }
 
+    pub fn set_lcd_on_quirk(&mut self, enabled: bool) {
+        self.lcd_on_quirk = enabled;
+    }
+
+    // Draw on the emulation thread, even if the threads feature is enabled.
+    // Call before the frame is started.
+    pub fn disable_render_thread(&mut self) {
+        self.renderer = Box::new(InlineRenderer::new(self.vram.clone()));
+    }
+
+    // Render the full background map as 256x256 RGBA.
+    pub fn dump_background(&self) -> Vec<u8> {
+        let mut target = vec![255; 256 * 256 * 4];
+        self.vram.lock().unwrap().draw_background(&mut target, &self.regs, self.cgb_mode);
+        target
+    }
+
+    // Raw tile data (0x8000-0x97FF) from a VRAM bank. Empty if the bank doesn't exist.
+    pub fn dump_tiles(&self, bank: u8) -> Vec<u8> {
+        if bank > if self.cgb_mode {1} else {0} {
+            return Vec::new();
+        }
+
+        let vram = self.vram.lock().unwrap();
+        let offset = bank as usize * 0x1800;
+        (offset..(offset + 0x1800)).map(|base| if base % 2 == 0 {
+            vram.tile_mem.get_pixel_lower_row(base)
+        } else {
+            vram.tile_mem.get_pixel_upper_row(base)
+        }).collect()
+    }
+
+    // Both tile maps (0x9800-0x9FFF) from a VRAM bank. In bank 1 these are the CGB tile attributes.
+    // Empty if the bank doesn't exist.
+    pub fn dump_tilemap(&self, bank: u8) -> Vec<u8> {
+        let vram = self.vram.lock().unwrap();
+        match bank {
+            0 => [vram.tile_map_0.as_slice(), vram.tile_map_1.as_slice()].concat(),
+            1 if self.cgb_mode => [vram.tile_attrs_0.as_slice(), vram.tile_attrs_1.as_slice()].concat(),
+            _ => Vec::new()
+        }
+    }
+
+    pub fn set_smooth_scroll(&mut self, enabled: bool) {
+        self.vram.lock().unwrap().smooth_scroll = enabled;
+    }
+
+    pub fn sprite_overflow_lines(&self) -> Vec<u8> {
+        self.vram.lock().unwrap().sprite_overflow.clone()
+    }
+
+    pub fn set_sprite_debug(&mut self, enabled: bool) {
+        self.vram.lock().unwrap().sprite_debug = enabled;
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.vram.lock().unwrap().colour_palettes.set_colour_correction(correction);
+    }
+
     // Query to see if the video device is in H-Blank.
     pub fn is_in_hblank(&self) -> bool {
         self.regs.read_mode() == Mode::_0
@@ -126,9 +206,16 @@ impl VideoDevice {
             let mode = self.regs.read_mode();
 
             let int = match mode {
+                Mode::_0 if self.first_line => if line_cycle >= MODE_2 {
+                    self.first_line = false;
+                    self.update_mode(Mode::_3)
+                } else {
+                    InterruptFlags::default()
+                },
                 Mode::_2 if line_cycle >= MODE_2 => self.update_mode(Mode::_3),
                 Mode::_3 if line_cycle >= MODE_3 => self.update_mode(Mode::_0),
                 Mode::_0 if self.get_cycle_count() >= FRAME_CYCLE => {
+                    self.skip_frame = false;
                     self.regs.inc_lcdc_y();
                     self.update_mode(Mode::_1) | InterruptFlags::V_BLANK
                 },
@@ -139,6 +226,7 @@ impl VideoDevice {
                 Mode::_1 => if self.get_cycle_count() >= MODE_1 {
                     //self.renderer.end_frame();
                     self.regs.set_lcdc_y(0);
+                    self.regs.reset_window_line();
                     self.frame_cycle_reset();
                     self.update_mode(Mode::_2)
                 } else {
@@ -149,12 +237,17 @@ impl VideoDevice {
                 _ => InterruptFlags::default(),
             };
 
-            (if int.contains(InterruptFlags::V_BLANK) {
-                true
+            // LY=LYC is checked whenever LY or LYC changes, separately from the mode interrupts.
+            let int = if self.regs.take_coincidence_int() {
+                int | InterruptFlags::LCD_STAT
             } else {
-                false
-            }, int)
+                int
+            };
+
+            (int.contains(InterruptFlags::V_BLANK), int)
         } else {
+            // STAT interrupts can't fire while the LCD is off.
+            self.regs.take_coincidence_int();
             let keep_cycling = if self.get_cycle_count() > MODE_1 {
                 self.frame_cycle_reset();
                 true
@@ -174,36 +267,28 @@ impl VideoDevice {
         let stat_flags = self.regs.read_flags();
 
         if mode == Mode::_3 {
-            if self.cgb_mode {
+            if self.skip_frame {
+                self.renderer.clear_line(self.regs.clone());
+            } else if self.cgb_mode {
                 self.renderer.draw_line_cgb(self.regs.clone());
             } else {
                 self.renderer.draw_line_gb(self.regs.clone());
             }
+            self.regs.inc_window_line(self.cgb_mode);
         }
 
-        // Trigger STAT interrupt
-        if !stat_flags.is_empty() {
-            // LY Coincidence interrupt
-            if stat_flags.contains(LCDStatusFlags::COINCIDENCE_INT) {
-                if self.regs.compare_ly_equal() {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::OAM_INT) {
-                if mode == Mode::_2 {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::V_BLANK_INT) {
-                if mode == Mode::_1 {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::H_BLANK_INT) {
-                if mode == Mode::_0 {
-                    return InterruptFlags::LCD_STAT;
-                }
-            }
+        // Trigger STAT interrupt for the new mode. Each source is enabled separately.
+        let mode_int = match mode {
+            Mode::_0 => LCDStatusFlags::H_BLANK_INT,
+            Mode::_1 => LCDStatusFlags::V_BLANK_INT,
+            Mode::_2 => LCDStatusFlags::OAM_INT,
+            Mode::_3 => LCDStatusFlags::empty(),
+        };
+        if stat_flags.intersects(mode_int) {
+            InterruptFlags::LCD_STAT
+        } else {
+            InterruptFlags::default()
         }
-
-        InterruptFlags::default()
     }
 }
 
@@ -222,6 +307,7 @@ impl VideoDevice {
 }
 
 impl MemDevice for VideoDevice {
+    #[allow(clippy::manual_is_multiple_of)]
     fn read(&self, loc: u16) -> u8 {
         match loc {
             // Raw tile data
@@ -254,6 +340,13 @@ impl MemDevice for VideoDevice {
             },
             // Sprite data
             0xFE00..=0xFE9F if self.regs.can_access_oam() => self.vram.lock().unwrap().object_mem.read(loc - 0xFE00),
+            // Unusable region: DMG reads 0, CGB repeats the upper nibble of the address.
+            0xFEA0..=0xFEFF if self.regs.can_access_oam() => if self.cgb_mode {
+                let nibble = lo_16!(loc) & 0xF0;
+                nibble | (nibble >> 4)
+            } else {
+                0
+            },
             // Registers
             0xFF40 => self.regs.read_lcd_control(),
             0xFF41 => self.regs.read_status(),
@@ -267,15 +360,16 @@ impl MemDevice for VideoDevice {
             0xFF4A => self.regs.window_y,
             0xFF4B => self.regs.window_x,
             0xFF4F => self.vram_bank | 0xFE,
-            // Colour palettes
-            0xFF68 => self.vram.lock().unwrap().colour_palettes.read_bg_index(),
-            0xFF69 => self.vram.lock().unwrap().colour_palettes.read_bg(),
-            0xFF6A => self.vram.lock().unwrap().colour_palettes.read_obj_index(),
-            0xFF6B => self.vram.lock().unwrap().colour_palettes.read_obj(),
+            // Colour palettes: CGB only, and the data can't be read while drawing.
+            0xFF68 if self.cgb_mode => self.vram.lock().unwrap().colour_palettes.read_bg_index(),
+            0xFF69 if self.cgb_mode && self.regs.can_access_vram() => self.vram.lock().unwrap().colour_palettes.read_bg(),
+            0xFF6A if self.cgb_mode => self.vram.lock().unwrap().colour_palettes.read_obj_index(),
+            0xFF6B if self.cgb_mode && self.regs.can_access_vram() => self.vram.lock().unwrap().colour_palettes.read_obj(),
             _ => 0xFF
         }
     }
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn write(&mut self, loc: u16, val: u8) {
         match loc {
             // Raw tile data
@@ -317,30 +411,205 @@ impl MemDevice for VideoDevice {
             },
             // Sprite data
             0xFE00..=0xFE9F if self.regs.can_access_oam() => self.vram.lock().unwrap().object_mem.write(loc - 0xFE00, val),
+            // Writes land at the end of the CPU's memory cycle, after video has been clocked for it.
+            // So turning the LCD off resets LY in time for a read in the next instruction.
             0xFF40 => {
+                let old_tile_data_select = self.regs.lo_tile_data_select();
                 if self.regs.write_lcd_control(val) {
                     self.cycle_count = 0;
+                    if self.lcd_on_quirk {
+                        self.first_line = true;
+                        self.skip_frame = true;
+                    } else {
+                        self.regs.write_mode(Mode::_2);
+                    }
+                }
+                // The map caches need to be rebuilt if the tile data addressing mode changes.
+                if self.regs.lo_tile_data_select() != old_tile_data_select {
+                    self.vram.lock().unwrap().set_cache_0_dirty();
+                    self.vram.lock().unwrap().set_cache_1_dirty();
                 }
-                self.vram.lock().unwrap().set_cache_0_dirty();
-                self.vram.lock().unwrap().set_cache_1_dirty();
             },
             0xFF41 => self.regs.write_status(val),
             0xFF42 => self.regs.scroll_y = val,
             0xFF43 => self.regs.scroll_x = val,
             0xFF44 => self.regs.set_lcdc_y(0),
-            0xFF45 => self.regs.ly_compare = val,
+            0xFF45 => self.regs.write_ly_compare(val),
             0xFF47 => self.vram.lock().unwrap().palettes.write(0, val),
             0xFF48 => self.vram.lock().unwrap().palettes.write(1, val),
             0xFF49 => self.vram.lock().unwrap().palettes.write(2, val),
             0xFF4A => self.regs.window_y = val,
             0xFF4B => self.regs.window_x = val,
             0xFF4F => self.vram_bank = val & 1,
-            // Colour palettes
-            0xFF68 => self.vram.lock().unwrap().colour_palettes.write_bg_index(val),
-            0xFF69 => self.vram.lock().unwrap().colour_palettes.write_bg(val),
-            0xFF6A => self.vram.lock().unwrap().colour_palettes.write_obj_index(val),
-            0xFF6B => self.vram.lock().unwrap().colour_palettes.write_obj(val),
+            // Colour palettes: writes while drawing are ignored, but still auto-increment the index.
+            0xFF68 if self.cgb_mode => self.vram.lock().unwrap().colour_palettes.write_bg_index(val),
+            0xFF69 if self.cgb_mode => {
+                let accessible = self.regs.can_access_vram();
+                self.vram.lock().unwrap().colour_palettes.write_bg(val, accessible);
+            },
+            0xFF6A if self.cgb_mode => self.vram.lock().unwrap().colour_palettes.write_obj_index(val),
+            0xFF6B if self.cgb_mode => {
+                let accessible = self.regs.can_access_vram();
+                self.vram.lock().unwrap().colour_palettes.write_obj(val, accessible);
+            },
             _ => {}//unreachable!()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sgbpalettes::BW_PALETTE;
+
+    #[test]
+    fn unusable_oam_region() {
+        let dmg = VideoDevice::new(BW_PALETTE, false);
+        let cgb = VideoDevice::new(BW_PALETTE, true);
+
+        assert_eq!(dmg.read(0xFEA0), 0x00);
+        assert_eq!(dmg.read(0xFEFF), 0x00);
+        assert_eq!(cgb.read(0xFEA0), 0xAA);
+        assert_eq!(cgb.read(0xFEB7), 0xBB);
+        assert_eq!(cgb.read(0xFEFF), 0xFF);
+    }
+
+    #[test]
+    fn stat_read_only_bits() {
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        video.write(0xFF45, 0);
+
+        // Mode 2 with LY=LYC. Bit 7 always reads set.
+        video.write(0xFF41, 0x00);
+        assert_eq!(video.read(0xFF41), 0x86);
+        video.write(0xFF41, 0xFF);
+        assert_eq!(video.read(0xFF41), 0xFE);
+
+        // The mode bits still follow the PPU.
+        video.video_mode(constants::MODE_2);
+        assert_eq!(video.read(0xFF41), 0xFF);
+        video.write(0xFF41, 0x00);
+        assert_eq!(video.read(0xFF41), 0x87);
+    }
+
+    #[test]
+    fn coincidence_lcd_off() {
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        video.write(0xFF41, 0x40);  // LY=LYC interrupt enabled
+        video.write(0xFF45, 0x40);
+        while video.read(0xFF44) != 0x10 {
+            video.video_mode(4);
+        }
+        assert_eq!(video.read(0xFF41) & 0x04, 0x00);
+
+        // LY reads 0 with the LCD off.
+        video.write(0xFF40, 0x11);
+        assert_eq!(video.read(0xFF44), 0x00);
+        assert_eq!(video.read(0xFF41) & 0x04, 0x00);
+
+        // It compares as 0 too, but the interrupt can't fire.
+        video.write(0xFF45, 0x00);
+        assert_eq!(video.read(0xFF41) & 0x04, 0x04);
+        assert_eq!(video.video_mode(4).1, InterruptFlags::default());
+
+        // Turning the LCD off with LYC=0 sets the flag straight away.
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        video.write(0xFF45, 0x00);
+        while video.read(0xFF44) != 0x10 {
+            video.video_mode(4);
+        }
+        assert_eq!(video.read(0xFF41) & 0x04, 0x00);
+        video.write(0xFF40, 0x11);
+        assert_eq!(video.read(0xFF41) & 0x04, 0x04);
+    }
+
+    // Run the video for a frame, and return the lines where a STAT interrupt was raised.
+    fn stat_int_lines(video: &mut VideoDevice) -> Vec<u8> {
+        let mut lines = Vec::new();
+        for _ in 0..(154 * 456 / 4) {
+            if video.video_mode(4).1.contains(InterruptFlags::LCD_STAT) {
+                lines.push(video.read(0xFF44));
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn coincidence_int() {
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        video.write(0xFF45, 0x90);
+        while video.read(0xFF44) != 0x10 {
+            video.video_mode(4);
+        }
+
+        // Set mid-frame, LYC fires on the matching line and not before.
+        video.write(0xFF41, 0x40);
+        video.write(0xFF45, 0x30);
+        assert_eq!(stat_int_lines(&mut video), vec![0x30]);
+        assert_eq!(video.read(0xFF41) & 0x04, 0x00);
+
+        // It fires separately from the mode interrupts: line 0x50 gets H-blank and LY=LYC.
+        video.write(0xFF41, 0x48);
+        video.write(0xFF45, 0x50);
+        let lines = stat_int_lines(&mut video);
+        assert_eq!(lines.len(), 145, "{:?}", lines);
+        assert_eq!(lines.iter().filter(|&&ly| ly == 0x50).count(), 2, "{:?}", lines);
+
+        // Setting LYC to the current line fires straight away.
+        video.write(0xFF41, 0x40);
+        while video.read(0xFF44) != 0x20 {
+            video.video_mode(4);
+        }
+        video.write(0xFF45, 0x20);
+        assert_eq!(video.video_mode(4).1, InterruptFlags::LCD_STAT);
+        assert_eq!(video.read(0xFF41) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn cgb_palette_registers() {
+        let mut video = VideoDevice::new(BW_PALETTE, true);
+
+        // Background palette 1, colour 1, with auto-increment.
+        video.write(0xFF68, 0x8A);
+        video.write(0xFF69, 0x1F);
+        video.write(0xFF69, 0x06);
+        assert_eq!(video.read(0xFF68), 0xCC);
+        video.write(0xFF68, 0x0A);
+        assert_eq!(video.read(0xFF69), 0x1F);
+        video.write(0xFF68, 0x0B);
+        assert_eq!(video.read(0xFF69), 0x06);
+
+        // Red 31, green 16, blue 1.
+        let colour = video.vram.lock().unwrap().get_gbc_bg_colour(1, 1);
+        assert_eq!((colour.r, colour.g, colour.b), (255, 132, 8));
+        // Object palettes are separate, and start white.
+        let colour = video.vram.lock().unwrap().get_gbc_obj_colour(1, 1);
+        assert_eq!((colour.r, colour.g, colour.b), (255, 255, 255));
+
+        video.write(0xFF6A, 0x80);
+        video.write(0xFF6B, 0xE0);
+        video.write(0xFF6B, 0x03);
+        assert_eq!(video.read(0xFF6A), 0xC2);
+        video.write(0xFF6A, 0x00);
+        assert_eq!(video.read(0xFF6B), 0xE0);
+        let colour = video.vram.lock().unwrap().get_gbc_obj_colour(0, 0);
+        assert_eq!((colour.r, colour.g, colour.b), (0, 255, 0));
+
+        // Not present on DMG.
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        video.write(0xFF68, 0x8A);
+        assert_eq!(video.read(0xFF68), 0xFF);
+    }
+
+    #[test]
+    fn render_error_logged() {
+        crate::testutil::capture_errors();
+
+        // Draw a line without starting a frame.
+        let mut video = VideoDevice::new(BW_PALETTE, false);
+        for _ in 0..(constants::H_CYCLES / 4) {
+            video.video_mode(4);
+        }
+        assert!(crate::testutil::error_logged("Line drawn before the frame was started"));
+    }
+}
@@ -41,10 +41,12 @@ impl LCDStatus {
         }
     }
 
+    // The mode and coincidence flag are live. Bit 7 is unused and reads 1.
     fn read(&self) -> u8 {
-        self.flags.bits() | self.video_mode as u8
+        self.flags.bits() | self.video_mode as u8 | bit!(7)
     }
 
+    // Only the interrupt enable bits (3-6) can be written.
     fn write(&mut self, val: u8) {
         let coincidence_flag = self.flags & LCDStatusFlags::COINCIDENCE_FLAG;
         let mut new_flags = LCDStatusFlags::from_bits_truncate(val);
@@ -72,11 +74,15 @@ pub struct VideoRegs {
     lcd_status:     LCDStatus,
     lcdc_y:         u8,
     pub ly_compare: u8,
+    // Set when LY becomes equal to LYC, until the interrupt is raised.
+    coincidence_int: bool,
 
     pub scroll_y:   u8,
     pub scroll_x:   u8,
     pub window_y:   u8,
     pub window_x:   u8,
+    // Window row to draw. Only advances on lines where the window was visible.
+    window_line:    u8,
 }
 
 impl VideoRegs {
@@ -86,11 +92,13 @@ impl VideoRegs {
             lcd_status:     LCDStatus::new(),
             lcdc_y:         0,
             ly_compare:     0,
+            coincidence_int: false,
 
             scroll_y:       0,
             scroll_x:       0,
             window_y:       0,
             window_x:       0,
+            window_line:    0,
         }
     }
 
@@ -144,18 +152,50 @@ impl VideoRegs {
                 
     pub fn inc_lcdc_y(&mut self) {
         self.lcdc_y += 1;
-        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, self.lcdc_y == self.ly_compare);
+        self.update_coincidence();
     }
 
     pub fn set_lcdc_y(&mut self, val: u8) {
         self.lcdc_y = val;
-        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, self.lcdc_y == self.ly_compare);
+        self.update_coincidence();
+    }
+
+    // LY is compared with LYC continuously, so changing LYC updates the flag too.
+    pub fn write_ly_compare(&mut self, val: u8) {
+        self.ly_compare = val;
+        self.update_coincidence();
+    }
+
+    // The interrupt fires when the flag goes from clear to set, if it is enabled.
+    fn update_coincidence(&mut self) {
+        let was_equal = self.lcd_status.flags.contains(LCDStatusFlags::COINCIDENCE_FLAG);
+        let is_equal = self.compare_ly_equal();
+        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, is_equal);
+        if is_equal && !was_equal && self.lcd_status.flags.contains(LCDStatusFlags::COINCIDENCE_INT) {
+            self.coincidence_int = true;
+        }
+    }
+
+    // Returns true once for each LY=LYC coincidence interrupt.
+    pub fn take_coincidence_int(&mut self) -> bool {
+        std::mem::replace(&mut self.coincidence_int, false)
     }
 
     pub fn write_mode(&mut self, mode: Mode) {
         self.lcd_status.write_mode(mode);
     }
 
+    // Call after drawing each line.
+    pub fn inc_window_line(&mut self, cgb_mode: bool) {
+        if self.is_window_visible(cgb_mode) {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    pub fn reset_window_line(&mut self) {
+        self.window_line = 0;
+    }
+
     // TODO: improve these
     pub fn bg_tile_map_select(&self) -> bool {
         self.lcd_control.contains(LCDControl::BG_TILE_MAP_SELECT)
@@ -177,6 +217,16 @@ impl VideoRegs {
         self.lcdc_y
     }
 
+    pub fn read_window_line(&self) -> u8 {
+        self.window_line
+    }
+
+    // If the window covers part of the current line. On DMG, clearing LCDC bit 0 hides it.
+    fn is_window_visible(&self, cgb_mode: bool) -> bool {
+        self.get_window_enable() && (cgb_mode || self.get_background_priority()) &&
+        self.lcdc_y >= self.window_y && self.window_x < 166
+    }
+
     pub fn read_lcd_control(&self) -> u8 {
         self.lcd_control.bits()
     }
@@ -196,12 +246,13 @@ impl VideoRegs {
         let is_display_enabled = self.is_display_enabled();
 
         // Has display been toggled on/off?
-        if is_display_enabled && !was_display_enabled {         // ON
-            self.lcd_status.write_mode(Mode::_2);
+        if is_display_enabled && !was_display_enabled {         // ON: the first line skips mode 2, unless the quirk is off.
+            self.lcd_status.write_mode(Mode::_0);
+            self.reset_window_line();
             return true;
-        } else if !is_display_enabled && was_display_enabled {  // OFF
+        } else if !is_display_enabled && was_display_enabled {  // OFF: LY reads and compares as 0.
             self.lcd_status.write_mode(Mode::_0);
-            self.lcdc_y = 0;
+            self.set_lcdc_y(0);
         }
 
         false
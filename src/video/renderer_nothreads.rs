@@ -1,48 +1,59 @@
 // Pixel renderer. Makes a texture of format R8G8B8A8Unorm
 use super::vram::VRAM;
 use super::regs::VideoRegs;
+use super::{Renderer, RenderTarget};
 
 use std::sync::{
     Arc,
     Mutex
 };
 
-pub type RenderTarget = Arc<Mutex<[u8]>>;
-
-// Messages to send to the render thread.
-enum RendererMessage {
-    StartFrame(RenderTarget),   // Begin frame, and target the provided byte array.
-    DrawLineGB(VideoRegs),
-    DrawLineCGB(VideoRegs)
-}
-
-// Renderer for video that spawns a thread to render on.
-pub struct Renderer {
+// Renderer for video that draws each line straight into the target, on the emulation thread.
+pub struct InlineRenderer {
     mem:    Arc<Mutex<VRAM>>,
     target: Option<RenderTarget>
 }
 
-impl Renderer {
+impl InlineRenderer {
     pub fn new(mem: Arc<Mutex<VRAM>>) -> Self {
-        Renderer {
+        InlineRenderer {
             mem:        mem,
             target:     None,
         }
     }
+}
 
-    pub fn start_frame(&mut self, target: RenderTarget) {
+impl Renderer for InlineRenderer {
+    fn start_frame(&mut self, target: RenderTarget) {
         self.target = Some(target);
     }
 
-    pub fn draw_line_gb(&mut self, regs: VideoRegs) {
-        let mut mem = self.mem.lock().unwrap();
-        let mut t = self.target.as_ref().unwrap().lock().unwrap();
-        mem.draw_line_gb(&mut t, &regs);
+    fn draw_line_gb(&mut self, regs: VideoRegs) {
+        if let Some(target) = &self.target {
+            let mut mem = self.mem.lock().unwrap();
+            let mut t = target.lock().unwrap();
+            mem.draw_line_gb(&mut t, &regs);
+        } else {
+            log::error!("Line drawn before the frame was started");
+        }
     }
 
-    pub fn draw_line_cgb(&mut self, regs: VideoRegs) {
-        let mut mem = self.mem.lock().unwrap();
-        let mut t = self.target.as_ref().unwrap().lock().unwrap();
-        mem.draw_line_cgb(&mut t, &regs);
+    fn draw_line_cgb(&mut self, regs: VideoRegs) {
+        if let Some(target) = &self.target {
+            let mut mem = self.mem.lock().unwrap();
+            let mut t = target.lock().unwrap();
+            mem.draw_line_cgb(&mut t, &regs);
+        } else {
+            log::error!("Line drawn before the frame was started");
+        }
+    }
+
+    fn clear_line(&mut self, regs: VideoRegs) {
+        if let Some(target) = &self.target {
+            let mut t = target.lock().unwrap();
+            VRAM::clear_line(&mut t, &regs);
+        } else {
+            log::error!("Line drawn before the frame was started");
+        }
     }
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 // Pixel renderer. Makes a texture of format R8G8B8A8Unorm
 use super::vram::VRAM;
 use super::regs::VideoRegs;
+use super::{Renderer, RenderTarget};
 
 use std::sync::{
     Arc,
@@ -13,22 +14,21 @@ use crossbeam_channel::{
     Receiver
 };
 
-pub type RenderTarget = Arc<Mutex<[u8]>>;
-
 // Messages to send to the render thread.
 enum RendererMessage {
     StartFrame(RenderTarget),   // Begin frame, and target the provided byte array.
     DrawLineGB(VideoRegs),
-    DrawLineCGB(VideoRegs)
+    DrawLineCGB(VideoRegs),
+    ClearLine(VideoRegs)
 }
 
 // Renderer for video that spawns a thread to render on.
-pub struct Renderer {
+pub struct ThreadedRenderer {
     sender:     Sender<RendererMessage>,
     receiver:   Receiver<()>,
 }
 
-impl Renderer {
+impl ThreadedRenderer {
     pub fn new(mem: Arc<Mutex<VRAM>>) -> Self {
         let (send_msg, recv_msg) = unbounded();
         let (send_reply, recv_reply) = unbounded();
@@ -42,51 +42,67 @@ impl Renderer {
                     StartFrame(data) => {
                         target = Some(data);
                     },
-                    DrawLineGB(regs) => {
+                    DrawLineGB(regs) => if let Some(target) = &target {
                         let mut mem = mem.lock().unwrap();
-                        let mut t = target.as_ref().unwrap().lock().unwrap();
+                        let mut t = target.lock().unwrap();
                         send_reply.send(()).unwrap();
                         mem.draw_line_gb(&mut t, &regs);
+                    } else {
+                        log::error!("Line drawn before the frame was started");
+                        send_reply.send(()).unwrap();
                     },
-                    DrawLineCGB(regs) => {
+                    DrawLineCGB(regs) => if let Some(target) = &target {
                         let mut mem = mem.lock().unwrap();
-                        let mut t = target.as_ref().unwrap().lock().unwrap();
+                        let mut t = target.lock().unwrap();
                         send_reply.send(()).unwrap();
                         mem.draw_line_cgb(&mut t, &regs);
+                    } else {
+                        log::error!("Line drawn before the frame was started");
+                        send_reply.send(()).unwrap();
+                    },
+                    ClearLine(regs) => if let Some(target) = &target {
+                        let mut t = target.lock().unwrap();
+                        send_reply.send(()).unwrap();
+                        VRAM::clear_line(&mut t, &regs);
+                    } else {
+                        log::error!("Line drawn before the frame was started");
+                        send_reply.send(()).unwrap();
                     }
                 }
             }
         });
 
-        Renderer {
+        ThreadedRenderer {
             sender:     send_msg,
             receiver:   recv_reply,
         }
     }
+}
 
-    pub fn start_frame(&mut self, target: RenderTarget) {
-        self.sender
-            .send(RendererMessage::StartFrame(target))
-            .expect("Couldn't send start frame message!");
+impl Renderer for ThreadedRenderer {
+    fn start_frame(&mut self, target: RenderTarget) {
+        if self.sender.send(RendererMessage::StartFrame(target)).is_err() {
+            log::error!("Render thread has stopped: frame not started");
+        }
     }
 
-    pub fn draw_line_gb(&mut self, regs: VideoRegs) {
-        self.sender
-            .send(RendererMessage::DrawLineGB(regs))
-            .expect("Couldn't send draw line message!");
-
-        self.receiver
-            .recv()
-            .expect("GB");
+    // Waits until the render thread has locked VRAM, so any palette or tile writes
+    // made after this (e.g. in H-blank) only affect subsequent lines.
+    fn draw_line_gb(&mut self, regs: VideoRegs) {
+        if self.sender.send(RendererMessage::DrawLineGB(regs)).is_err() || self.receiver.recv().is_err() {
+            log::error!("Render thread has stopped: line not drawn");
+        }
     }
 
-    pub fn draw_line_cgb(&mut self, regs: VideoRegs) {
-        self.sender
-            .send(RendererMessage::DrawLineCGB(regs))
-            .expect("Couldn't send draw line message!");
+    fn draw_line_cgb(&mut self, regs: VideoRegs) {
+        if self.sender.send(RendererMessage::DrawLineCGB(regs)).is_err() || self.receiver.recv().is_err() {
+            log::error!("Render thread has stopped: line not drawn");
+        }
+    }
 
-        self.receiver
-            .recv()
-            .expect("CGB");
+    fn clear_line(&mut self, regs: VideoRegs) {
+        if self.sender.send(RendererMessage::ClearLine(regs)).is_err() || self.receiver.recv().is_err() {
+            log::error!("Render thread has stopped: line not drawn");
+        }
     }
 }
\ No newline at end of file
@@ -14,6 +14,16 @@ impl Colour {
         }
     }
 
+    // Linearly interpolate towards another colour.
+    pub fn blend(self, other: Colour, t: f32) -> Colour {
+        let lerp = |a: u8, b: u8| ((a as f32) + ((b as f32) - (a as f32)) * t) as u8;
+        Colour {
+            r: lerp(self.r, other.r),
+            g: lerp(self.g, other.g),
+            b: lerp(self.b, other.b)
+        }
+    }
+
     pub fn zero() -> Colour {
         Colour {
             r: 255,
@@ -23,4 +33,62 @@ impl Colour {
     }
 }
 
-pub type PaletteColours = [Colour; 4];
\ No newline at end of file
+pub type PaletteColours = [Colour; 4];
+
+// How CGB 15-bit colours are converted for display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColourCorrection {
+    None,   // Scale directly to 24-bit.
+    CgbLcd, // Mix channels like the CGB screen.
+    GbaLcd, // Washed out, with the GBA screen's gamma.
+}
+
+impl ColourCorrection {
+    // Convert 5-bit RGB channels.
+    pub fn convert(self, r: u16, g: u16, b: u16) -> Colour {
+        use ColourCorrection::*;
+        match self {
+            None => {
+                let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+                Colour::new(scale(r), scale(g), scale(b))
+            },
+            CgbLcd => {
+                let scale = |c: u16| (c.min(960) >> 2) as u8;
+                Colour::new(
+                    scale(r * 26 + g * 4 + b * 2),
+                    scale(g * 24 + b * 8),
+                    scale(r * 6 + g * 4 + b * 22)
+                )
+            },
+            GbaLcd => {
+                const LCD_GAMMA: f32 = 4.0;
+                const OUT_GAMMA: f32 = 2.2;
+                let lcd = |c: u16| (c as f32 / 31.0).powf(LCD_GAMMA);
+                let (lr, lg, lb) = (lcd(r), lcd(g), lcd(b));
+                let scale = |c: f32| ((c / 255.0).powf(1.0 / OUT_GAMMA) * (255.0 * 255.0 / 280.0)).min(255.0) as u8;
+                Colour::new(
+                    scale(50.0 * lg + 255.0 * lr),
+                    scale(30.0 * lb + 230.0 * lg + 10.0 * lr),
+                    scale(220.0 * lb + 10.0 * lg + 50.0 * lr)
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white(correction: ColourCorrection) -> (u8, u8, u8) {
+        let colour = correction.convert(31, 31, 31);
+        (colour.r, colour.g, colour.b)
+    }
+
+    #[test]
+    fn colour_correction_white() {
+        assert_eq!(white(ColourCorrection::None), (255, 255, 255));
+        assert_eq!(white(ColourCorrection::CgbLcd), (240, 240, 240));
+        assert_eq!(white(ColourCorrection::GbaLcd), (251, 238, 242));
+    }
+}
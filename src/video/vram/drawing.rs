@@ -7,6 +7,7 @@ use super::{
 };
 
 const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
 
 impl VRAM {
     pub fn draw_line_gb(&mut self, target: &mut [u8], regs: &VideoRegs) {
@@ -17,8 +18,11 @@ impl VRAM {
         self.map_cache_0.construct_gb(&self.tile_map_0, &self.tile_mem, regs);
         self.map_cache_1.construct_gb(&self.tile_map_1, &self.tile_mem, regs);
 
+        self.update_smooth_offset(y, regs);
+
         // Find objects
         let objects = self.get_objects_for_line(y, regs);
+        self.record_sprite_overflow(y, objects.len());
         let mut sprite_pixels = [SpritePixel::None; SCREEN_WIDTH];
 
         self.render_sprites_to_line(&mut sprite_pixels, &objects, y, regs.is_large_sprites());
@@ -50,21 +54,31 @@ impl VRAM {
                 }
             }
         }
+
+        if self.sprite_debug {
+            draw_sprite_outlines(target, &objects, y, regs.is_large_sprites());
+        }
+    }
+
+    // The tile and tile row for a line of a sprite.
+    // 8x16 sprites ignore bit 0 of the tile number. Y-flip flips the whole 16 rows,
+    // so the bottom tile is drawn (upside down) at the top.
+    #[inline]
+    fn sprite_tile(o: &Sprite, sprite_y: u8, large: bool) -> (u8, u8) {
+        if large {
+            let row = if o.flip_y() {15 - sprite_y} else {sprite_y};
+            ((o.tile_num & 0xFE) + (row / 8), row % 8)
+        } else {
+            let row = if o.flip_y() {7 - sprite_y} else {sprite_y};
+            (o.tile_num, row)
+        }
     }
 
     fn render_sprites_to_line(&self, line: &mut [SpritePixel], objects: &[Sprite], y: u8, large: bool) {
         for o in objects.iter().take(10) {
             let sprite_y = y + 16 - o.y;
-            let (tile_num_offset, tile_y) = match (large, sprite_y < 8, o.flip_y()) {
-                (false, true, false)    => (0_u8, sprite_y),
-                (false, true, true)     => (0_u8, 7 - sprite_y),
-                (true, true, false)     => (0_u8, sprite_y),
-                (true, false, false)    => (1_u8, sprite_y - 8),
-                (true, true, true)      => (1_u8, 7 - sprite_y),
-                (true, false, true)     => (0_u8, 15 - sprite_y),
-                _ => unreachable!("Cannot have small sprites with sprite_y >= 8")
-            };
-            let tile = self.ref_tile(o.tile_num.wrapping_add(tile_num_offset) as usize);
+            let (tile_num, tile_y) = Self::sprite_tile(o, sprite_y, large);
+            let tile = self.ref_tile(tile_num as usize);
 
             let start_x = (o.x as isize) - 8;
             for x_offset in 0..8 {
@@ -87,11 +101,27 @@ impl VRAM {
         }
     }
 
+    // The window column at a screen position, if the window covers it.
+    // WX=166 hides the window. With WX=0, DMG skips the fine scroll amount of extra window pixels.
+    #[inline]
+    fn window_column(x: u8, regs: &VideoRegs, dmg: bool) -> Option<usize> {
+        if regs.window_x >= 166 || (x + 7) < regs.window_x {
+            None
+        } else if dmg && regs.window_x == 0 {
+            Some((x + 7 + (regs.scroll_x & 0x7)) as usize)
+        } else {
+            Some((x + 7 - regs.window_x) as usize)
+        }
+    }
+
     #[inline]
     fn window_pixel(&self, x: u8, y: u8, regs: &VideoRegs) -> Option<BGPixel> {
-        if regs.get_window_enable() && regs.get_background_priority() && (x + 7 >= regs.window_x) && (y >= regs.window_y) {
-            let win_x = (x + 7 - regs.window_x) as usize;
-            let win_y = (y - regs.window_y) as usize;
+        if !regs.get_window_enable() || !regs.get_background_priority() || (y < regs.window_y) {
+            return None;
+        }
+
+        if let Some(win_x) = Self::window_column(x, regs, true) {
+            let win_y = regs.read_window_line() as usize;
             let win_cache = self.ref_window(regs);
             let win_texel = win_cache.get_texel(win_x, win_y);
             Some(if win_texel == 0 {
@@ -111,10 +141,11 @@ impl VRAM {
             let bg_y = regs.scroll_y.wrapping_add(y) as usize;
             let bg_cache = self.ref_background(regs);
             let bg_texel = bg_cache.get_texel(bg_x, bg_y);
+            let colour = self.smooth_colour(bg_x, bg_y, |tx, ty| self.get_bg_colour(bg_cache.get_texel(tx, ty)));
             if bg_texel == 0 {
-                BGPixel::Zero(self.get_bg_colour(bg_texel))
+                BGPixel::Zero(colour)
             } else {
-                BGPixel::NonZero(self.get_bg_colour(bg_texel))
+                BGPixel::NonZero(colour)
             }
         } else {
             BGPixel::Zero(Colour::zero())
@@ -131,8 +162,11 @@ impl VRAM {
         self.map_cache_0.construct_cgb(&self.tile_map_0, &self.tile_attrs_0, &self.tile_mem, regs);
         self.map_cache_1.construct_cgb(&self.tile_map_1, &self.tile_attrs_1, &self.tile_mem, regs);
 
+        self.update_smooth_offset(y, regs);
+
         // Find objects
         let objects = self.get_objects_for_line(y, regs);
+        self.record_sprite_overflow(y, objects.len());
         let mut sprite_pixels = [SpritePixel::None; SCREEN_WIDTH];
 
         self.render_sprites_to_line_cgb(&mut sprite_pixels, &objects, y, regs.is_large_sprites());
@@ -178,21 +212,17 @@ impl VRAM {
                 }
             }
         }
+
+        if self.sprite_debug {
+            draw_sprite_outlines(target, &objects, y, regs.is_large_sprites());
+        }
     }
 
     fn render_sprites_to_line_cgb(&self, line: &mut [SpritePixel], objects: &[Sprite], y: u8, large: bool) {
         for o in objects.iter().take(10).rev() {
             let sprite_y = y + 16 - o.y;
-            let (tile_num_offset, tile_y) = match (large, sprite_y < 8, o.flip_y()) {
-                (false, true, false)    => (0_u8, sprite_y),
-                (false, true, true)     => (0_u8, 7 - sprite_y),
-                (true, true, false)     => (0_u8, sprite_y),
-                (true, false, false)    => (1_u8, sprite_y - 8),
-                (true, true, true)      => (1_u8, 7 - sprite_y),
-                (true, false, true)     => (0_u8, 15 - sprite_y),
-                _ => unreachable!("Cannot have small sprites with sprite_y >= 8")
-            };
-            let tile_num = (o.tile_num.wrapping_add(tile_num_offset) as usize) + o.bank_offset();
+            let (tile_num, tile_y) = Self::sprite_tile(o, sprite_y, large);
+            let tile_num = (tile_num as usize) + o.bank_offset();
             let tile = self.ref_tile(tile_num);
 
             let start_x = (o.x as isize) - 8;
@@ -219,9 +249,12 @@ impl VRAM {
 
     #[inline]
     fn window_pixel_cgb(&self, x: u8, y: u8, regs: &VideoRegs) -> Option<CGBPixel> {
-        if regs.get_window_enable() && (x + 7 >= regs.window_x) && (y >= regs.window_y) {
-            let win_x = (x + 7 - regs.window_x) as usize;
-            let win_y = (y - regs.window_y) as usize;
+        if !regs.get_window_enable() || (y < regs.window_y) {
+            return None;
+        }
+
+        if let Some(win_x) = Self::window_column(x, regs, false) {
+            let win_y = regs.read_window_line() as usize;
             let win_cache = self.ref_window(regs);
             let win_texel = win_cache.get_texel(win_x, win_y);
             let attrs = win_cache.get_attrs(win_x, win_y);
@@ -253,8 +286,10 @@ impl VRAM {
         let bg_cache = self.ref_background(regs);
         let bg_texel = bg_cache.get_texel(bg_x, bg_y);
         let attrs = bg_cache.get_attrs(bg_x, bg_y);
-        let palette = (attrs & TileAttributes::CGB_PAL).bits();
-        let colour = self.get_gbc_bg_colour(palette, bg_texel);
+        let colour = self.smooth_colour(bg_x, bg_y, |tx, ty| {
+            let palette = (bg_cache.get_attrs(tx, ty) & TileAttributes::CGB_PAL).bits();
+            self.get_gbc_bg_colour(palette, bg_cache.get_texel(tx, ty))
+        });
 
         if regs.get_background_priority() {
             if attrs.contains(TileAttributes::PRIORITY) {
@@ -272,6 +307,100 @@ impl VRAM {
     }
 }
 
+// Smooth scroll enhancement
+// Renders the background between the last frame's scroll position and the current one.
+impl VRAM {
+    // Note which lines had objects dropped due to the limit of 10 per line.
+    fn record_sprite_overflow(&mut self, y: u8, object_count: usize) {
+        if y == 0 {
+            self.sprite_overflow.clear();
+        }
+        if object_count > 10 {
+            self.sprite_overflow.push(y);
+        }
+    }
+
+    fn update_smooth_offset(&mut self, y: u8, regs: &VideoRegs) {
+        const INTERPOLATION_FACTOR: f32 = 0.5;
+        const MAX_SCROLL: i8 = 8;  // Larger changes are treated as jumps, and not smoothed.
+
+        let line = (y as usize) % SCREEN_HEIGHT;
+        let (prev_x, prev_y) = self.prev_scroll[line];
+        let scroll_x = regs.scroll_x.wrapping_sub(prev_x) as i8;
+        let scroll_y = regs.scroll_y.wrapping_sub(prev_y) as i8;
+        self.prev_scroll[line] = (regs.scroll_x, regs.scroll_y);
+
+        let smoothable = |d: i8| d != 0 && d.abs() <= MAX_SCROLL;
+        self.smooth_offset = if self.smooth_scroll && (smoothable(scroll_x) || smoothable(scroll_y)) {
+            let smooth = |d: i8| if smoothable(d) {(d as f32) * INTERPOLATION_FACTOR} else {0.0};
+            Some((smooth(scroll_x), smooth(scroll_y)))
+        } else {
+            None
+        };
+    }
+
+    // Get the background colour at a position, adjusted by the current smooth offset.
+    #[inline]
+    fn smooth_colour<F: Fn(usize, usize) -> Colour>(&self, bg_x: usize, bg_y: usize, colour: F) -> Colour {
+        if let Some((offset_x, offset_y)) = self.smooth_offset {
+            let pos_x = (bg_x as f32) - offset_x;
+            let pos_y = (bg_y as f32) - offset_y;
+            let x0 = (pos_x.floor() as isize).rem_euclid(256) as usize;
+            let y0 = (pos_y.floor() as isize).rem_euclid(256) as usize;
+            let x1 = (x0 + 1) % 256;
+            let y1 = (y0 + 1) % 256;
+            let frac_x = pos_x - pos_x.floor();
+            let frac_y = pos_y - pos_y.floor();
+
+            let top = colour(x0, y0).blend(colour(x1, y0), frac_x);
+            let bottom = colour(x0, y1).blend(colour(x1, y1), frac_x);
+            top.blend(bottom, frac_y)
+        } else {
+            colour(bg_x, bg_y)
+        }
+    }
+}
+
+// Background map viewer
+impl VRAM {
+    // Draw the full 256x256 background map, with the visible viewport outlined.
+    pub fn draw_background(&mut self, target: &mut [u8], regs: &VideoRegs, cgb_mode: bool) {
+        const VIEWPORT_COLOUR: Colour = Colour::new(255, 0, 0);
+
+        if cgb_mode {
+            self.map_cache_0.construct_cgb(&self.tile_map_0, &self.tile_attrs_0, &self.tile_mem, regs);
+            self.map_cache_1.construct_cgb(&self.tile_map_1, &self.tile_attrs_1, &self.tile_mem, regs);
+        } else {
+            self.map_cache_0.construct_gb(&self.tile_map_0, &self.tile_mem, regs);
+            self.map_cache_1.construct_gb(&self.tile_map_1, &self.tile_mem, regs);
+        }
+
+        let bg_cache = self.ref_background(regs);
+        for (i, out) in target.chunks_mut(4).take(256 * 256).enumerate() {
+            let x = i % 256;
+            let y = i / 256;
+
+            // Offset into the viewport, which wraps around the map.
+            let view_x = x.wrapping_sub(regs.scroll_x as usize) % 256;
+            let view_y = y.wrapping_sub(regs.scroll_y as usize) % 256;
+            let in_view_x = view_x < SCREEN_WIDTH;
+            let in_view_y = view_y < SCREEN_HEIGHT;
+            let on_edge_x = in_view_y && (view_x == 0 || view_x == SCREEN_WIDTH - 1);
+            let on_edge_y = in_view_x && (view_y == 0 || view_y == SCREEN_HEIGHT - 1);
+
+            let colour = if on_edge_x || on_edge_y {
+                VIEWPORT_COLOUR
+            } else if cgb_mode {
+                let palette = (bg_cache.get_attrs(x, y) & TileAttributes::CGB_PAL).bits();
+                self.get_gbc_bg_colour(palette, bg_cache.get_texel(x, y))
+            } else {
+                self.get_bg_colour(bg_cache.get_texel(x, y))
+            };
+            write_pixel(out, colour);
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum SpritePixel {
     Hi(Colour), // High priority
@@ -290,9 +419,45 @@ enum CGBPixel {
     LoZero(Colour),     // Low prio, zero colour (draw HI & LO sprites above this)
 }
 
+// Debug overlay: outline the objects on this line.
+// Drawn objects are green, and objects past the limit of 10 per line are red.
+fn draw_sprite_outlines(target: &mut [u8], objects: &[Sprite], y: u8, large: bool) {
+    const DRAWN_COLOUR: Colour = Colour::new(0, 255, 0);
+    const HIDDEN_COLOUR: Colour = Colour::new(255, 0, 0);
+
+    let height = if large {16} else {8};
+    let target_start = (y as usize) * SCREEN_WIDTH;
+    let line = &mut target[(target_start * 4)..((target_start + SCREEN_WIDTH) * 4)];
+
+    for (n, o) in objects.iter().enumerate().rev() {
+        let colour = if n < 10 {DRAWN_COLOUR} else {HIDDEN_COLOUR};
+        let sprite_y = y + 16 - o.y;
+        let edge_row = (sprite_y == 0) || (sprite_y == height - 1);
+
+        let start_x = (o.x as isize) - 8;
+        for x_offset in 0..8 {
+            let x = start_x + x_offset;
+            if (edge_row || x_offset == 0 || x_offset == 7) && x >= 0 && x < (SCREEN_WIDTH as isize) {
+                write_pixel(&mut line[((x as usize) * 4)..], colour);
+            }
+        }
+    }
+}
+
+impl VRAM {
+    // Draw a white line, while the LCD isn't displaying.
+    pub fn clear_line(target: &mut [u8], regs: &VideoRegs) {
+        let target_start = (regs.read_lcdc_y() as usize) * SCREEN_WIDTH;
+        for i in target.chunks_mut(4).skip(target_start).take(SCREEN_WIDTH) {
+            write_pixel(i, Colour::new(255, 255, 255));
+        }
+    }
+}
+
 #[inline]
 fn write_pixel(output: &mut [u8], colour: Colour) {
     output[0] = colour.r;
     output[1] = colour.g;
     output[2] = colour.b;
+    output[3] = 255;
 }
\ No newline at end of file
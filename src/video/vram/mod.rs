@@ -24,6 +24,7 @@ use super::sgbpalettes::SGBPalette;
 use super::regs::VideoRegs;
 
 // VRAM is shared between threads and contains some cached data
+#[allow(clippy::upper_case_acronyms)]
 pub struct VRAM {
     // Raw tile mem and tile maps
     pub tile_mem:           TileMem,
@@ -40,6 +41,15 @@ pub struct VRAM {
     // Palettes
     pub palettes:           StaticPaletteMem,
     pub colour_palettes:    DynamicPaletteMem,
+
+    // Smooth scroll enhancement
+    pub smooth_scroll:      bool,
+    prev_scroll:            Vec<(u8, u8)>,      // Scroll of each line in the previous frame.
+    smooth_offset:          Option<(f32, f32)>, // Sub-pixel offset of the current line.
+
+    // Debug overlay
+    pub sprite_debug:       bool,
+    pub sprite_overflow:    Vec<u8>,            // Lines in the current frame with more than 10 objects.
 }
 
 impl VRAM {
@@ -57,13 +67,20 @@ impl VRAM {
 
             palettes:           StaticPaletteMem::new(palette),
             colour_palettes:    DynamicPaletteMem::new(),
+
+            smooth_scroll:      false,
+            prev_scroll:        vec![(0, 0); 144],
+            smooth_offset:      None,
+
+            sprite_debug:       false,
+            sprite_overflow:    Vec::new(),
         }
     }
 }
 
 impl VRAM {
 
-    pub fn ref_tile<'a>(&'a self, tile_num: usize) -> &'a Tile {
+    pub fn ref_tile(&self, tile_num: usize) -> &Tile {
         self.tile_mem.ref_tile(tile_num)
     }
 
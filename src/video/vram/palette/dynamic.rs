@@ -5,21 +5,19 @@ use crate::{
     mem::MemDevice,
     video::{
         PaletteColours,
-        Colour
+        Colour,
+        ColourCorrection
     }
 };
 
 const MAX_COLOUR: u16 = 0x1F;
 macro_rules! col15_to_col888 {
-    ($rgb:expr) => {
+    ($rgb:expr, $correction:expr) => {
         {
-            let r_i = ($rgb & MAX_COLOUR) << 3;
-            let g_i = (($rgb >> 5) & MAX_COLOUR) << 3;
-            let b_i = (($rgb >> 10) & MAX_COLOUR) << 3;
-            let r = r_i + (r_i >> 5);
-            let g = g_i + (g_i >> 5);
-            let b = b_i + (b_i >> 5);
-            Colour::new(r as u8, g as u8, b as u8)
+            let r = $rgb & MAX_COLOUR;
+            let g = ($rgb >> 5) & MAX_COLOUR;
+            let b = ($rgb >> 10) & MAX_COLOUR;
+            $correction.convert(r, g, b)
         }
     };
 }
@@ -35,6 +33,7 @@ bitflags! {
 struct DynamicPalette {
     colours:    PaletteColours,
     raw:        [u8; 8],
+    correction: ColourCorrection,
 }
 
 impl DynamicPalette {
@@ -42,6 +41,16 @@ impl DynamicPalette {
         DynamicPalette {
             colours:    [Colour::zero(); 4],
             raw:        [0; 8],
+            correction: ColourCorrection::None,
+        }
+    }
+
+    // Convert all the colours again with the new correction.
+    fn set_correction(&mut self, correction: ColourCorrection) {
+        self.correction = correction;
+        for colour in 0..4 {
+            let raw_idx = colour << 1;
+            self.colours[colour] = col15_to_col888!(make_16!(self.raw[raw_idx + 1], self.raw[raw_idx]), self.correction);
         }
     }
 }
@@ -56,7 +65,7 @@ impl MemDevice for DynamicPalette {
         self.raw[(loc % 8) as usize] = val;
 
         let raw_idx = colour << 1;
-        self.colours[colour] = col15_to_col888!(make_16!(self.raw[raw_idx + 1], self.raw[raw_idx]));
+        self.colours[colour] = col15_to_col888!(make_16!(self.raw[raw_idx + 1], self.raw[raw_idx]), self.correction);
     }
 }
 
@@ -84,6 +93,12 @@ impl DynamicPaletteMem {
         }
     }
 
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        for palette in self.bg_palettes.iter_mut().chain(self.obj_palettes.iter_mut()) {
+            palette.set_correction(correction);
+        }
+    }
+
     pub fn get_bg_colour(&self, which: usize, texel: u8) -> Colour {
         self.bg_palettes[which].colours[texel as usize]
     }
@@ -92,8 +107,9 @@ impl DynamicPaletteMem {
         self.obj_palettes[which].colours[texel as usize]
     }
 
+    // Reads back the current index and auto-increment flag. Bit 6 is unused and reads 1.
     pub fn read_bg_index(&self) -> u8 {
-        (self.bg_palette_index as u8) | self.bg_auto_inc.bits()
+        (self.bg_palette_index as u8) | self.bg_auto_inc.bits() | bit!(6)
     }
 
     pub fn write_bg_index(&mut self, val: u8) {
@@ -102,7 +118,7 @@ impl DynamicPaletteMem {
     }
 
     pub fn read_obj_index(&self) -> u8 {
-        (self.obj_palette_index as u8) | self.obj_auto_inc.bits()
+        (self.obj_palette_index as u8) | self.obj_auto_inc.bits() | bit!(6)
     }
 
     pub fn write_obj_index(&mut self, val: u8) {
@@ -116,10 +132,14 @@ impl DynamicPaletteMem {
         self.bg_palettes[palette].read(colour as u16)
     }
 
-    pub fn write_bg(&mut self, val: u8) {
+    // Colours are 15-bit little-endian: 5 bits each of red, green and blue, from the lowest bit.
+    // If the data isn't accessible, only the index is changed.
+    pub fn write_bg(&mut self, val: u8, accessible: bool) {
         let palette = self.bg_palette_index / 8;
         let colour = self.bg_palette_index % 8;
-        self.bg_palettes[palette].write(colour as u16, val);
+        if accessible {
+            self.bg_palettes[palette].write(colour as u16, val);
+        }
         if self.bg_auto_inc.contains(PaletteIndex::AUTO_INCREMENT) {
             self.bg_palette_index = (self.bg_palette_index + 1) % 0x40;
         }
@@ -131,12 +151,38 @@ impl DynamicPaletteMem {
         self.obj_palettes[palette].read(colour as u16)
     }
 
-    pub fn write_obj(&mut self, val: u8) {
+    pub fn write_obj(&mut self, val: u8, accessible: bool) {
         let palette = self.obj_palette_index / 8;
         let colour = self.obj_palette_index % 8;
-        self.obj_palettes[palette].write(colour as u16, val);
+        if accessible {
+            self.obj_palettes[palette].write(colour as u16, val);
+        }
         if self.obj_auto_inc.contains(PaletteIndex::AUTO_INCREMENT) {
             self.obj_palette_index = (self.obj_palette_index + 1) % 0x40;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_increment_index() {
+        let mut palettes = DynamicPaletteMem::new();
+        palettes.write_bg_index(0xBE);
+        palettes.write_bg(0x1F, true);
+        assert_eq!(palettes.read_bg_index(), 0xFF);
+        palettes.write_bg(0x00, true);
+        assert_eq!(palettes.read_bg_index(), 0xC0);   // Wraps around
+
+        palettes.write_obj_index(0x01);
+        palettes.write_obj(0x1F, true);
+        assert_eq!(palettes.read_obj_index(), 0x41);   // No auto-increment
+        palettes.write_obj_index(0x81);
+        palettes.write_obj(0x1F, false);
+        assert_eq!(palettes.read_obj_index(), 0xC2);
+        palettes.write_bg_index(0x3E);
+        assert_eq!(palettes.read_bg(), 0x1F);
+    }
+}
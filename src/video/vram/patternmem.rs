@@ -91,7 +91,7 @@ impl TileMem {
         })
     }
 
-    pub fn ref_tile<'a>(&'a self, tile_num: usize) -> &'a Tile {
+    pub fn ref_tile(&self, tile_num: usize) -> &Tile {
         &self.tiles[tile_num]
     }
 }